@@ -0,0 +1,44 @@
+#import bevy_pbr::{
+    forward_io::{VertexOutput, FragmentOutput},
+    pbr_fragment::pbr_input_from_standard_material,
+    pbr_functions::{apply_pbr_lighting, main_pass_post_lighting_processing},
+}
+
+// terrain_array.rs の MAX_TERRAIN_LAYERS と合わせる
+const MaxTerrainLayers: u32 = 256u;
+
+struct TerrainLayerParams {
+    base_color: array<vec4<f32>, 256>,
+    // x: perceptual_roughness, y: reflectance, z: metallic
+    surface: array<vec4<f32>, 256>,
+    emissive: array<vec4<f32>, 256>,
+}
+
+@group(#{MATERIAL_BIND_GROUP}) @binding(100) var layer_textures: texture_2d_array<f32>;
+@group(#{MATERIAL_BIND_GROUP}) @binding(101) var layer_sampler: sampler;
+@group(#{MATERIAL_BIND_GROUP}) @binding(102) var<uniform> layer_params: TerrainLayerParams;
+
+@fragment
+fn fragment(
+    in: VertexOutput,
+    @builtin(front_facing) is_front: bool,
+) -> FragmentOutput {
+    // UV_1 の x にレイヤー番号が入っている
+    let layer = min(u32(in.uv_b.x + 0.5), MaxTerrainLayers - 1u);
+
+    // base_color には頂点カラー (AOと明るさ) が掛けられている
+    var pbr_input = pbr_input_from_standard_material(in, is_front);
+    let texel = textureSample(layer_textures, layer_sampler, in.uv, layer);
+    pbr_input.material.base_color *= texel * layer_params.base_color[layer];
+
+    let surface = layer_params.surface[layer];
+    pbr_input.material.perceptual_roughness = surface.x;
+    pbr_input.material.reflectance = vec3<f32>(surface.y);
+    pbr_input.material.metallic = surface.z;
+    pbr_input.material.emissive = vec4<f32>(layer_params.emissive[layer].rgb, pbr_input.material.emissive.a);
+
+    var out: FragmentOutput;
+    out.color = apply_pbr_lighting(pbr_input);
+    out.color = main_pass_post_lighting_processing(pbr_input, out.color);
+    return out;
+}
@@ -0,0 +1,121 @@
+#import bevy_pbr::{
+    mesh_functions,
+    mesh_view_bindings::globals,
+    view_transformations::position_world_to_clip,
+}
+
+// 本描画とプリパス (深度・影) で同じ頂点シェーダーを使う
+#ifdef PREPASS_PIPELINE
+#import bevy_pbr::prepass_io::{Vertex, VertexOutput}
+#else
+#import bevy_pbr::forward_io::{Vertex, VertexOutput}
+#endif
+
+struct VegetationExtension {
+    direction: vec2<f32>,
+    strength: f32,
+    speed: f32,
+}
+
+@group(#{MATERIAL_BIND_GROUP}) @binding(100) var<uniform> wind: VegetationExtension;
+
+// 突風の模様の大きさ (ブロック単位)
+const GustScale: f32 = 12.0;
+// 細かい揺れの速さと、風に直交する方向への揺れの割合
+const FlutterSpeed: f32 = 2.3;
+const FlutterAmount: f32 = 0.35;
+
+fn hash(n: f32) -> f32 {
+    return fract(sin(n) * 43758.5453123);
+}
+
+fn noise(x: vec2<f32>) -> f32 {
+    let p = floor(x);
+    let f = fract(x);
+    let f2 = f * f * (3.0 - 2.0 * f);
+    let n = p.x + p.y * 57.0;
+    return mix(mix(hash(n + 0.0), hash(n + 1.0), f2.x),
+               mix(hash(n + 57.0), hash(n + 58.0), f2.x), f2.y);
+}
+
+// ワールド座標の頂点を風で動かす量
+// 風下に流れる突風の模様で大きく傾き、位置ごとに位相のずれた細かい揺れを加える
+// 同じ位置の頂点は同じだけ動く。揺れる面は貪欲法でまとめずボクセルごとに作るので、
+// 隣り合う葉の面は辺の両端の頂点を共有し、間に隙間はできない
+fn wind_offset(world_position: vec3<f32>, time: f32) -> vec3<f32> {
+    let direction = vec3<f32>(wind.direction.x, 0.0, wind.direction.y);
+    let side = vec3<f32>(-wind.direction.y, 0.0, wind.direction.x);
+    let gust = noise((world_position.xz - wind.direction * time * wind.speed) / GustScale);
+    let phase = dot(world_position, vec3<f32>(0.7, 0.3, 0.5));
+    let flutter = sin(time * FlutterSpeed + phase) * 0.5 + sin(time * FlutterSpeed * 1.7 + phase * 1.3) * 0.5;
+    return (direction * (0.3 + gust * 0.7 + flutter * 0.2) + side * flutter * FlutterAmount) * wind.strength;
+}
+
+// UV_1 の y に入れた揺れの重み
+fn sway_weight(vertex: Vertex) -> f32 {
+#ifdef VERTEX_UVS_B
+    return vertex.uv_b.y;
+#else
+    return 0.0;
+#endif
+}
+
+@vertex
+fn vertex(vertex: Vertex) -> VertexOutput {
+    var out: VertexOutput;
+
+    let world_from_local = mesh_functions::get_world_from_local(vertex.instance_index);
+    let local_position = vec4<f32>(vertex.position, 1.0);
+    let weight = sway_weight(vertex);
+    var world_position = mesh_functions::mesh_position_local_to_world(world_from_local, local_position);
+    world_position += vec4<f32>(wind_offset(world_position.xyz, globals.time) * weight, 0.0);
+    out.world_position = world_position;
+    out.position = position_world_to_clip(world_position.xyz);
+
+#ifdef PREPASS_PIPELINE
+#ifdef UNCLIPPED_DEPTH_ORTHO_EMULATION
+    out.unclipped_depth = out.position.z;
+    out.position.z = min(out.position.z, 1.0);
+#endif
+#ifdef NORMAL_PREPASS_OR_DEFERRED_PREPASS
+#ifdef VERTEX_NORMALS
+    out.world_normal = mesh_functions::mesh_normal_local_to_world(vertex.normal, vertex.instance_index);
+#endif
+#ifdef VERTEX_TANGENTS
+    out.world_tangent = mesh_functions::mesh_tangent_local_to_world(world_from_local, vertex.tangent, vertex.instance_index);
+#endif
+#endif
+#ifdef MOTION_VECTOR_PREPASS
+    // 前のフレームの位置も、前のフレームの時刻の風で動かす
+    let previous_world_from_local = mesh_functions::get_previous_world_from_local(vertex.instance_index);
+    let previous_position = mesh_functions::mesh_position_local_to_world(previous_world_from_local, local_position);
+    let previous_offset = wind_offset(previous_position.xyz, globals.time - globals.delta_time) * weight;
+    out.previous_world_position = previous_position + vec4<f32>(previous_offset, 0.0);
+#endif
+#else
+#ifdef VERTEX_NORMALS
+    out.world_normal = mesh_functions::mesh_normal_local_to_world(vertex.normal, vertex.instance_index);
+#endif
+#ifdef VERTEX_TANGENTS
+    out.world_tangent = mesh_functions::mesh_tangent_local_to_world(world_from_local, vertex.tangent, vertex.instance_index);
+#endif
+#endif
+
+#ifdef VERTEX_UVS_A
+    out.uv = vertex.uv;
+#endif
+#ifdef VERTEX_UVS_B
+    out.uv_b = vertex.uv_b;
+#endif
+#ifdef VERTEX_COLORS
+    out.color = vertex.color;
+#endif
+#ifdef VERTEX_OUTPUT_INSTANCE_INDEX
+    out.instance_index = vertex.instance_index;
+#endif
+#ifdef VISIBILITY_RANGE_DITHER
+    out.visibility_range_dither = mesh_functions::get_visibility_range_dither_level(vertex.instance_index, world_from_local[3]);
+#endif
+
+    return out;
+}
@@ -1,12 +1,14 @@
 #import bevy_pbr::{
-    mesh_view_bindings::globals,
-    forward_io::{VertexOutput, FragmentOutput},
+    mesh_functions,
+    mesh_view_bindings::{globals, view, view_transmission_texture, view_transmission_sampler},
+    forward_io::{Vertex, VertexOutput, FragmentOutput},
     pbr_fragment::pbr_input_from_standard_material,
     pbr_functions::{apply_pbr_lighting, main_pass_post_lighting_processing},
     prepass_utils::prepass_depth,
     view_transformations::{
         depth_ndc_to_view_z,
         position_ndc_to_world,
+        position_world_to_clip,
     },
 }
 
@@ -49,16 +51,94 @@ fn get_wave_height(p: vec2<f32>, delta: f32) -> f32 {
 struct WaterExtension {
     deep_color: vec4<f32>,
     shallow_color: vec4<f32>,
+    foam_color: vec4<f32>,
     depth_scale: f32,
-    _padding: vec3<f32>,
+    foam_depth: f32,
+    refraction_strength: f32,
+    _padding: f32,
 }
 
+// 水中から水面を見上げたとき、法線とのなす角のcosがこれより小さいと全反射して外が見えない
+// (水の臨界角は約49°)
+const CriticalCos: f32 = 0.66;
+
 const WaveScale: f32 = 4.0;
 const WaveSpeed: f32 = 0.4;
-const WaveStrength: f32 = 0.1;
+// 水面を上下させる幅 (ブロック単位)。fluid.rs の WATER_SURFACE_HEIGHT の隙間に収まるようにする
+const WaveAmplitude: f32 = 0.24;
+// 法線の傾き。頂点の変位と同じ波の勾配になるようにする
+const WaveStrength: f32 = WaveAmplitude / WaveScale;
+
+// ワールド座標 xz の水面の変位
+fn wave_offset(world_xz: vec2<f32>, time: f32) -> f32 {
+    return (get_wave_height(world_xz / WaveScale, time * WaveSpeed) - 0.5) * WaveAmplitude;
+}
+
+// 泡の模様の細かさ
+const FoamNoiseScale: f32 = 1.5;
 
 @group(#{MATERIAL_BIND_GROUP}) @binding(100) var<uniform> water_ext: WaterExtension;
 
+// フレームバッファの座標をビューポート内のUVにする
+fn frag_coord_to_uv(frag_coord: vec2<f32>) -> vec2<f32> {
+    return (frag_coord - view.viewport.xy) / view.viewport.zw;
+}
+
+fn uv_to_frag_coord(uv: vec2<f32>) -> vec4<f32> {
+    return vec4<f32>(uv * view.viewport.zw + view.viewport.xy, 0.0, 0.0);
+}
+
+// 屈折でずらした位置が画面内か (画面外の深度やテクスチャは読めない)
+fn is_on_screen(uv: vec2<f32>) -> bool {
+    return all(uv >= vec2<f32>(0.0)) && all(uv < vec2<f32>(1.0));
+}
+
+// 水面より奥に描画された不透明な物体の色
+// 水は透過するマテリアルとして、不透明な物体の描画が終わった後の画面を読む
+fn background_color(uv: vec2<f32>) -> vec3<f32> {
+    return textureSampleLevel(view_transmission_texture, view_transmission_sampler, uv, 0.0).rgb;
+}
+
+// 空気に接する水面の頂点を波の高さだけ上下させる
+// 水面の頂点だけがボクセルの境界 (整数の高さ) の間にあるので、高さの端数で見分ける
+// 隣り合う面の頂点は同じ位置なら同じだけ動くので隙間はできない
+@vertex
+fn vertex(vertex: Vertex) -> VertexOutput {
+    var out: VertexOutput;
+
+    let world_from_local = mesh_functions::get_world_from_local(vertex.instance_index);
+    var world_position = mesh_functions::mesh_position_local_to_world(world_from_local, vec4<f32>(vertex.position, 1.0));
+    if abs(world_position.y - round(world_position.y)) > 0.001 {
+        world_position.y += wave_offset(world_position.xz, globals.time);
+    }
+    out.world_position = world_position;
+    out.position = position_world_to_clip(world_position.xyz);
+
+#ifdef VERTEX_NORMALS
+    out.world_normal = mesh_functions::mesh_normal_local_to_world(vertex.normal, vertex.instance_index);
+#endif
+#ifdef VERTEX_UVS_A
+    out.uv = vertex.uv;
+#endif
+#ifdef VERTEX_UVS_B
+    out.uv_b = vertex.uv_b;
+#endif
+#ifdef VERTEX_TANGENTS
+    out.world_tangent = mesh_functions::mesh_tangent_local_to_world(world_from_local, vertex.tangent, vertex.instance_index);
+#endif
+#ifdef VERTEX_COLORS
+    out.color = vertex.color;
+#endif
+#ifdef VERTEX_OUTPUT_INSTANCE_INDEX
+    out.instance_index = vertex.instance_index;
+#endif
+#ifdef VISIBILITY_RANGE_DITHER
+    out.visibility_range_dither = mesh_functions::get_visibility_range_dither_level(vertex.instance_index, world_from_local[3]);
+#endif
+
+    return out;
+}
+
 @fragment
 fn fragment(
     in: VertexOutput,
@@ -85,6 +165,32 @@ fn fragment(
 
     var out: FragmentOutput;
 
+    // 裏面が見えている = カメラが水中にあって水面を見上げている
+    // 水面の向こう側は空気なので、深度による吸収ではなく全反射で色を決める
+    // (水中の吸収はカメラ側のフォグで表現する)
+    if !is_front {
+        let cos_theta = abs(dot(pbr_input.V, pbr_input.N));
+        let transmission = smoothstep(CriticalCos - 0.08, CriticalCos + 0.08, cos_theta);
+        let reflected_color = vec4<f32>(0.02, 0.12, 0.22, 0.97);
+        let transmitted_color = vec4<f32>(0.4, 0.6, 0.75, 0.25);
+        let underside_color = mix(reflected_color, transmitted_color, transmission);
+
+        pbr_input.material.base_color = underside_color;
+        // 下向きの面は太陽の光を受けないので、水面を透過してくる光を発光として加える
+        pbr_input.material.emissive = vec4<f32>(underside_color.rgb * 0.5, 1.0);
+        // 透過はここで合成するので、Bevy側では透過の光を加えない
+        pbr_input.material.specular_transmission = 0.0;
+        let lit = main_pass_post_lighting_processing(pbr_input, apply_pbr_lighting(pbr_input));
+        // 水面の向こうの景色も波で揺らす (画面外にずれる場合は揺らさない)
+        let screen_uv = frag_coord_to_uv(in.position.xy);
+        var uv = screen_uv + pbr_input.N.xz * water_ext.refraction_strength;
+        if !is_on_screen(uv) {
+            uv = screen_uv;
+        }
+        out.color = vec4<f32>(mix(background_color(uv), lit.rgb, underside_color.a), 1.0);
+        return out;
+    }
+
     // 現在のピクセル（水面）の深度情報
     let surface_raw_depth = in.position.z;
     let surface_view_z = depth_ndc_to_view_z(surface_raw_depth);
@@ -99,27 +205,52 @@ fn fragment(
     // 念のため
     let safe_depth = max(0.0, water_depth);
 
+    // 屈折: 波の法線の傾きだけ背景を読む位置をずらす
+    // 岸辺では水深に合わせて弱め、地形との境目で背景がずれて見えないようにする
+    let screen_uv = frag_coord_to_uv(in.position.xy);
+    var refracted_uv = screen_uv + pbr_input.N.xz * water_ext.refraction_strength * saturate(safe_depth);
+    // 画面の端でずらした先が画面外になる場合は屈折させない
+    if !is_on_screen(refracted_uv) {
+        refracted_uv = screen_uv;
+    }
+    var refracted_depth = surface_view_z - depth_ndc_to_view_z(prepass_depth(uv_to_frag_coord(refracted_uv), 0u));
+    // ずらした先が水面より手前の物体 (水から出ている地形など) なら屈折させない
+    if refracted_depth < 0.0 {
+        refracted_uv = screen_uv;
+        refracted_depth = safe_depth;
+    }
+    let background = background_color(refracted_uv);
 
-    // 水の色設定
-    let deep_color = vec4<f32>(0.0, 0.03, 0.2, 0.99);
-    let shallow_color = vec4<f32>(0.1, 0.25, 0.6, 0.6);
-    let depth_scale = 0.7;
+    // 水の色設定 (WaterSettings から渡される)
+    let deep_color = water_ext.deep_color;
+    let shallow_color = water_ext.shallow_color;
+    let depth_scale = water_ext.depth_scale;
 
-    // let deep_color = water_ext.deep_color;
-    // let shallow_color = water_ext.shallow_color;
-    // let depth_scale = water_ext.depth_scale;
+    // Beer's Lawを適用 (屈折した先の水深で吸収する)
+    let absorption = exp(-refracted_depth * depth_scale);
+    var water_color = mix(deep_color, shallow_color, absorption);
+#ifdef VERTEX_COLORS
+    // 頂点カラーにはバイオームの水の色と明るさが入っている
+    water_color = vec4<f32>(water_color.rgb * in.color.rgb, water_color.a);
+#endif
 
-    // Beer's Lawを適用
-    let absorption = exp(-safe_depth * depth_scale);
-    let water_color = mix(deep_color, shallow_color, absorption);
+    // 岸辺の泡: 地形までの深さが foam_depth より浅いところに、ノイズで途切れた泡を出す
+    let foam_noise = fbm(in.world_position.xz * FoamNoiseScale + vec2<f32>(t * 0.3, -t * 0.2));
+    let shore = 1.0 - saturate(safe_depth / max(water_ext.foam_depth, 0.001));
+    let foam = smoothstep(0.45, 0.75, shore * (0.5 + foam_noise)) * water_ext.foam_color.a;
 
-    // 半透明合成
-    pbr_input.material.base_color = mix(pbr_input.material.base_color, water_color, water_color.a);
+    let water_base = mix(pbr_input.material.base_color.rgb, water_color.rgb, water_color.a);
+    pbr_input.material.base_color = vec4<f32>(mix(water_base, water_ext.foam_color.rgb, foam), 1.0);
+    pbr_input.material.perceptual_roughness = mix(pbr_input.material.perceptual_roughness, 0.9, foam);
+    // 透過はここで合成するので、Bevy側では透過の光を加えない
+    pbr_input.material.specular_transmission = 0.0;
 
     // PBRライティング計算
-    out.color = apply_pbr_lighting(pbr_input);
-
+    let lit = apply_pbr_lighting(pbr_input);
 
+    // 屈折した背景と合成する
+    let opacity = max(water_color.a, foam);
+    out.color = vec4<f32>(mix(background, lit.rgb, opacity), 1.0);
 
     return out;
-}
\ No newline at end of file
+}
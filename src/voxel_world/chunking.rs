@@ -4,15 +4,16 @@ use itertools::iproduct;
 use crate::voxel_world::{
     core::{
         chunk_range::{is_within_active_chunk_range, should_unload_chunk},
-        coordinates::TERRAIN_CHUNK_LENGTH,
+        coordinates::{TERRAIN_CHUNK_LENGTH, VOXEL_SIZE},
         ChunkEntities, RenderDistanceParams, TerrainChunk
     },
-    storage::ChunkMap,
+    storage::{ChunkMap, RegionStore},
     pipelines::cpu_noise::WaitForTerrainGeneration
 };
 
 pub fn unload_distant_chunks(
     mut chunk_map: ResMut<ChunkMap>,
+    region_store: Res<RegionStore>,
     render_distance_params: Res<RenderDistanceParams>,
 ) {
     let mut to_remove = Vec::new();
@@ -25,11 +26,28 @@ pub fn unload_distant_chunks(
     if !to_remove.is_empty() {
         // info!("Unloading {} distant chunks", to_remove.len());
         for chunk_pos in to_remove {
-            chunk_map.chunks.remove(&chunk_pos);
+            chunk_map.unload(&chunk_pos, &region_store);
         }
     }
 }
 
+// 終了時にまだメモリ上にある編集済みチャンクを保存する
+// バックグラウンドの書き出しが終わる前に終了しないように、保存待ちのチャンクはここでまとめて書き出す
+pub fn save_dirty_chunks_on_exit(
+    mut exit_events: MessageReader<AppExit>,
+    mut chunk_map: ResMut<ChunkMap>,
+    region_store: Res<RegionStore>,
+) {
+    if exit_events.read().next().is_none() {
+        return;
+    }
+    chunk_map.save_dirty(&region_store);
+    match region_store.flush_pending() {
+        Ok(saved) => info!("Saved {saved} modified chunks"),
+        Err(err) => error!("Failed to save modified chunks: {err}"),
+    }
+}
+
 pub fn update_chunk_entities(
     mut commands: Commands,
     mut chunk_entities: ResMut<ChunkEntities>,
@@ -63,7 +81,9 @@ pub fn update_chunk_entities(
                 position: chunk_pos,
             },
             WaitForTerrainGeneration,
-            Transform::from_translation(chunk_pos.as_vec3() * TERRAIN_CHUNK_LENGTH),
+            // メッシュはパディングを含む座標で生成されるので、1ボクセル分ずらして
+            // ボクセル座標とワールド座標を一致させる
+            Transform::from_translation(chunk_pos.as_vec3() * TERRAIN_CHUNK_LENGTH - Vec3::splat(VOXEL_SIZE)),
             InheritedVisibility::default(),
         )).id();
         chunk_entities.entities.insert(chunk_pos, entity);
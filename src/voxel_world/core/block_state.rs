@@ -0,0 +1,213 @@
+use bevy::prelude::*;
+
+use crate::voxel_world::core::voxel::Voxel;
+
+// ボクセルごとに宣言できるブロックステートのプロパティ
+// 値は define_voxels! で宣言した順番に Voxel::state の下位ビットから詰めて格納される
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VoxelProperty {
+    // 原木などの向き (VoxelAxis)
+    Axis,
+    // 上面が向いている方向 (VoxelFacing)
+    Facing,
+    // 成長段階 (0..=max)
+    Age(u8),
+    // 液体の水位 (0..=max)。0 が水源
+    Level(u8),
+}
+
+impl VoxelProperty {
+    // 値を格納するのに必要なビット数
+    pub fn bits(&self) -> u32 {
+        match self {
+            Self::Axis => 2,
+            Self::Facing => 3,
+            Self::Age(max) | Self::Level(max) => u8::BITS - max.leading_zeros(),
+        }
+    }
+
+    // 取りうる値の数
+    pub fn value_count(&self) -> u16 {
+        match self {
+            Self::Axis => 3,
+            Self::Facing => 6,
+            Self::Age(max) | Self::Level(max) => *max as u16 + 1,
+        }
+    }
+
+    fn same_kind(&self, other: &Self) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+    }
+}
+
+// デフォルト値 (state = 0) が縦置きになるように Y を 0 にしている
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum VoxelAxis {
+    Y = 0,
+    X = 1,
+    Z = 2,
+}
+
+impl VoxelAxis {
+    pub fn from_value(value: u16) -> Self {
+        match value {
+            1 => Self::X,
+            2 => Self::Z,
+            _ => Self::Y,
+        }
+    }
+
+    // 法線などのベクトルから最も近い軸を選ぶ
+    pub fn from_direction(direction: IVec3) -> Self {
+        let abs = direction.abs();
+        if abs.x > abs.y && abs.x >= abs.z {
+            Self::X
+        } else if abs.z > abs.y {
+            Self::Z
+        } else {
+            Self::Y
+        }
+    }
+
+    pub fn direction(&self) -> IVec3 {
+        match self {
+            Self::X => IVec3::X,
+            Self::Y => IVec3::Y,
+            Self::Z => IVec3::Z,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum VoxelFacing {
+    Up = 0,
+    Down = 1,
+    North = 2,
+    South = 3,
+    West = 4,
+    East = 5,
+}
+
+impl VoxelFacing {
+    pub fn from_value(value: u16) -> Self {
+        match value {
+            1 => Self::Down,
+            2 => Self::North,
+            3 => Self::South,
+            4 => Self::West,
+            5 => Self::East,
+            _ => Self::Up,
+        }
+    }
+
+    // 最も近い方向を選ぶ (北は -Z)
+    pub fn from_direction(direction: IVec3) -> Self {
+        match VoxelAxis::from_direction(direction) {
+            VoxelAxis::X if direction.x < 0 => Self::West,
+            VoxelAxis::X => Self::East,
+            VoxelAxis::Z if direction.z < 0 => Self::North,
+            VoxelAxis::Z => Self::South,
+            VoxelAxis::Y if direction.y < 0 => Self::Down,
+            VoxelAxis::Y => Self::Up,
+        }
+    }
+
+    pub fn direction(&self) -> IVec3 {
+        match self {
+            Self::Up => IVec3::Y,
+            Self::Down => IVec3::NEG_Y,
+            Self::North => IVec3::NEG_Z,
+            Self::South => IVec3::Z,
+            Self::West => IVec3::NEG_X,
+            Self::East => IVec3::X,
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl Voxel {
+    // プロパティの (ビットオフセット, ビット数) を返す。宣言されていない場合はNone
+    fn property_layout(&self, property: VoxelProperty) -> Option<(u32, VoxelProperty)> {
+        let mut offset = 0;
+        for declared in self.properties() {
+            if declared.same_kind(&property) {
+                return Some((offset, *declared));
+            }
+            offset += declared.bits();
+        }
+        None
+    }
+
+    pub fn has_property(&self, property: VoxelProperty) -> bool {
+        self.property_layout(property).is_some()
+    }
+
+    pub fn get_property(&self, property: VoxelProperty) -> Option<u16> {
+        let (offset, declared) = self.property_layout(property)?;
+        let mask = (1u16 << declared.bits()) - 1;
+        Some((self.state >> offset) & mask)
+    }
+
+    // 宣言されていないプロパティや範囲外の値は無視する
+    pub fn with_property(mut self, property: VoxelProperty, value: u16) -> Self {
+        let Some((offset, declared)) = self.property_layout(property) else {
+            return self;
+        };
+        if value >= declared.value_count() {
+            return self;
+        }
+        let mask = ((1u16 << declared.bits()) - 1) << offset;
+        self.state = (self.state & !mask) | (value << offset);
+        self
+    }
+
+    // ステートを無視して同じ種類のボクセルか
+    pub fn is(&self, other: Voxel) -> bool {
+        self.id == other.id
+    }
+
+    pub fn axis(&self) -> Option<VoxelAxis> {
+        self.get_property(VoxelProperty::Axis).map(VoxelAxis::from_value)
+    }
+
+    pub fn with_axis(self, axis: VoxelAxis) -> Self {
+        self.with_property(VoxelProperty::Axis, axis as u16)
+    }
+
+    pub fn facing(&self) -> Option<VoxelFacing> {
+        self.get_property(VoxelProperty::Facing).map(VoxelFacing::from_value)
+    }
+
+    pub fn with_facing(self, facing: VoxelFacing) -> Self {
+        self.with_property(VoxelProperty::Facing, facing as u16)
+    }
+
+    pub fn age(&self) -> Option<u16> {
+        self.get_property(VoxelProperty::Age(0))
+    }
+
+    pub fn with_age(self, age: u16) -> Self {
+        self.with_property(VoxelProperty::Age(0), age)
+    }
+
+    pub fn level(&self) -> Option<u16> {
+        self.get_property(VoxelProperty::Level(0))
+    }
+
+    pub fn with_level(self, level: u16) -> Self {
+        self.with_property(VoxelProperty::Level(0), level)
+    }
+
+    // テクスチャの「上面」が向いている方向
+    // Axis は正の方向、Facing はその方向、どちらもなければ +Y
+    pub fn up_direction(&self) -> IVec3 {
+        if let Some(facing) = self.facing() {
+            facing.direction()
+        } else if let Some(axis) = self.axis() {
+            axis.direction()
+        } else {
+            IVec3::Y
+        }
+    }
+}
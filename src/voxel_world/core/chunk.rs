@@ -3,22 +3,21 @@ use std::ops::Range;
 use bevy::math::UVec3;
 use block_mesh::ndshape::Shape;
 use itertools::iproduct;
-use crate::voxel_world::core::voxel::Voxel;
+use crate::voxel_world::core::{palette::{PalettedVoxels, VoxelMut}, voxel::Voxel};
 
 #[derive(Debug, Clone)]
 pub struct Chunk<S: Shape<3, Coord = u32>> {
-    pub voxels: Box<[Voxel]>,
+    pub voxels: PalettedVoxels,
     pub shape: S,
 }
 
 #[allow(dead_code)]
 impl<S: Shape<3, Coord = u32>> Chunk<S> {
     pub fn new_empty(shape: S) -> Self {
-        let voxels = vec![Voxel::EMPTY; shape.usize()].into_boxed_slice();
-        Self { voxels, shape }
+        Self::new_filled(shape, Voxel::EMPTY)
     }
     pub fn new_filled(shape: S, voxel: Voxel) -> Self {
-        let voxels = vec![voxel; shape.usize()].into_boxed_slice();
+        let voxels = PalettedVoxels::new_filled(shape.usize(), voxel);
         Self { voxels, shape }
     }
 
@@ -30,26 +29,53 @@ impl<S: Shape<3, Coord = u32>> Chunk<S> {
         for (z, y, x) in iproduct!(0..shape.as_array()[2], 0..shape.as_array()[1], 0..shape.as_array()[0]) {
             voxels.push(f(x, y, z));
         }
+        Self::from_vec(shape, voxels)
+    }
+
+    // 線形化済みのボクセル列から作成する
+    pub fn from_vec(shape: S, voxels: Vec<Voxel>) -> Self {
+        assert_eq!(voxels.len(), shape.usize());
         Self {
-            voxels: voxels.into_boxed_slice(),
+            voxels: PalettedVoxels::from_vec(voxels),
             shape,
         }
     }
 
     #[inline]
     pub fn get_at(&self, pos: UVec3) -> Voxel {
-        self.voxels[self.shape.linearize(pos.to_array()) as usize]
+        self.voxels.get(self.shape.linearize(pos.to_array()) as usize)
+    }
+    #[inline]
+    pub fn get_at_mut(&mut self, pos: UVec3) -> VoxelMut<'_> {
+        let index = self.shape.linearize(pos.to_array()) as usize;
+        VoxelMut::new(&mut self.voxels, index)
     }
     #[inline]
-    pub fn get_at_mut(&mut self, pos: UVec3) -> &mut Voxel {
-        &mut self.voxels[self.shape.linearize(pos.to_array()) as usize]
+    pub fn set_at(&mut self, pos: UVec3, voxel: Voxel) {
+        self.voxels.set(self.shape.linearize(pos.to_array()) as usize, voxel);
     }
 
-    pub fn as_slice(&self) -> &[Voxel] {
-        &self.voxels
+    // 全て同じボクセルの場合はそのボクセルを返す
+    pub fn as_single(&self) -> Option<Voxel> {
+        self.voxels.as_single()
     }
+    // 線形化された順番でボクセルを展開する
+    pub fn to_vec(&self) -> Vec<Voxel> {
+        self.voxels.to_vec()
+    }
+    pub fn iter(&self) -> impl Iterator<Item = Voxel> + '_ {
+        self.voxels.iter()
+    }
+    pub fn compact(&mut self) {
+        self.voxels.compact();
+    }
+
     pub fn get_range(&self, range_x: Range<u32>, range_y: Range<u32>, range_z: Range<u32>) -> Vec<Voxel> {
         let mut result = Vec::new();
+        if let Some(voxel) = self.as_single() {
+            result.resize(range_x.len() * range_y.len() * range_z.len(), voxel);
+            return result;
+        }
         for (z, y, x) in iproduct!(range_z, range_y, range_x) {
             let voxel = self.get_at(UVec3::new(x, y, z));
             result.push(voxel);
@@ -1,4 +1,15 @@
 use bevy::prelude::*;
 
+use super::voxel::Voxel;
+
+#[derive(Event, Message, Debug, Clone, Copy)]
+pub struct ChunkGeneratedEvent(pub IVec3);
+
+// 実行時にボクセルが書き換えられたときに送られる
+#[allow(dead_code)]
 #[derive(Event, Message, Debug, Clone, Copy)]
-pub struct ChunkGeneratedEvent(pub IVec3);
\ No newline at end of file
+pub struct VoxelChanged {
+    pub position: IVec3,
+    pub old: Voxel,
+    pub new: Voxel,
+}
\ No newline at end of file
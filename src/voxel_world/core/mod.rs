@@ -1,14 +1,14 @@
 pub mod voxel;
+pub mod block_state;
 pub mod chunk;
+pub mod palette;
 pub mod terrain_chunk;
 pub mod coordinates;
 pub mod chunk_range;
 pub mod events;
 pub mod components;
+pub mod registry;
 
-pub use voxel::*;
-pub use chunk::*;
-pub use terrain_chunk::*;
-pub use coordinates::*;
 pub use events::*;
-pub use components::*;
\ No newline at end of file
+pub use components::*;
+pub use registry::*;
\ No newline at end of file
@@ -0,0 +1,293 @@
+use std::ops::{Deref, DerefMut};
+
+use crate::voxel_world::core::voxel::Voxel;
+
+// 1インデックスあたりのビット数の候補
+// 64の約数に限定することで、インデックスがu64の境界をまたがないようにする
+const BIT_WIDTHS: [u32; 5] = [1, 2, 4, 8, 16];
+
+fn bits_for_palette_len(len: usize) -> u32 {
+    BIT_WIDTHS
+        .into_iter()
+        .find(|&bits| len <= 1usize << bits)
+        .unwrap_or(16)
+}
+
+// パレット + ビットパックされたインデックスによるボクセル列の圧縮表現
+// 全て同じボクセルの場合 (空気だけのチャンク、石だけのチャンクなど) は値を1つだけ保持する
+#[derive(Debug, Clone)]
+pub enum PalettedVoxels {
+    Single {
+        voxel: Voxel,
+        len: usize,
+    },
+    Paletted {
+        palette: Vec<Voxel>,
+        bits: u32,
+        data: Box<[u64]>,
+        len: usize,
+    },
+}
+
+impl PalettedVoxels {
+    pub fn new_filled(len: usize, voxel: Voxel) -> Self {
+        Self::Single { voxel, len }
+    }
+
+    pub fn from_vec(voxels: Vec<Voxel>) -> Self {
+        let len = voxels.len();
+        let mut palette: Vec<Voxel> = Vec::new();
+        let mut indices = Vec::with_capacity(len);
+        let mut last = None;
+        for voxel in voxels {
+            // 地形は同じボクセルが連続しやすいので、直前の結果を使い回す
+            let index = match last {
+                Some((last_voxel, last_index)) if last_voxel == voxel => last_index,
+                _ => match palette.iter().position(|&v| v == voxel) {
+                    Some(index) => index,
+                    None => {
+                        palette.push(voxel);
+                        palette.len() - 1
+                    }
+                },
+            };
+            last = Some((voxel, index));
+            indices.push(index as u32);
+        }
+
+        match palette.len() {
+            0 => Self::Single { voxel: Voxel::EMPTY, len },
+            1 => Self::Single { voxel: palette[0], len },
+            _ => {
+                let bits = bits_for_palette_len(palette.len());
+                let mut data = vec![0u64; Self::words_for(len, bits)].into_boxed_slice();
+                for (i, index) in indices.into_iter().enumerate() {
+                    Self::write_index(&mut data, bits, i, index);
+                }
+                Self::Paletted { palette, bits, data, len }
+            }
+        }
+    }
+
+    #[inline]
+    fn words_for(len: usize, bits: u32) -> usize {
+        let per_word = (64 / bits) as usize;
+        len.div_ceil(per_word)
+    }
+
+    #[inline]
+    fn read_index(data: &[u64], bits: u32, i: usize) -> u32 {
+        let per_word = (64 / bits) as usize;
+        let shift = (i % per_word) as u32 * bits;
+        let mask = (1u64 << bits) - 1;
+        ((data[i / per_word] >> shift) & mask) as u32
+    }
+
+    #[inline]
+    fn write_index(data: &mut [u64], bits: u32, i: usize, index: u32) {
+        let per_word = (64 / bits) as usize;
+        let shift = (i % per_word) as u32 * bits;
+        let mask = (1u64 << bits) - 1;
+        let word = &mut data[i / per_word];
+        *word = (*word & !(mask << shift)) | ((index as u64 & mask) << shift);
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Single { len, .. } | Self::Paletted { len, .. } => *len,
+        }
+    }
+
+    // 全て同じボクセルの場合はそのボクセルを返す
+    pub fn as_single(&self) -> Option<Voxel> {
+        match self {
+            Self::Single { voxel, .. } => Some(*voxel),
+            Self::Paletted { .. } => None,
+        }
+    }
+
+    #[inline]
+    pub fn get(&self, i: usize) -> Voxel {
+        match self {
+            Self::Single { voxel, len } => {
+                debug_assert!(i < *len);
+                *voxel
+            },
+            Self::Paletted { palette, bits, data, .. } => {
+                palette[Self::read_index(data, *bits, i) as usize]
+            },
+        }
+    }
+
+    pub fn set(&mut self, i: usize, new_voxel: Voxel) {
+        match self {
+            Self::Single { voxel, len } => {
+                if *voxel == new_voxel {
+                    return;
+                }
+                // 2種類目のボクセルが現れたのでパレット形式に切り替える
+                let (old_voxel, len) = (*voxel, *len);
+                let bits = 1;
+                let mut data = vec![0u64; Self::words_for(len, bits)].into_boxed_slice();
+                Self::write_index(&mut data, bits, i, 1);
+                *self = Self::Paletted {
+                    palette: vec![old_voxel, new_voxel],
+                    bits,
+                    data,
+                    len,
+                };
+            },
+            Self::Paletted { palette, bits, data, len } => {
+                let index = match palette.iter().position(|&v| v == new_voxel) {
+                    Some(index) => index,
+                    None => {
+                        palette.push(new_voxel);
+                        let required = bits_for_palette_len(palette.len());
+                        if required > *bits {
+                            // パレットが溢れたのでインデックスのビット幅を広げて詰め直す
+                            let mut new_data = vec![0u64; Self::words_for(*len, required)].into_boxed_slice();
+                            for j in 0..*len {
+                                Self::write_index(&mut new_data, required, j, Self::read_index(data, *bits, j));
+                            }
+                            *data = new_data;
+                            *bits = required;
+                        }
+                        palette.len() - 1
+                    }
+                };
+                Self::write_index(data, *bits, i, index as u32);
+            },
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Voxel> + '_ {
+        (0..self.len()).map(move |i| self.get(i))
+    }
+
+    pub fn to_vec(&self) -> Vec<Voxel> {
+        match self {
+            Self::Single { voxel, len } => vec![*voxel; *len],
+            Self::Paletted { .. } => self.iter().collect(),
+        }
+    }
+
+    // 編集によって使われなくなったパレットエントリを取り除き、可能なら単一値形式に戻す
+    pub fn compact(&mut self) {
+        if let Self::Paletted { .. } = self {
+            *self = Self::from_vec(self.to_vec());
+        }
+    }
+}
+
+// PalettedVoxels内の1ボクセルへの可変参照の代わり
+// 変更はドロップ時に書き戻される
+pub struct VoxelMut<'a> {
+    storage: &'a mut PalettedVoxels,
+    index: usize,
+    voxel: Voxel,
+}
+
+impl<'a> VoxelMut<'a> {
+    pub fn new(storage: &'a mut PalettedVoxels, index: usize) -> Self {
+        let voxel = storage.get(index);
+        Self { storage, index, voxel }
+    }
+}
+
+impl Deref for VoxelMut<'_> {
+    type Target = Voxel;
+    fn deref(&self) -> &Voxel {
+        &self.voxel
+    }
+}
+
+impl DerefMut for VoxelMut<'_> {
+    fn deref_mut(&mut self) -> &mut Voxel {
+        &mut self.voxel
+    }
+}
+
+impl Drop for VoxelMut<'_> {
+    fn drop(&mut self) {
+        if self.storage.get(self.index) != self.voxel {
+            self.storage.set(self.index, self.voxel);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn voxel(id: u16) -> Voxel {
+        Voxel::with_state(id, 0)
+    }
+
+    fn palette_len(voxels: &PalettedVoxels) -> usize {
+        match voxels {
+            PalettedVoxels::Single { .. } => 1,
+            PalettedVoxels::Paletted { palette, .. } => palette.len(),
+        }
+    }
+
+    #[test]
+    fn set_then_get_round_trips() {
+        let mut voxels = PalettedVoxels::new_filled(100, Voxel::EMPTY);
+        voxels.set(3, voxel(1));
+        voxels.set(50, Voxel::with_state(2, 7));
+        assert_eq!(voxels.get(3), voxel(1));
+        assert_eq!(voxels.get(50), Voxel::with_state(2, 7));
+        assert_eq!(voxels.get(4), Voxel::EMPTY);
+
+        // 同じ値の書き込みでは単一値形式のまま
+        let mut single = PalettedVoxels::new_filled(10, voxel(1));
+        single.set(0, voxel(1));
+        assert_eq!(single.as_single(), Some(voxel(1)));
+    }
+
+    #[test]
+    fn palette_grows_through_every_bit_width() {
+        let len = 70_000;
+        let mut voxels = PalettedVoxels::new_filled(len, Voxel::EMPTY);
+        let mut expected = vec![Voxel::EMPTY; len];
+        // 2, 3, 5, 17, 257 種類目でそれぞれ 1, 2, 4, 8, 16 ビットになる
+        for (count, bits) in [(2, 1), (3, 2), (5, 4), (17, 8), (257, 16)] {
+            for id in palette_len(&voxels) as u16..count {
+                let i = id as usize * 251 % len;
+                voxels.set(i, voxel(id));
+                expected[i] = voxel(id);
+            }
+            match &voxels {
+                PalettedVoxels::Paletted { bits: actual, palette, .. } => {
+                    assert_eq!(*actual, bits);
+                    assert_eq!(palette.len(), count as usize);
+                },
+                PalettedVoxels::Single { .. } => panic!("expected paletted storage"),
+            }
+            // 詰め直した後も既存の値が変わらない
+            assert_eq!(voxels.to_vec(), expected);
+        }
+    }
+
+    #[test]
+    fn from_vec_then_to_vec_round_trips() {
+        let source: Vec<Voxel> = (0..4096u16).map(|i| Voxel::with_state(i % 37, i % 3)).collect();
+        let voxels = PalettedVoxels::from_vec(source.clone());
+        assert_eq!(voxels.len(), source.len());
+        assert_eq!(palette_len(&voxels), 37 * 3);
+        assert_eq!(voxels.to_vec(), source);
+
+        let uniform = PalettedVoxels::from_vec(vec![voxel(5); 64]);
+        assert_eq!(uniform.as_single(), Some(voxel(5)));
+        assert_eq!(uniform.to_vec(), vec![voxel(5); 64]);
+    }
+
+    #[test]
+    fn compact_drops_unused_entries() {
+        let mut voxels = PalettedVoxels::new_filled(16, Voxel::EMPTY);
+        voxels.set(0, voxel(1));
+        voxels.set(0, Voxel::EMPTY);
+        voxels.compact();
+        assert_eq!(voxels.as_single(), Some(Voxel::EMPTY));
+    }
+}
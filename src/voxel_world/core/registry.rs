@@ -0,0 +1,449 @@
+use bevy::{asset::{io::Reader, AssetLoader, LoadContext}, platform::collections::HashMap, prelude::*};
+use block_mesh::VoxelVisibility;
+use serde::Deserialize;
+
+use crate::voxel_world::{core::voxel::{get_voxel_definitions, BiomeTint, MaterialDef, Voxel, VoxelMaterial}, lighting::MAX_LIGHT};
+
+// 追加・上書きするボクセルを定義するアセット
+pub const VOXEL_DEFINITIONS_PATH: &str = "voxels/default.voxels.ron";
+
+#[derive(Debug, Clone)]
+pub struct VoxelDefinition {
+    pub id: u16,
+    pub name: String,
+    pub visibility: VoxelVisibility,
+    pub material: VoxelMaterial,
+    // プレイヤーなどがぶつかるか
+    pub solid: bool,
+    // 支えを失うと落下するか
+    pub gravity: bool,
+    // 自身が発する光の強さ (0..=15)
+    pub light: u8,
+}
+
+// 実行時のボクセル定義
+// define_voxels! の組み込み定義に、アセットファイルの定義を重ねたもの
+#[derive(Resource, Debug, Clone)]
+pub struct VoxelRegistry {
+    definitions: Vec<VoxelDefinition>,
+    ids_by_name: HashMap<String, u16>,
+}
+
+impl Default for VoxelRegistry {
+    fn default() -> Self {
+        let definitions = get_voxel_definitions()
+            .into_iter()
+            .map(|(id, visibility, material)| VoxelDefinition {
+                id,
+                name: Voxel::new(id).name().to_string(),
+                visibility,
+                solid: is_solid_material(&material),
+                gravity: Voxel::new(id).has_gravity(),
+                light: Voxel::new(id).light_emission(),
+                material,
+            })
+            .collect();
+        Self::from_definitions(definitions)
+    }
+}
+
+impl VoxelRegistry {
+    fn from_definitions(mut definitions: Vec<VoxelDefinition>) -> Self {
+        definitions.sort_by_key(|def| def.id);
+        let ids_by_name = definitions.iter().map(|def| (def.name.clone(), def.id)).collect();
+        Self { definitions, ids_by_name }
+    }
+
+    // 組み込み定義にアセットの定義を適用する
+    // id が省略された場合は同名の定義を上書きし、同名がなければ空いているidを割り当てる
+    pub fn with_asset(asset: &VoxelDefinitionsAsset) -> Result<Self, String> {
+        let mut registry = Self::default();
+        for spec in &asset.voxels {
+            let id = match spec.id.or_else(|| registry.id_by_name(&spec.name)) {
+                Some(id) => id,
+                None => registry.next_free_id().ok_or_else(|| format!("voxel {:?}: no free id left", spec.name))?,
+            };
+            let material: VoxelMaterial = spec.material.clone().into();
+            registry.insert(VoxelDefinition {
+                id,
+                name: spec.name.clone(),
+                visibility: spec.visibility.into(),
+                solid: spec.solid.unwrap_or_else(|| is_solid_material(&material)),
+                gravity: spec.gravity.unwrap_or_else(|| registry.get(id).is_some_and(|def| def.gravity)),
+                light: spec.light.unwrap_or_else(|| registry.get(id).map_or(0, |def| def.light)).min(MAX_LIGHT),
+                material,
+            })?;
+        }
+        Ok(registry)
+    }
+
+    // 同じidと名前の定義は上書きする
+    // idか名前の一方だけが既存の定義と重なる場合は、その定義を消さないようにエラーにする
+    fn insert(&mut self, definition: VoxelDefinition) -> Result<(), String> {
+        if let Some(existing) = self.get(definition.id)
+            && existing.name != definition.name
+        {
+            return Err(format!("voxel {:?}: id {} is already used by {:?}", definition.name, definition.id, existing.name));
+        }
+        if let Some(existing_id) = self.id_by_name(&definition.name)
+            && existing_id != definition.id
+        {
+            return Err(format!("voxel {:?}: already defined with id {existing_id}, not {}", definition.name, definition.id));
+        }
+        self.definitions.retain(|def| def.id != definition.id);
+        self.definitions.push(definition);
+        *self = Self::from_definitions(std::mem::take(&mut self.definitions));
+        Ok(())
+    }
+
+    fn next_free_id(&self) -> Option<u16> {
+        self.definitions.iter().map(|def| def.id).max().map_or(Some(0), |id| id.checked_add(1))
+    }
+
+    pub fn definitions(&self) -> &[VoxelDefinition] {
+        &self.definitions
+    }
+
+    pub fn get(&self, id: u16) -> Option<&VoxelDefinition> {
+        self.definitions
+            .binary_search_by_key(&id, |def| def.id)
+            .ok()
+            .map(|index| &self.definitions[index])
+    }
+
+    pub fn id_by_name(&self, name: &str) -> Option<u16> {
+        self.ids_by_name.get(name).copied()
+    }
+
+    pub fn name(&self, voxel: Voxel) -> &str {
+        self.get(voxel.id).map_or("UNKNOWN", |def| def.name.as_str())
+    }
+
+    // 未定義のボクセルは通り抜けられないものとして扱う
+    pub fn is_solid(&self, voxel: Voxel) -> bool {
+        self.get(voxel.id).is_none_or(|def| def.solid)
+    }
+
+    pub fn has_gravity(&self, voxel: Voxel) -> bool {
+        self.get(voxel.id).is_some_and(|def| def.gravity)
+    }
+
+    // 未定義のボクセルは不透明なものとして扱う
+    pub fn visibility(&self, voxel: Voxel) -> VoxelVisibility {
+        self.get(voxel.id).map_or(VoxelVisibility::Opaque, |def| def.visibility)
+    }
+
+    pub fn is_cross(&self, voxel: Voxel) -> bool {
+        self.get(voxel.id).is_some_and(|def| matches!(def.material, VoxelMaterial::Cross(_)))
+    }
+
+    pub fn light_emission(&self, voxel: Voxel) -> u8 {
+        self.get(voxel.id).map_or(0, |def| def.light)
+    }
+
+    // 光が通り抜けるときに弱まる量
+    // 不透明なボクセルは光を通さず、水や葉のような半透明のボクセルは少しずつ弱める
+    pub fn light_opacity(&self, voxel: Voxel) -> u8 {
+        let Some(def) = self.get(voxel.id) else {
+            return MAX_LIGHT;
+        };
+        match (&def.material, def.visibility) {
+            (VoxelMaterial::Cross(_), _) | (_, VoxelVisibility::Empty) => 0,
+            (_, VoxelVisibility::Translucent) => 1,
+            (_, VoxelVisibility::Opaque) => MAX_LIGHT,
+        }
+    }
+}
+
+// 描画されないボクセル、草花、水は通り抜けられる
+fn is_solid_material(material: &VoxelMaterial) -> bool {
+    !matches!(material, VoxelMaterial::None | VoxelMaterial::Cross(_) | VoxelMaterial::Water(_))
+}
+
+// アセットファイルの形式
+#[derive(Asset, TypePath, Debug, Deserialize)]
+pub struct VoxelDefinitionsAsset {
+    pub voxels: Vec<VoxelDefinitionSpec>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct VoxelDefinitionSpec {
+    pub name: String,
+    #[serde(default)]
+    pub id: Option<u16>,
+    pub visibility: VisibilitySpec,
+    pub material: VoxelMaterialSpec,
+    // 省略した場合はマテリアルから決める (草花と水は通り抜けられる)
+    #[serde(default)]
+    pub solid: Option<bool>,
+    // 省略した場合は同名の組み込み定義に従う
+    #[serde(default)]
+    pub gravity: Option<bool>,
+    #[serde(default)]
+    pub light: Option<u8>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub enum VisibilitySpec {
+    Empty,
+    Translucent,
+    Opaque,
+}
+
+impl From<VisibilitySpec> for VoxelVisibility {
+    fn from(spec: VisibilitySpec) -> Self {
+        match spec {
+            VisibilitySpec::Empty => VoxelVisibility::Empty,
+            VisibilitySpec::Translucent => VoxelVisibility::Translucent,
+            VisibilitySpec::Opaque => VoxelVisibility::Opaque,
+        }
+    }
+}
+
+// アセットを読み込むときに VoxelMaterial に変換するだけで、ボクセルごとに持ち続けることはないので
+// Column を Box にせずそのまま持つ
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, Deserialize)]
+pub enum VoxelMaterialSpec {
+    None,
+    Uniform(MaterialDefSpec),
+    Column {
+        top: MaterialDefSpec,
+        side: MaterialDefSpec,
+        bottom: MaterialDefSpec,
+    },
+    Cross(MaterialDefSpec),
+    Water(MaterialDefSpec),
+}
+
+impl From<VoxelMaterialSpec> for VoxelMaterial {
+    fn from(spec: VoxelMaterialSpec) -> Self {
+        match spec {
+            VoxelMaterialSpec::None => VoxelMaterial::None,
+            VoxelMaterialSpec::Uniform(def) => VoxelMaterial::Uniform(def.into()),
+            VoxelMaterialSpec::Column { top, side, bottom } => VoxelMaterial::Column {
+                top: top.into(),
+                side: side.into(),
+                bottom: bottom.into(),
+            },
+            VoxelMaterialSpec::Cross(def) => VoxelMaterial::Cross(def.into()),
+            VoxelMaterialSpec::Water(def) => VoxelMaterial::Water(def.into()),
+        }
+    }
+}
+
+// 省略したフィールドは MaterialDef::default() の値になる
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MaterialDefSpec {
+    // sRGBA
+    pub color: (f32, f32, f32, f32),
+    pub texture: Option<String>,
+    pub normal_map: Option<String>,
+    pub metallic_roughness_map: Option<String>,
+    pub occlusion_map: Option<String>,
+    pub depth_map: Option<String>,
+    pub parallax_depth_scale: f32,
+    pub metallic: f32,
+    pub roughness: f32,
+    pub reflectance: f32,
+    pub alpha_mode: AlphaModeSpec,
+    // sRGB
+    pub emissive: (f32, f32, f32),
+    pub emissive_strength: f32,
+    pub tint: Option<BiomeTint>,
+    pub sway: bool,
+}
+
+impl Default for MaterialDefSpec {
+    fn default() -> Self {
+        let def = MaterialDef::default();
+        Self {
+            color: (1.0, 1.0, 1.0, 1.0),
+            texture: None,
+            normal_map: None,
+            metallic_roughness_map: None,
+            occlusion_map: None,
+            depth_map: None,
+            parallax_depth_scale: def.parallax_depth_scale,
+            metallic: def.metallic,
+            roughness: def.perceptual_roughness,
+            reflectance: def.reflectance,
+            alpha_mode: AlphaModeSpec::Opaque,
+            emissive: (0.0, 0.0, 0.0),
+            emissive_strength: def.emissive_strength,
+            tint: None,
+            sway: def.sway,
+        }
+    }
+}
+
+impl From<MaterialDefSpec> for MaterialDef {
+    fn from(spec: MaterialDefSpec) -> Self {
+        let (r, g, b, a) = spec.color;
+        let (er, eg, eb) = spec.emissive;
+        Self {
+            base_color: Color::srgba(r, g, b, a),
+            texture: spec.texture,
+            normal_map: spec.normal_map,
+            metallic_roughness_map: spec.metallic_roughness_map,
+            occlusion_map: spec.occlusion_map,
+            depth_map: spec.depth_map,
+            parallax_depth_scale: spec.parallax_depth_scale,
+            metallic: spec.metallic,
+            perceptual_roughness: spec.roughness,
+            reflectance: spec.reflectance,
+            alpha_mode: spec.alpha_mode.into(),
+            emissive: Color::srgb(er, eg, eb),
+            emissive_strength: spec.emissive_strength,
+            tint: spec.tint,
+            sway: spec.sway,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub enum AlphaModeSpec {
+    #[default]
+    Opaque,
+    Blend,
+    Mask(f32),
+}
+
+impl From<AlphaModeSpec> for AlphaMode {
+    fn from(spec: AlphaModeSpec) -> Self {
+        match spec {
+            AlphaModeSpec::Opaque => AlphaMode::Opaque,
+            AlphaModeSpec::Blend => AlphaMode::Blend,
+            AlphaModeSpec::Mask(cutoff) => AlphaMode::Mask(cutoff),
+        }
+    }
+}
+
+#[derive(Default, TypePath)]
+pub struct VoxelDefinitionsLoader;
+
+impl AssetLoader for VoxelDefinitionsLoader {
+    type Asset = VoxelDefinitionsAsset;
+    type Settings = ();
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        _settings: &(),
+        _load_context: &mut LoadContext<'_>,
+    ) -> Result<Self::Asset, Self::Error> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        Ok(ron::de::from_bytes(&bytes)?)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["voxels.ron"]
+    }
+}
+
+#[derive(Resource)]
+pub struct VoxelDefinitionsHandle(pub Handle<VoxelDefinitionsAsset>);
+
+pub fn load_voxel_definitions(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+) {
+    commands.insert_resource(VoxelDefinitionsHandle(asset_server.load(VOXEL_DEFINITIONS_PATH)));
+}
+
+// アセットの読み込み・変更 (file_watcher によるホットリロード) のたびにレジストリを作り直す
+pub fn apply_voxel_definitions(
+    mut events: MessageReader<AssetEvent<VoxelDefinitionsAsset>>,
+    handle: Option<Res<VoxelDefinitionsHandle>>,
+    assets: Res<Assets<VoxelDefinitionsAsset>>,
+    mut registry: ResMut<VoxelRegistry>,
+) {
+    let Some(handle) = handle else {
+        return;
+    };
+    let mut updated = false;
+    for event in events.read() {
+        if event.is_loaded_with_dependencies(&handle.0) || event.is_modified(&handle.0) {
+            updated = true;
+        }
+    }
+    if !updated {
+        return;
+    }
+    if let Some(asset) = assets.get(&handle.0) {
+        match VoxelRegistry::with_asset(asset) {
+            Ok(updated) => {
+                *registry = updated;
+                info!("Loaded {} voxel definitions from {}", asset.voxels.len(), VOXEL_DEFINITIONS_PATH);
+            },
+            // 以前のレジストリをそのまま使う
+            Err(err) => error!("Invalid voxel definitions in {VOXEL_DEFINITIONS_PATH}: {err}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(voxels: &str) -> VoxelDefinitionsAsset {
+        ron::from_str(&format!("#![enable(implicit_some)]\n(voxels: [{voxels}])")).unwrap()
+    }
+
+    #[test]
+    fn id_or_name_clash_is_rejected() {
+        // 既存の STONE の id を別の名前で使う
+        let id_clash = VoxelRegistry::with_asset(&parse(r#"(name: "MARBLE", id: 5, visibility: Opaque, material: Uniform(()))"#));
+        assert!(id_clash.unwrap_err().contains("already used by \"STONE\""));
+
+        // 既存の STONE を別の id で定義し直す
+        let name_clash = VoxelRegistry::with_asset(&parse(r#"(name: "STONE", id: 200, visibility: Opaque, material: Uniform(()))"#));
+        assert!(name_clash.unwrap_err().contains("already defined with id 5"));
+    }
+
+    #[test]
+    fn same_id_and_name_overrides_the_builtin() {
+        let defaults = VoxelRegistry::default();
+        let registry = VoxelRegistry::with_asset(&parse(
+            r#"(name: "STONE", id: 5, visibility: Translucent, material: Uniform(()), solid: false, light: 20),
+               (name: "SAND", visibility: Opaque, material: Uniform(()))"#,
+        ))
+        .unwrap();
+
+        assert_eq!(registry.definitions().len(), defaults.definitions().len());
+        assert_eq!(registry.visibility(Voxel::STONE), VoxelVisibility::Translucent);
+        assert!(!registry.is_solid(Voxel::STONE));
+        // 光の強さは MAX_LIGHT までに抑える
+        assert_eq!(registry.light_emission(Voxel::STONE), MAX_LIGHT);
+        // id を省略すると同名の定義を上書きし、省略した gravity は組み込み定義に従う
+        assert_eq!(registry.id_by_name("SAND"), Some(Voxel::SAND.id));
+        assert_eq!(registry.has_gravity(Voxel::SAND), defaults.has_gravity(Voxel::SAND));
+        assert!(registry.has_gravity(Voxel::SAND));
+    }
+
+    #[test]
+    fn asset_file_layers_over_the_builtin_definitions() {
+        let defaults = VoxelRegistry::default();
+        let asset: VoxelDefinitionsAsset = ron::from_str(include_str!("../../../assets/voxels/default.voxels.ron")).unwrap();
+        let registry = VoxelRegistry::with_asset(&asset).unwrap();
+
+        assert_eq!(registry.definitions().len(), defaults.definitions().len() + asset.voxels.len());
+        for def in defaults.definitions() {
+            assert_eq!(registry.id_by_name(&def.name), Some(def.id));
+        }
+        assert_eq!(registry.id_by_name("BRICKS"), Some(64));
+        assert_eq!(registry.name(Voxel::new(65)), "MOSSY_COBBLESTONE");
+
+        assert_eq!(registry.definitions().iter().map(|def| def.id).max(), Some(65));
+
+        // id を省略した新しいボクセルには最大の id の次が割り当てられる
+        let mut layered = asset;
+        layered.voxels.extend(parse(r#"(name: "BASALT", visibility: Opaque, material: Uniform(()))"#).voxels);
+        let registry = VoxelRegistry::with_asset(&layered).unwrap();
+        assert_eq!(registry.id_by_name("BASALT"), Some(66));
+        assert!(registry.is_solid(Voxel::new(66)));
+    }
+}
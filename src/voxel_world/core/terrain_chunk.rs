@@ -1,9 +1,9 @@
 use bevy::math::{IVec3, UVec3, Vec3};
 use block_mesh::ndshape::ConstShape3u32;
 
-use crate::voxel_world::core::{chunk::Chunk, voxel::Voxel, coordinates::*};
+use crate::voxel_world::core::{chunk::Chunk, palette::VoxelMut, voxel::Voxel, coordinates::*};
 
-type TerrainChunkShape = ConstShape3u32<
+pub type TerrainChunkShape = ConstShape3u32<
     TERRAIN_CHUNK_SIZE,
     TERRAIN_CHUNK_SIZE,
     TERRAIN_CHUNK_SIZE,
@@ -15,6 +15,15 @@ pub type PaddedTerrainChunkShape = ConstShape3u32<
     PADDED_TERRAIN_CHUNK_SIZE,
 >;
 
+// 隣接チャンクのオフセット (各軸 -1..=1) ごとに、パディング込みのチャンクへコピーする範囲
+// (パディング込みの開始位置, 隣接チャンク内の開始位置, 長さ) を各軸について返す
+pub fn padding_span(offset: i32) -> (u32, u32, u32) {
+    match offset {
+        -1 => (0, TERRAIN_CHUNK_SIZE - 1, 1),
+        0 => (1, 0, TERRAIN_CHUNK_SIZE),
+        _ => (TERRAIN_CHUNK_SIZE + 1, 0, 1),
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct TerrainChunkData {
@@ -36,6 +45,13 @@ impl TerrainChunkData {
             position,
         }
     }
+    // 線形化済みのボクセル列から作成する (ディスクからの読み込みなど)
+    pub fn from_vec(position: IVec3, voxels: Vec<Voxel>) -> Self {
+        Self {
+            chunk: Chunk::from_vec(TerrainChunkShape {}, voxels),
+            position,
+        }
+    }
     pub fn new_from_fn<F>(position: IVec3, mut f: F) -> Self
     where 
         F: FnMut(IVec3) -> Voxel,
@@ -67,10 +83,14 @@ impl TerrainChunkData {
         self.chunk.get_at(pos)
     }
     #[inline]
-    pub fn get_local_at_mut(&mut self, pos: UVec3) -> &mut Voxel {
+    pub fn get_local_at_mut(&mut self, pos: UVec3) -> VoxelMut<'_> {
         self.chunk.get_at_mut(pos)
     }
     #[inline]
+    pub fn set_local_at(&mut self, pos: UVec3, voxel: Voxel) {
+        self.chunk.set_at(pos, voxel);
+    }
+    #[inline]
     pub fn get_at(&self, world_pos: IVec3) -> Voxel {
         let local_x = (world_pos.x - self.chunk_origin().x) as u32;
         let local_y = (world_pos.y - self.chunk_origin().y) as u32;
@@ -78,7 +98,7 @@ impl TerrainChunkData {
         self.get_local_at(UVec3::new(local_x, local_y, local_z))
     }
     #[inline]
-    pub fn get_at_mut(&mut self, world_pos: IVec3) -> &mut Voxel {
+    pub fn get_at_mut(&mut self, world_pos: IVec3) -> VoxelMut<'_> {
         let local_x = (world_pos.x - self.chunk_origin().x) as u32;
         let local_y = (world_pos.y - self.chunk_origin().y) as u32;
         let local_z = (world_pos.z - self.chunk_origin().z) as u32;
@@ -1,13 +1,50 @@
 use bevy::prelude::*;
 use block_mesh::{MergeVoxel, Voxel as MergableVoxel, VoxelVisibility};
+use serde::Deserialize;
+
+use crate::voxel_world::core::block_state::VoxelProperty;
+
+// バイオームごとに色が変わる面の種類
+// メッシュを作るときに、周囲のバイオームの色を混ぜて頂点カラーに掛ける
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+pub enum BiomeTint {
+    Grass,
+    Foliage,
+    Water,
+}
+
+impl BiomeTint {
+    pub const COUNT: usize = 3;
+    pub const ALL: [Self; Self::COUNT] = [Self::Grass, Self::Foliage, Self::Water];
+
+    pub fn index(self) -> usize {
+        self as usize
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct MaterialDef {
     pub base_color: Color,
-    pub texture: Option<&'static str>,
+    pub texture: Option<String>,
+    // PBR用のテクスチャ (いずれもリニアで読み込む)
+    pub normal_map: Option<String>,
+    // G: ラフネス, B: メタリック (glTF と同じ)
+    pub metallic_roughness_map: Option<String>,
+    pub occlusion_map: Option<String>,
+    // 視差マッピング用の深度マップ
+    pub depth_map: Option<String>,
+    pub parallax_depth_scale: f32,
+    pub metallic: f32,
     pub perceptual_roughness: f32,
     pub reflectance: f32,
     pub alpha_mode: AlphaMode,
+    // 自己発光の色と強さ (黒なら発光しない)
+    pub emissive: Color,
+    pub emissive_strength: f32,
+    // バイオームの色で染めるか (None なら染めない)
+    pub tint: Option<BiomeTint>,
+    // 風で揺らすか (草花と葉)
+    pub sway: bool,
 }
 
 impl Default for MaterialDef {
@@ -15,9 +52,19 @@ impl Default for MaterialDef {
         Self {
             base_color: Color::WHITE,
             texture: None,
+            normal_map: None,
+            metallic_roughness_map: None,
+            occlusion_map: None,
+            depth_map: None,
+            parallax_depth_scale: 0.1,
+            metallic: 0.0,
             perceptual_roughness: 0.9,
             reflectance: 0.1,
             alpha_mode: AlphaMode::Opaque,
+            emissive: Color::BLACK,
+            emissive_strength: 1.0,
+            tint: None,
+            sway: false,
         }
     }
 }
@@ -29,9 +76,9 @@ impl MaterialDef {
             ..default()
         }
     }
-    pub fn texture(path: &'static str) -> Self {
+    pub fn texture(path: &str) -> Self {
         Self {
-            texture: Some(path),
+            texture: Some(path.to_string()),
             ..default()
         }
     }
@@ -47,8 +94,28 @@ impl MaterialDef {
         self.alpha_mode = alpha_mode;
         self
     }
+    pub fn with_emissive(mut self, color: Color, strength: f32) -> Self {
+        self.emissive = color;
+        self.emissive_strength = strength;
+        self
+    }
+    pub fn with_tint(mut self, tint: BiomeTint) -> Self {
+        self.tint = Some(tint);
+        self
+    }
+    pub fn with_sway(mut self) -> Self {
+        self.sway = true;
+        self
+    }
+    // StandardMaterial の emissive に渡す値
+    pub fn emissive_linear(&self) -> LinearRgba {
+        self.emissive.to_linear() * self.emissive_strength
+    }
 }
 
+// Column は他の3倍の大きさになるが、VoxelRegistry がボクセルの種類ごとに1つ持つだけなので、
+// define_voxels! の定義を書きやすいように Box にせずそのまま持つ
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone)]
 pub enum VoxelMaterial {
     None,
@@ -68,23 +135,42 @@ macro_rules! define_voxels {
             $name:ident = $id:expr => {
                 visibility: $vis:expr,
                 material: $mat:expr
+                $(, gravity: $gravity:expr)?
+                $(, light: $light:expr)?
+                $(, properties: [$($prop:expr),* $(,)?])?
             }
         ),* $(,)?
     ) => {
+        // state には properties で宣言したプロパティの値が詰められている
         #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
         pub struct Voxel {
             pub id: u16,
+            pub state: u16,
         }
 
         impl Voxel {
             $(
                 #[allow(dead_code)]
-                pub const $name: Self = Self { id: $id };
+                pub const $name: Self = Self { id: $id, state: 0 };
             )*
 
             #[allow(dead_code)]
             pub fn new(id: u16) -> Self {
-                Self { id }
+                Self { id, state: 0 }
+            }
+
+            #[allow(dead_code)]
+            pub fn with_state(id: u16, state: u16) -> Self {
+                Self { id, state }
+            }
+
+            pub fn name(&self) -> &'static str {
+                match self.id {
+                    $(
+                        $id => stringify!($name),
+                    )*
+                    _ => "UNKNOWN",
+                }
             }
 
             pub fn visibility(&self) -> VoxelVisibility {
@@ -95,6 +181,36 @@ macro_rules! define_voxels {
                     _ => VoxelVisibility::Opaque,
                 }
             }
+
+            // 支えを失うと落下するボクセルか
+            pub fn has_gravity(&self) -> bool {
+                match self.id {
+                    $(
+                        $id => false $(|| $gravity)?,
+                    )*
+                    _ => false,
+                }
+            }
+
+            // 自身が発する光の強さ (0..=15)
+            pub fn light_emission(&self) -> u8 {
+                match self.id {
+                    $(
+                        $id => 0 $(.max($light))?,
+                    )*
+                    _ => 0,
+                }
+            }
+
+            // このボクセルが持つブロックステートのプロパティ (宣言順)
+            pub fn properties(&self) -> &'static [VoxelProperty] {
+                match self.id {
+                    $(
+                        $id => &[$($($prop),*)?],
+                    )*
+                    _ => &[],
+                }
+            }
         }
 
         pub fn get_voxel_definitions() -> Vec<(u16, VoxelVisibility, VoxelMaterial)> {
@@ -130,7 +246,7 @@ define_voxels! {
     GRASS = 4 => {
         visibility: VoxelVisibility::Opaque,
         material: VoxelMaterial::Column {
-            top: MaterialDef::texture("textures/grass_top.png").with_reflectance(0.2),
+            top: MaterialDef::texture("textures/grass_top.png").with_reflectance(0.2).with_tint(BiomeTint::Grass),
             side: MaterialDef::texture("textures/grass_side.png"),
             bottom: MaterialDef::texture("textures/dirt.png").with_reflectance(0.05)
         }
@@ -145,15 +261,18 @@ define_voxels! {
     },
     GRAVEL = 7 => {
         visibility: VoxelVisibility::Opaque,
-        material: VoxelMaterial::Uniform(MaterialDef::color(Color::srgb(0.4, 0.4, 0.4)))
+        material: VoxelMaterial::Uniform(MaterialDef::color(Color::srgb(0.4, 0.4, 0.4))),
+        gravity: true
     },
     SAND = 8 => {
         visibility: VoxelVisibility::Opaque,
-        material: VoxelMaterial::Uniform(MaterialDef::texture("textures/sand.png"))
+        material: VoxelMaterial::Uniform(MaterialDef::texture("textures/sand.png")),
+        gravity: true
     },
     RED_SAND = 9 => {
         visibility: VoxelVisibility::Opaque,
-        material: VoxelMaterial::Uniform(MaterialDef::color(Color::srgb(0.8, 0.4, 0.1)))
+        material: VoxelMaterial::Uniform(MaterialDef::color(Color::srgb(0.8, 0.4, 0.1))),
+        gravity: true
     },
     MUD = 10 => {
         visibility: VoxelVisibility::Opaque,
@@ -179,79 +298,130 @@ define_voxels! {
     // Liquid
     WATER = 15 => {
         visibility: VoxelVisibility::Translucent,
-        material: VoxelMaterial::Water(MaterialDef::color(Color::srgba(0.0, 0.3, 0.8, 0.5)).with_roughness(0.1).with_alpha_mode(AlphaMode::Blend))
+        material: VoxelMaterial::Water(MaterialDef::color(Color::srgba(0.0, 0.3, 0.8, 0.5)).with_roughness(0.1).with_alpha_mode(AlphaMode::Blend).with_tint(BiomeTint::Water)),
+        // 0: 水源, 1..=7: 流れ (水源から離れるほど大きい), 8: 落下中
+        properties: [VoxelProperty::Level(8)]
     },
 
     // Trees
     OAK_LOG = 16 => {
         visibility: VoxelVisibility::Opaque,
-        material: VoxelMaterial::Uniform(MaterialDef::color(Color::srgb(0.4, 0.25, 0.1)).with_roughness(0.8))
+        material: VoxelMaterial::Column {
+            top: MaterialDef::color(Color::srgb(0.6, 0.45, 0.25)).with_roughness(0.8),
+            side: MaterialDef::color(Color::srgb(0.4, 0.25, 0.1)).with_roughness(0.8),
+            bottom: MaterialDef::color(Color::srgb(0.6, 0.45, 0.25)).with_roughness(0.8)
+        },
+        properties: [VoxelProperty::Axis]
     },
     OAK_LEAVES = 17 => {
         visibility: VoxelVisibility::Translucent,
-        material: VoxelMaterial::Uniform(MaterialDef::color(Color::srgba(0.1, 0.6, 0.1, 0.8)).with_roughness(0.8).with_alpha_mode(AlphaMode::Blend))
+        material: VoxelMaterial::Uniform(MaterialDef::color(Color::srgba(0.1, 0.6, 0.1, 0.8)).with_roughness(0.8).with_alpha_mode(AlphaMode::Blend).with_tint(BiomeTint::Foliage).with_sway())
     },
     PINE_LOG = 18 => {
         visibility: VoxelVisibility::Opaque,
-        material: VoxelMaterial::Uniform(MaterialDef::color(Color::srgb(0.3, 0.2, 0.1)).with_roughness(0.8))
+        material: VoxelMaterial::Column {
+            top: MaterialDef::color(Color::srgb(0.55, 0.4, 0.25)).with_roughness(0.8),
+            side: MaterialDef::color(Color::srgb(0.3, 0.2, 0.1)).with_roughness(0.8),
+            bottom: MaterialDef::color(Color::srgb(0.55, 0.4, 0.25)).with_roughness(0.8)
+        },
+        properties: [VoxelProperty::Axis]
     },
     PINE_LEAVES = 19 => {
         visibility: VoxelVisibility::Translucent,
-        material: VoxelMaterial::Uniform(MaterialDef::color(Color::srgba(0.05, 0.3, 0.05, 0.8)).with_roughness(0.8).with_alpha_mode(AlphaMode::Blend))
+        material: VoxelMaterial::Uniform(MaterialDef::color(Color::srgba(0.05, 0.3, 0.05, 0.8)).with_roughness(0.8).with_alpha_mode(AlphaMode::Blend).with_sway())
     },
     BIRCH_LOG = 20 => {
         visibility: VoxelVisibility::Opaque,
-        material: VoxelMaterial::Uniform(MaterialDef::color(Color::srgb(0.9, 0.9, 0.8)).with_roughness(0.8))
+        material: VoxelMaterial::Column {
+            top: MaterialDef::color(Color::srgb(0.8, 0.7, 0.5)).with_roughness(0.8),
+            side: MaterialDef::color(Color::srgb(0.9, 0.9, 0.8)).with_roughness(0.8),
+            bottom: MaterialDef::color(Color::srgb(0.8, 0.7, 0.5)).with_roughness(0.8)
+        },
+        properties: [VoxelProperty::Axis]
     },
     BIRCH_LEAVES = 21 => {
         visibility: VoxelVisibility::Translucent,
-        material: VoxelMaterial::Uniform(MaterialDef::color(Color::srgba(0.4, 0.8, 0.4, 0.8)).with_roughness(0.8).with_alpha_mode(AlphaMode::Blend))
+        material: VoxelMaterial::Uniform(MaterialDef::color(Color::srgba(0.4, 0.8, 0.4, 0.8)).with_roughness(0.8).with_alpha_mode(AlphaMode::Blend).with_tint(BiomeTint::Foliage).with_sway())
     },
     ACACIA_LOG = 22 => {
         visibility: VoxelVisibility::Opaque,
-        material: VoxelMaterial::Uniform(MaterialDef::color(Color::srgb(0.45, 0.4, 0.35)).with_roughness(0.8))
+        material: VoxelMaterial::Column {
+            top: MaterialDef::color(Color::srgb(0.7, 0.4, 0.2)).with_roughness(0.8),
+            side: MaterialDef::color(Color::srgb(0.45, 0.4, 0.35)).with_roughness(0.8),
+            bottom: MaterialDef::color(Color::srgb(0.7, 0.4, 0.2)).with_roughness(0.8)
+        },
+        properties: [VoxelProperty::Axis]
     },
     ACACIA_LEAVES = 23 => {
         visibility: VoxelVisibility::Translucent,
-        material: VoxelMaterial::Uniform(MaterialDef::color(Color::srgba(0.3, 0.5, 0.1, 0.8)).with_roughness(0.8).with_alpha_mode(AlphaMode::Blend))
+        material: VoxelMaterial::Uniform(MaterialDef::color(Color::srgba(0.3, 0.5, 0.1, 0.8)).with_roughness(0.8).with_alpha_mode(AlphaMode::Blend).with_tint(BiomeTint::Foliage).with_sway())
     },
     JUNGLE_LOG = 24 => {
         visibility: VoxelVisibility::Opaque,
-        material: VoxelMaterial::Uniform(MaterialDef::color(Color::srgb(0.35, 0.2, 0.05)).with_roughness(0.8))
+        material: VoxelMaterial::Column {
+            top: MaterialDef::color(Color::srgb(0.6, 0.45, 0.25)).with_roughness(0.8),
+            side: MaterialDef::color(Color::srgb(0.35, 0.2, 0.05)).with_roughness(0.8),
+            bottom: MaterialDef::color(Color::srgb(0.6, 0.45, 0.25)).with_roughness(0.8)
+        },
+        properties: [VoxelProperty::Axis]
     },
     JUNGLE_LEAVES = 25 => {
         visibility: VoxelVisibility::Translucent,
-        material: VoxelMaterial::Uniform(MaterialDef::color(Color::srgba(0.1, 0.7, 0.1, 0.8)).with_roughness(0.8).with_alpha_mode(AlphaMode::Blend))
+        material: VoxelMaterial::Uniform(MaterialDef::color(Color::srgba(0.1, 0.7, 0.1, 0.8)).with_roughness(0.8).with_alpha_mode(AlphaMode::Blend).with_tint(BiomeTint::Foliage).with_sway())
     },
     CHERRY_LOG = 26 => {
         visibility: VoxelVisibility::Opaque,
-        material: VoxelMaterial::Uniform(MaterialDef::color(Color::srgb(0.4, 0.1, 0.1)).with_roughness(0.8))
+        material: VoxelMaterial::Column {
+            top: MaterialDef::color(Color::srgb(0.7, 0.45, 0.45)).with_roughness(0.8),
+            side: MaterialDef::color(Color::srgb(0.4, 0.1, 0.1)).with_roughness(0.8),
+            bottom: MaterialDef::color(Color::srgb(0.7, 0.45, 0.45)).with_roughness(0.8)
+        },
+        properties: [VoxelProperty::Axis]
     },
     CHERRY_LEAVES = 27 => {
         visibility: VoxelVisibility::Translucent,
-        material: VoxelMaterial::Uniform(MaterialDef::color(Color::srgba(0.9, 0.4, 0.6, 0.8)).with_roughness(0.8).with_alpha_mode(AlphaMode::Blend))
+        material: VoxelMaterial::Uniform(MaterialDef::color(Color::srgba(0.9, 0.4, 0.6, 0.8)).with_roughness(0.8).with_alpha_mode(AlphaMode::Blend).with_sway())
     },
 
     // Plants
     CACTUS = 28 => {
         visibility: VoxelVisibility::Opaque,
-        material: VoxelMaterial::Uniform(MaterialDef::color(Color::srgb(0.1, 0.5, 0.1)).with_roughness(0.8))
+        material: VoxelMaterial::Uniform(MaterialDef::color(Color::srgb(0.1, 0.5, 0.1)).with_roughness(0.8)),
+        properties: [VoxelProperty::Age(15)]
     },
     BAMBOO = 29 => {
         visibility: VoxelVisibility::Opaque,
-        material: VoxelMaterial::Uniform(MaterialDef::color(Color::srgb(0.3, 0.7, 0.2)).with_roughness(0.5))
+        material: VoxelMaterial::Uniform(MaterialDef::color(Color::srgb(0.3, 0.7, 0.2)).with_roughness(0.5)),
+        properties: [VoxelProperty::Age(15)]
     },
     FLOWER_RED = 30 => {
         visibility: VoxelVisibility::Empty,
-        material: VoxelMaterial::Cross(MaterialDef::texture("textures/flower_red.png"))
+        material: VoxelMaterial::Cross(MaterialDef::texture("textures/flower_red.png").with_sway())
     },
     FLOWER_YELLOW = 31 => {
         visibility: VoxelVisibility::Empty,
-        material: VoxelMaterial::Cross(MaterialDef::texture("textures/flower_yellow.png"))
+        material: VoxelMaterial::Cross(MaterialDef::texture("textures/flower_yellow.png").with_sway())
     },
     TALL_GRASS = 32 => {
         visibility: VoxelVisibility::Empty,
-        material: VoxelMaterial::Cross(MaterialDef::color(Color::srgba(0.2, 0.6, 0.2, 0.0)))
+        material: VoxelMaterial::Cross(MaterialDef::color(Color::srgba(0.2, 0.6, 0.2, 0.0)).with_tint(BiomeTint::Grass).with_sway())
+    },
+
+    // Light sources
+    LAVA = 33 => {
+        visibility: VoxelVisibility::Opaque,
+        material: VoxelMaterial::Uniform(MaterialDef::color(Color::srgb(0.9, 0.35, 0.05)).with_roughness(0.6).with_emissive(Color::srgb(1.0, 0.4, 0.05), 4.0)),
+        light: 15
+    },
+    GLOWSTONE = 34 => {
+        visibility: VoxelVisibility::Opaque,
+        material: VoxelMaterial::Uniform(MaterialDef::color(Color::srgb(0.9, 0.8, 0.5)).with_roughness(0.7).with_emissive(Color::srgb(1.0, 0.85, 0.55), 2.0)),
+        light: 15
+    },
+    TORCH = 35 => {
+        visibility: VoxelVisibility::Empty,
+        material: VoxelMaterial::Cross(MaterialDef::texture("textures/torch.png").with_emissive(Color::srgb(1.0, 0.6, 0.2), 3.0)),
+        light: 14
     }
 }
 
@@ -262,9 +432,10 @@ impl MergableVoxel for Voxel {
 }
 
 impl MergeVoxel for Voxel {
-    type MergeValue = u16;
+    type MergeValue = (u16, u16);
 
+    // ステートが異なる面は向きなどが違うので結合しない
     fn merge_value(&self) -> Self::MergeValue {
-        self.id
+        (self.id, self.state)
     }
 }
\ No newline at end of file
@@ -0,0 +1,255 @@
+use bevy::{ecs::system::SystemParam, platform::collections::HashSet, prelude::*};
+use itertools::iproduct;
+
+use crate::voxel_world::{
+    core::{coordinates::TERRAIN_CHUNK_SIZE, registry::VoxelRegistry, voxel::Voxel, ChunkEntities, VoxelChanged},
+    storage::ChunkMap,
+    pipelines::cpu_mesh::meshing::{MeshQueued, NeedImmediateMeshUpdate, NeedMeshUpdate},
+    raycast::{raycast_voxels, VoxelRayHit, VoxelRaycastFilter},
+};
+
+// 実行時にボクセルを読み書きするためのSystemParam
+// 書き込みはChunkMapに反映され、影響を受けるチャンクは再メッシュされる
+#[derive(SystemParam)]
+pub struct VoxelWorld<'w, 's> {
+    chunk_map: ResMut<'w, ChunkMap>,
+    chunk_entities: Res<'w, ChunkEntities>,
+    registry: Res<'w, VoxelRegistry>,
+    changed_writer: MessageWriter<'w, VoxelChanged>,
+    commands: Commands<'w, 's>,
+}
+
+#[allow(dead_code)]
+impl VoxelWorld<'_, '_> {
+    pub fn get_voxel(&self, world_pos: IVec3) -> Option<Voxel> {
+        self.chunk_map.get_at(world_pos)
+    }
+
+    pub fn raycast(&self, origin: Vec3, direction: Vec3, max_distance: f32, filter: &VoxelRaycastFilter) -> Option<VoxelRayHit> {
+        raycast_voxels(&self.chunk_map, &self.registry, origin, direction, max_distance, filter)
+    }
+
+    // ボクセルを書き換え、変更前のボクセルを返す
+    // チャンクが読み込まれていない場合は何もせずNoneを返す
+    pub fn set_voxel(&mut self, world_pos: IVec3, voxel: Voxel) -> Option<Voxel> {
+        let mut touched = HashSet::new();
+        let old = self.write(world_pos, voxel, &mut touched);
+        self.flag_remesh(touched, true);
+        old
+    }
+
+    // 複数のボクセルをまとめて書き換え、実際に変更されたボクセル数を返す
+    pub fn set_voxels(&mut self, changes: impl IntoIterator<Item = (IVec3, Voxel)>) -> usize {
+        let mut touched = HashSet::new();
+        let mut count = 0;
+        for (world_pos, voxel) in changes {
+            if self.write(world_pos, voxel, &mut touched).is_some_and(|old| old != voxel) {
+                count += 1;
+            }
+        }
+        self.flag_remesh(touched, true);
+        count
+    }
+
+    // set_voxels と同じだが、メッシュはその場では作り直さずバックグラウンドで更新する
+    // 流体のように毎ティック少しずつ書き換わるものに使う
+    pub fn set_voxels_deferred(&mut self, changes: impl IntoIterator<Item = (IVec3, Voxel)>) -> usize {
+        let mut touched = HashSet::new();
+        let mut count = 0;
+        for (world_pos, voxel) in changes {
+            if self.write(world_pos, voxel, &mut touched).is_some_and(|old| old != voxel) {
+                count += 1;
+            }
+        }
+        self.flag_remesh(touched, false);
+        count
+    }
+
+    // min から max まで (両端を含む) の直方体をボクセルで埋め、変更されたボクセル数を返す
+    pub fn fill_region(&mut self, min: IVec3, max: IVec3, voxel: Voxel) -> usize {
+        let (min, max) = (min.min(max), min.max(max));
+        let mut touched = HashSet::new();
+        let mut count = 0;
+        for z in min.z..=max.z {
+            for y in min.y..=max.y {
+                for x in min.x..=max.x {
+                    let world_pos = IVec3::new(x, y, z);
+                    if self.write(world_pos, voxel, &mut touched).is_some_and(|old| old != voxel) {
+                        count += 1;
+                    }
+                }
+            }
+        }
+        self.flag_remesh(touched, true);
+        count
+    }
+
+    // 地形生成のフィーチャーを書き込む
+    // 空気・水・雪だけを置き換え、プレイヤーの編集ではないので VoxelChanged は送らない
+    pub fn apply_generated_features(&mut self, changes: Vec<(IVec3, Voxel)>) {
+        let touched = self.chunk_map.set_bulk(changes);
+        self.flag_remesh(touched, false);
+    }
+
+    fn write(&mut self, world_pos: IVec3, voxel: Voxel, touched: &mut HashSet<IVec3>) -> Option<Voxel> {
+        let old = self.chunk_map.set_at(world_pos, voxel)?;
+        if old != voxel {
+            touched.extend(chunks_affected_by(world_pos));
+            self.changed_writer.write(VoxelChanged { position: world_pos, old, new: voxel });
+        }
+        Some(old)
+    }
+
+    // 既にメッシュが作られているチャンクだけを再メッシュする
+    // まだメッシュがないチャンクは、生成完了時に最新のデータでメッシュが作られる
+    fn flag_remesh(&mut self, touched: impl IntoIterator<Item = IVec3>, immediate: bool) {
+        for chunk_pos in touched {
+            let Some(&entity) = self.chunk_entities.entities.get(&chunk_pos) else {
+                continue;
+            };
+            self.commands.queue(move |world: &mut World| {
+                if let Ok(mut entity_world) = world.get_entity_mut(entity)
+                    && entity_world.contains::<MeshQueued>()
+                {
+                    if immediate {
+                        entity_world.insert(NeedImmediateMeshUpdate);
+                    } else {
+                        entity_world.insert(NeedMeshUpdate);
+                    }
+                }
+            });
+        }
+    }
+}
+
+// ボクセルの変更によってメッシュが変わるチャンクを返す
+// 自身のチャンクに加え、get_padded_chunk_vec のパディングにこのボクセルを含む隣接チャンク (面・辺・角)
+pub fn chunks_affected_by(world_pos: IVec3) -> impl Iterator<Item = IVec3> {
+    let size = TERRAIN_CHUNK_SIZE as i32;
+    let chunk_pos = world_pos.div_euclid(IVec3::splat(size));
+    let local = world_pos.rem_euclid(IVec3::splat(size));
+
+    // 各軸で、境界にあればその向きの隣も含める
+    let offsets = |axis: usize| {
+        if local[axis] == 0 {
+            -1..=0
+        } else if local[axis] == size - 1 {
+            0..=1
+        } else {
+            0..=0
+        }
+    };
+    iproduct!(offsets(0), offsets(1), offsets(2)).map(move |(dx, dy, dz)| chunk_pos + IVec3::new(dx, dy, dz))
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::RunSystemOnce;
+    use itertools::Itertools;
+
+    use super::*;
+    use crate::voxel_world::core::terrain_chunk::TerrainChunkData;
+
+    const SIZE: i32 = TERRAIN_CHUNK_SIZE as i32;
+
+    fn affected(world_pos: IVec3) -> Vec<IVec3> {
+        chunks_affected_by(world_pos).sorted_by_key(|pos| pos.to_array()).collect()
+    }
+
+    #[test]
+    fn affected_chunks_cover_faces_edges_and_corners() {
+        let chunk = IVec3::new(2, -1, 0);
+        let origin = chunk * SIZE;
+
+        assert_eq!(affected(origin + IVec3::splat(10)), vec![chunk]);
+        // 面: 2チャンク
+        assert_eq!(affected(origin + IVec3::new(0, 10, 10)), vec![chunk - IVec3::X, chunk]);
+        assert_eq!(affected(origin + IVec3::new(10, SIZE - 1, 10)), vec![chunk, chunk + IVec3::Y]);
+        // 辺: 4チャンク
+        let edge = affected(origin + IVec3::new(0, SIZE - 1, 10));
+        assert_eq!(edge.len(), 4);
+        assert!(edge.contains(&(chunk - IVec3::X + IVec3::Y)));
+        // 角: 8チャンク
+        let corner = affected(origin + IVec3::new(SIZE - 1, 0, 0));
+        assert_eq!(corner.len(), 8);
+        assert!(corner.contains(&(chunk + IVec3::new(1, -1, -1))));
+        assert!(corner.iter().all(|pos| (*pos - chunk).abs().max_element() <= 1));
+
+        // 負の座標でもチャンクの境界で分かれる
+        assert_eq!(affected(IVec3::new(-1, 5, 5)), vec![IVec3::new(-1, 0, 0), IVec3::ZERO]);
+    }
+
+    #[test]
+    fn set_at_marks_dirty_only_on_change() {
+        let mut chunk_map = ChunkMap::default();
+        chunk_map.insert(TerrainChunkData::new_empty(IVec3::ZERO));
+
+        assert_eq!(chunk_map.set_at(IVec3::new(1, 2, 3), Voxel::EMPTY), Some(Voxel::EMPTY));
+        assert!(chunk_map.dirty.is_empty());
+
+        assert_eq!(chunk_map.set_at(IVec3::new(1, 2, 3), Voxel::STONE), Some(Voxel::EMPTY));
+        assert!(chunk_map.dirty.contains(&IVec3::ZERO));
+        assert_eq!(chunk_map.get_at(IVec3::new(1, 2, 3)), Some(Voxel::STONE));
+
+        // 読み込まれていないチャンクには書き込まない
+        assert_eq!(chunk_map.set_at(IVec3::new(-1, 0, 0), Voxel::STONE), None);
+        assert_eq!(chunk_map.dirty.len(), 1);
+    }
+
+    fn world_with_chunks(positions: &[IVec3]) -> World {
+        let mut world = World::new();
+        let mut chunk_map = ChunkMap::default();
+        for &position in positions {
+            chunk_map.insert(TerrainChunkData::new_empty(position));
+        }
+        world.insert_resource(chunk_map);
+        world.init_resource::<ChunkEntities>();
+        world.init_resource::<VoxelRegistry>();
+        world.init_resource::<Messages<VoxelChanged>>();
+        world
+    }
+
+    fn changed_positions(world: &World) -> Vec<IVec3> {
+        let messages = world.resource::<Messages<VoxelChanged>>();
+        messages.iter_current_update_messages().map(|changed| changed.position).collect()
+    }
+
+    #[test]
+    fn set_voxels_counts_and_reports_only_changes() {
+        let mut world = world_with_chunks(&[IVec3::ZERO]);
+        let changes = [
+            (IVec3::new(1, 1, 1), Voxel::STONE),
+            (IVec3::new(2, 1, 1), Voxel::EMPTY),
+            // 読み込まれていないチャンク
+            (IVec3::new(-1, 1, 1), Voxel::STONE),
+        ];
+        let count = world.run_system_once(move |mut voxel_world: VoxelWorld| voxel_world.set_voxels(changes)).unwrap();
+
+        assert_eq!(count, 1);
+        assert_eq!(changed_positions(&world), vec![IVec3::new(1, 1, 1)]);
+        let chunk_map = world.resource::<ChunkMap>();
+        assert_eq!(chunk_map.get_at(IVec3::new(1, 1, 1)), Some(Voxel::STONE));
+        assert!(chunk_map.get_at(IVec3::new(-1, 1, 1)).is_none());
+    }
+
+    #[test]
+    fn fill_region_fills_inclusive_box_across_chunks() {
+        let mut world = world_with_chunks(&[IVec3::ZERO, IVec3::X]);
+        // 端を逆に渡しても同じ直方体になる
+        let (min, max) = (IVec3::new(SIZE + 1, 3, 2), IVec3::new(SIZE - 2, 2, 2));
+        let count = world.run_system_once(move |mut voxel_world: VoxelWorld| voxel_world.fill_region(min, max, Voxel::STONE)).unwrap();
+
+        assert_eq!(count, 4 * 2);
+        assert_eq!(changed_positions(&world).len(), 8);
+        let chunk_map = world.resource::<ChunkMap>();
+        for (x, y) in iproduct!(SIZE - 2..=SIZE + 1, 2..=3) {
+            assert_eq!(chunk_map.get_at(IVec3::new(x, y, 2)), Some(Voxel::STONE));
+        }
+        assert_eq!(chunk_map.get_at(IVec3::new(SIZE + 2, 2, 2)), Some(Voxel::EMPTY));
+        assert_eq!(chunk_map.dirty.len(), 2);
+
+        // 同じ範囲をもう一度埋めても何も変わらない
+        let count = world.run_system_once(move |mut voxel_world: VoxelWorld| voxel_world.fill_region(min, max, Voxel::STONE)).unwrap();
+        assert_eq!(count, 0);
+    }
+}
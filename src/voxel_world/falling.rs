@@ -0,0 +1,213 @@
+use bevy::{platform::collections::HashSet, prelude::*};
+use block_mesh::ndshape::{ConstShape, ConstShape3u32};
+
+use crate::voxel_world::{
+    core::{coordinates::VOXEL_SIZE, registry::VoxelRegistry, voxel::Voxel, VoxelChanged},
+    editing::VoxelWorld,
+    lighting::{LightMap, UNLIT},
+    pipelines::cpu_mesh::{biome_tint::ChunkTints, material::MaterialRepository},
+};
+
+// 落下中のボクセル1つ分のメッシュを作るためのチャンク (周囲1ボクセルは空気)
+type FallingBlockShape = ConstShape3u32<3, 3, 3>;
+
+// 落下の加速度と速度の上限 (ボクセル/秒)
+const FALLING_GRAVITY: f32 = 24.0;
+const MAX_FALLING_SPEED: f32 = 40.0;
+
+// 支えを失ってワールドから取り出され、落下しているボクセル
+// Transform はボクセルの中心
+#[derive(Component, Debug)]
+pub struct FallingBlock {
+    pub voxel: Voxel,
+    pub velocity: f32,
+}
+
+// 支えを確認する位置
+#[derive(Resource, Debug, Default)]
+pub struct PendingGravityChecks(HashSet<IVec3>);
+
+// ボクセルが書き換えられたら、その位置と真上のボクセルが落ちるか確認する
+pub fn schedule_gravity_checks(
+    mut events: MessageReader<VoxelChanged>,
+    mut pending: ResMut<PendingGravityChecks>,
+) {
+    for event in events.read() {
+        pending.0.insert(event.position);
+        pending.0.insert(event.position + IVec3::Y);
+    }
+}
+
+// 下が通り抜けられるボクセルになった重力ボクセルを落下中のエンティティに置き換える
+pub fn start_falling_blocks(
+    mut commands: Commands,
+    mut pending: ResMut<PendingGravityChecks>,
+    mut voxel_world: VoxelWorld,
+    registry: Res<VoxelRegistry>,
+    material_repo: Res<MaterialRepository>,
+    light_map: Res<LightMap>,
+    mut meshes: ResMut<Assets<Mesh>>,
+) {
+    for world_pos in pending.0.drain() {
+        let Some(voxel) = voxel_world.get_voxel(world_pos) else {
+            continue;
+        };
+        if !registry.has_gravity(voxel) {
+            continue;
+        }
+        let supported = voxel_world
+            .get_voxel(world_pos - IVec3::Y)
+            .is_none_or(|below| registry.is_solid(below));
+        if supported {
+            continue;
+        }
+
+        voxel_world.set_voxel(world_pos, Voxel::EMPTY);
+        let mut voxels = [Voxel::EMPTY; FallingBlockShape::USIZE];
+        voxels[FallingBlockShape::linearize([1, 1, 1]) as usize] = voxel;
+        // 落下中は元の位置の明るさのまま
+        let light = light_map.get_at(world_pos).unwrap_or(UNLIT);
+        let generated_meshes = material_repo.create_mesh(&FallingBlockShape {}, &voxels, &[light; FallingBlockShape::USIZE], &ChunkTints::default());
+        commands
+            .spawn((
+                FallingBlock { voxel, velocity: 0.0 },
+                Transform::from_translation((world_pos.as_vec3() + Vec3::splat(0.5)) * VOXEL_SIZE),
+                Visibility::default(),
+            ))
+            .with_children(|parent| {
+                // メッシュはチャンク内の (1, 1, 1) に作られるので、中心が原点になるようにずらす
+                parent
+                    .spawn((Transform::from_translation(Vec3::splat(-1.5 * VOXEL_SIZE)), Visibility::default()))
+                    .with_children(|parent| {
+                        for (material, mesh) in generated_meshes {
+                            material.spawn(parent, meshes.add(mesh));
+                        }
+                    });
+            });
+    }
+}
+
+// 落下中のボクセルを1フレーム動かした結果
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FallStep {
+    // 真下のチャンクが読み込まれるまで空中で待つ
+    Wait,
+    // 障害物に当たらずに移動先まで落ちる
+    Fall,
+    // この位置に着地してワールドに書き戻す
+    Land(IVec3),
+    // この位置で止まって次のフレームを待つ
+    Stop(IVec3),
+}
+
+// 底面の高さが bottom から next_bottom (ボクセル単位) まで落ちるときの結果
+// voxel_at は読み込まれていない位置では None を返す
+fn fall_step(
+    column: IVec2,
+    bottom: f32,
+    next_bottom: f32,
+    voxel_at: impl Fn(IVec3) -> Option<Voxel>,
+    registry: &VoxelRegistry,
+) -> FallStep {
+    let current = IVec3::new(column.x, bottom.floor() as i32, column.y);
+    if voxel_at(current - IVec3::Y).is_none() {
+        return FallStep::Wait;
+    }
+    // 今いるボクセルの下から、移動先のボクセルまでを上から順に調べる
+    // 読み込まれていないボクセルでも止まり、その手前でチャンクが読み込まれるまで待つ
+    let obstacle = ((next_bottom.floor() as i32)..current.y)
+        .rev()
+        .map(|y| IVec3::new(column.x, y, column.y))
+        .find(|pos| voxel_at(*pos).is_none_or(|voxel| registry.is_solid(voxel)));
+
+    let Some(obstacle) = obstacle else {
+        return FallStep::Fall;
+    };
+    let landing = obstacle + IVec3::Y;
+    match (voxel_at(obstacle), voxel_at(landing)) {
+        (Some(_), Some(voxel)) if !registry.is_solid(voxel) => FallStep::Land(landing),
+        // 着地した位置が埋まっている場合 (落下中に置かれたなど) はその上に積む
+        (Some(_), Some(_)) => FallStep::Stop(landing + IVec3::Y),
+        _ => FallStep::Stop(landing),
+    }
+}
+
+// 落下中のボクセルを動かし、通り抜けられないボクセルの上に着地したらワールドに書き戻す
+pub fn update_falling_blocks(
+    mut commands: Commands,
+    mut blocks: Query<(Entity, &mut FallingBlock, &mut Transform)>,
+    mut voxel_world: VoxelWorld,
+    registry: Res<VoxelRegistry>,
+    time: Res<Time>,
+) {
+    let dt = time.delta_secs();
+    for (entity, mut block, mut transform) in &mut blocks {
+        let bottom = transform.translation.y / VOXEL_SIZE - 0.5;
+        let column = (transform.translation / VOXEL_SIZE).floor().as_ivec3().xz();
+        let velocity = (block.velocity - FALLING_GRAVITY * dt).max(-MAX_FALLING_SPEED);
+        let next_bottom = bottom + velocity * dt;
+
+        match fall_step(column, bottom, next_bottom, |pos| voxel_world.get_voxel(pos), &registry) {
+            FallStep::Wait => block.velocity = 0.0,
+            FallStep::Fall => {
+                block.velocity = velocity;
+                transform.translation.y = (next_bottom + 0.5) * VOXEL_SIZE;
+            },
+            FallStep::Land(landing) => {
+                voxel_world.set_voxel(landing, block.voxel);
+                commands.entity(entity).despawn();
+            },
+            FallStep::Stop(position) => {
+                transform.translation.y = (position.y as f32 + 0.5) * VOXEL_SIZE;
+                block.velocity = 0.0;
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::platform::collections::HashMap;
+
+    use super::*;
+
+    // y < 0 が石の地面で、それより上は空気。y が unloaded_below 未満は読み込まれていない
+    fn step(bottom: f32, next_bottom: f32, placed: &[(i32, Voxel)], unloaded_below: i32) -> FallStep {
+        let placed: HashMap<i32, Voxel> = placed.iter().copied().collect();
+        let voxel_at = |pos: IVec3| {
+            if pos.y < unloaded_below {
+                None
+            } else if let Some(&voxel) = placed.get(&pos.y) {
+                Some(voxel)
+            } else {
+                Some(if pos.y < 0 { Voxel::STONE } else { Voxel::EMPTY })
+            }
+        };
+        fall_step(IVec2::new(3, 5), bottom, next_bottom, voxel_at, &VoxelRegistry::default())
+    }
+
+    #[test]
+    fn falls_through_air_and_lands_on_ground() {
+        assert_eq!(step(10.0, 9.2, &[], i32::MIN), FallStep::Fall);
+        // 移動先が地面を越えても、地面の上に着地する
+        assert_eq!(step(2.5, -3.0, &[], i32::MIN), FallStep::Land(IVec3::new(3, 0, 5)));
+        // 水や草花は通り抜けて、その下の地面に着地する
+        let passable = [(1, Voxel::WATER), (0, Voxel::FLOWER_RED)];
+        assert_eq!(step(3.0, -3.0, &passable, i32::MIN), FallStep::Land(IVec3::new(3, 0, 5)));
+    }
+
+    #[test]
+    fn stacks_on_voxel_placed_at_landing() {
+        // 砂の上に着地する直前に、落下中のボクセルと重なる y = 1 にボクセルが置かれた
+        let placed = [(1, Voxel::STONE), (0, Voxel::SAND)];
+        assert_eq!(step(1.5, -2.0, &placed, i32::MIN), FallStep::Stop(IVec3::new(3, 2, 5)));
+    }
+
+    #[test]
+    fn waits_for_unloaded_chunks() {
+        // 真下が読み込まれていない
+        assert_eq!(step(10.0, 9.0, &[], 10), FallStep::Wait);
+        // 移動の途中で読み込まれていない位置に入る場合は、その手前で止まる
+        assert_eq!(step(10.0, 2.0, &[], 6), FallStep::Stop(IVec3::new(3, 6, 5)));
+    }
+}
@@ -0,0 +1,358 @@
+use std::collections::VecDeque;
+
+use bevy::{platform::collections::{HashMap, HashSet}, prelude::*};
+
+use crate::voxel_world::{
+    core::{registry::VoxelRegistry, voxel::Voxel, VoxelChanged},
+    editing::VoxelWorld,
+};
+
+// 水の水位 (WATER の Level プロパティ)
+pub const SOURCE_LEVEL: u16 = 0;
+// 水源から流れて届く最大の距離
+pub const MAX_FLOW_LEVEL: u16 = 7;
+// 上から落ちてきている水
+pub const FALLING_LEVEL: u16 = 8;
+
+// 空気に接する水源の水面の高さ (ボクセル単位)
+// 上のボクセルとの間に隙間を空けて、波で上下しても上のボクセルにめり込まないようにする
+pub const WATER_SURFACE_HEIGHT: f32 = 0.875;
+
+// 流体を更新する間隔
+pub const FLUID_TICK_MILLIS: u64 = 250;
+// 1ティックで更新するボクセル数の上限。残りは次のティックに回す
+const MAX_FLUID_UPDATES_PER_TICK: usize = 1024;
+
+const HORIZONTAL_DIRECTIONS: [IVec3; 4] = [IVec3::X, IVec3::NEG_X, IVec3::Z, IVec3::NEG_Z];
+
+// 更新を待っている位置 (古い順)
+#[derive(Resource, Debug, Default)]
+pub struct FluidSimulation {
+    queue: VecDeque<IVec3>,
+    scheduled: HashSet<IVec3>,
+}
+
+impl FluidSimulation {
+    pub fn schedule(&mut self, world_pos: IVec3) {
+        if self.scheduled.insert(world_pos) {
+            self.queue.push_back(world_pos);
+        }
+    }
+}
+
+// 水位から水面の高さ (ボクセル単位、0..=1) を求める
+// 上に水がある場合はボクセルいっぱいまで満たし、水源・落下中の水は WATER_SURFACE_HEIGHT まで満たす
+pub fn water_surface_height(voxel: Voxel, water_above: bool) -> f32 {
+    match voxel.level().unwrap_or(SOURCE_LEVEL) {
+        _ if water_above => 1.0,
+        SOURCE_LEVEL | FALLING_LEVEL => WATER_SURFACE_HEIGHT,
+        level => (FALLING_LEVEL - level.min(MAX_FLOW_LEVEL)) as f32 / FALLING_LEVEL as f32 * WATER_SURFACE_HEIGHT,
+    }
+}
+
+fn water(level: u16) -> Voxel {
+    Voxel::WATER.with_level(level)
+}
+
+fn is_water(voxel: Option<Voxel>) -> bool {
+    voxel.is_some_and(|voxel| voxel.is(Voxel::WATER))
+}
+
+fn is_source(voxel: Option<Voxel>) -> bool {
+    voxel.is_some_and(|voxel| voxel.is(Voxel::WATER) && voxel.level() == Some(SOURCE_LEVEL))
+}
+
+// 同じ位置に複数の書き込みがあった場合に優先する強さ
+// 水源 > 落下中 > 水位の小さい流れ > 水以外
+fn strength(voxel: Voxel) -> u16 {
+    if !voxel.is(Voxel::WATER) {
+        return 0;
+    }
+    match voxel.level().unwrap_or(SOURCE_LEVEL) {
+        SOURCE_LEVEL => FALLING_LEVEL + 2,
+        FALLING_LEVEL => FALLING_LEVEL + 1,
+        level => FALLING_LEVEL - level,
+    }
+}
+
+struct FluidRules<'a, 'w, 's> {
+    world: &'a VoxelWorld<'w, 's>,
+    registry: &'a VoxelRegistry,
+}
+
+impl FluidRules<'_, '_, '_> {
+    fn get(&self, world_pos: IVec3) -> Option<Voxel> {
+        self.world.get_voxel(world_pos)
+    }
+
+    // 水位 level の水がこのボクセルに流れ込めるか
+    // 空気や草花は押し流し、水位の大きい (弱い) 流れは上書きする
+    // 読み込まれていないチャンクには流れない
+    fn can_flow_into(&self, target: Option<Voxel>, level: u16) -> bool {
+        match target {
+            None => false,
+            Some(voxel) if voxel.is(Voxel::WATER) => {
+                let current = voxel.level().unwrap_or(SOURCE_LEVEL);
+                current != SOURCE_LEVEL && current != FALLING_LEVEL
+                    && (level == FALLING_LEVEL || current > level)
+            },
+            Some(voxel) => !self.registry.is_solid(voxel),
+        }
+    }
+
+    // 横に広がるときに隣に与える水位。横に広がらない場合はNone
+    fn spread_level(&self, world_pos: IVec3, voxel: Voxel) -> Option<u16> {
+        let level = voxel.level().unwrap_or(SOURCE_LEVEL);
+        let below = self.get(world_pos - IVec3::Y);
+        // 下に流れられるときは横には広がらない
+        if self.can_flow_into(below, FALLING_LEVEL) {
+            return None;
+        }
+        // 落ちている水や流れの上では広がらない (水源の上は地面と同じように広がる)
+        if is_water(below) && !is_source(below) {
+            return None;
+        }
+        let spread = match level {
+            SOURCE_LEVEL | FALLING_LEVEL => 1,
+            level => level + 1,
+        };
+        (spread <= MAX_FLOW_LEVEL).then_some(spread)
+    }
+
+    // 周囲から決まる水源以外の水の水位。Noneなら水は消える
+    fn expected_level(&self, world_pos: IVec3) -> Option<u16> {
+        if is_water(self.get(world_pos + IVec3::Y)) {
+            return Some(FALLING_LEVEL);
+        }
+        // 2つ以上の水源に挟まれ、下が支えられていれば新しい水源になる
+        let sources = HORIZONTAL_DIRECTIONS
+            .iter()
+            .filter(|&&dir| is_source(self.get(world_pos + dir)))
+            .count();
+        let below = self.get(world_pos - IVec3::Y);
+        let supported = is_source(below) || below.is_some_and(|voxel| self.registry.is_solid(voxel));
+        if sources >= 2 && supported {
+            return Some(SOURCE_LEVEL);
+        }
+        HORIZONTAL_DIRECTIONS
+            .iter()
+            .filter_map(|&dir| {
+                let neighbor_pos = world_pos + dir;
+                let neighbor = self.get(neighbor_pos).filter(|voxel| voxel.is(Voxel::WATER))?;
+                self.spread_level(neighbor_pos, neighbor)
+            })
+            .min()
+    }
+
+    // 1つの位置の更新による書き込みを changes に加える
+    fn update(&self, world_pos: IVec3, changes: &mut Vec<(IVec3, Voxel)>) {
+        let Some(mut voxel) = self.get(world_pos).filter(|voxel| voxel.is(Voxel::WATER)) else {
+            return;
+        };
+        // 水源以外は周囲の水に合わせて水位を決め直す
+        if voxel.level() != Some(SOURCE_LEVEL) {
+            match self.expected_level(world_pos) {
+                None => {
+                    changes.push((world_pos, Voxel::EMPTY));
+                    return;
+                },
+                Some(level) if Some(level) != voxel.level() => {
+                    voxel = water(level);
+                    changes.push((world_pos, voxel));
+                },
+                Some(_) => {},
+            }
+        }
+
+        let below_pos = world_pos - IVec3::Y;
+        if self.can_flow_into(self.get(below_pos), FALLING_LEVEL) {
+            changes.push((below_pos, water(FALLING_LEVEL)));
+            return;
+        }
+        let Some(spread) = self.spread_level(world_pos, voxel) else {
+            return;
+        };
+        for dir in HORIZONTAL_DIRECTIONS {
+            let neighbor_pos = world_pos + dir;
+            if self.can_flow_into(self.get(neighbor_pos), spread) {
+                changes.push((neighbor_pos, water(spread)));
+            }
+        }
+    }
+}
+
+// ボクセルが書き換えられたら、その位置と隣接する位置の水を更新する
+pub fn schedule_fluid_updates(
+    mut events: MessageReader<VoxelChanged>,
+    mut simulation: ResMut<FluidSimulation>,
+) {
+    for event in events.read() {
+        simulation.schedule(event.position);
+        for dir in HORIZONTAL_DIRECTIONS.into_iter().chain([IVec3::Y, IVec3::NEG_Y]) {
+            simulation.schedule(event.position + dir);
+        }
+    }
+}
+
+// 待っている位置を古い順に最大 MAX_FLUID_UPDATES_PER_TICK 個だけ更新する
+// すべての更新は同じティックの開始時の状態から計算し、まとめて書き込む
+pub fn tick_fluids(
+    mut simulation: ResMut<FluidSimulation>,
+    registry: Res<VoxelRegistry>,
+    mut voxel_world: VoxelWorld,
+) {
+    let count = simulation.queue.len().min(MAX_FLUID_UPDATES_PER_TICK);
+    if count == 0 {
+        return;
+    }
+    let mut changes = Vec::new();
+    {
+        let rules = FluidRules { world: &voxel_world, registry: &registry };
+        for _ in 0..count {
+            let Some(world_pos) = simulation.queue.pop_front() else {
+                break;
+            };
+            simulation.scheduled.remove(&world_pos);
+            rules.update(world_pos, &mut changes);
+        }
+    }
+
+    // 同じ位置への書き込みは強い水を優先する
+    let mut merged: HashMap<IVec3, Voxel> = HashMap::new();
+    for (world_pos, voxel) in changes {
+        merged
+            .entry(world_pos)
+            .and_modify(|current| {
+                if strength(voxel) > strength(*current) {
+                    *current = voxel;
+                }
+            })
+            .or_insert(voxel);
+    }
+    voxel_world.set_voxels_deferred(merged);
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::{schedule::Schedule, system::RunSystemOnce};
+
+    use super::*;
+    use crate::voxel_world::{
+        core::{terrain_chunk::TerrainChunkData, ChunkEntities},
+        storage::ChunkMap,
+    };
+
+    // 石の床 (y = 0) を敷いたチャンクと、流体を更新するスケジュール
+    fn fluid_world() -> (World, Schedule) {
+        let mut chunk_map = ChunkMap::default();
+        chunk_map.insert(TerrainChunkData::new_from_fn_local(IVec3::ZERO, |local| {
+            if local.y == 0 { Voxel::STONE } else { Voxel::EMPTY }
+        }));
+        let mut world = World::new();
+        world.insert_resource(chunk_map);
+        world.init_resource::<ChunkEntities>();
+        world.init_resource::<VoxelRegistry>();
+        world.init_resource::<Messages<VoxelChanged>>();
+        world.init_resource::<FluidSimulation>();
+        let mut schedule = Schedule::default();
+        schedule.add_systems((schedule_fluid_updates, tick_fluids).chain());
+        (world, schedule)
+    }
+
+    fn set(world: &mut World, world_pos: IVec3, voxel: Voxel) {
+        let old = world.resource_mut::<ChunkMap>().set_at(world_pos, voxel).unwrap();
+        world.write_message(VoxelChanged { position: world_pos, old, new: voxel });
+    }
+
+    // 更新待ちがなくなるまでティックを進める
+    fn settle(world: &mut World, schedule: &mut Schedule) {
+        for _ in 0..200 {
+            schedule.run(world);
+            world.resource_mut::<Messages<VoxelChanged>>().update();
+            if world.resource::<FluidSimulation>().queue.is_empty() && world.resource::<Messages<VoxelChanged>>().is_empty() {
+                return;
+            }
+        }
+        panic!("fluid simulation did not settle");
+    }
+
+    fn get(world: &World, world_pos: IVec3) -> Voxel {
+        world.resource::<ChunkMap>().get_at(world_pos).unwrap()
+    }
+
+    #[test]
+    fn source_spreads_up_to_max_flow_level() {
+        let (mut world, mut schedule) = fluid_world();
+        let source = IVec3::new(20, 1, 20);
+        set(&mut world, source, water(SOURCE_LEVEL));
+        settle(&mut world, &mut schedule);
+
+        assert_eq!(get(&world, source), water(SOURCE_LEVEL));
+        for distance in 1..=MAX_FLOW_LEVEL as i32 {
+            assert_eq!(get(&world, source + IVec3::X * distance), water(distance as u16));
+        }
+        assert_eq!(get(&world, source + IVec3::X * (MAX_FLOW_LEVEL as i32 + 1)), Voxel::EMPTY);
+        // 水位は水源からのマンハッタン距離になる
+        assert_eq!(get(&world, source + IVec3::new(2, 0, -3)), water(5));
+        assert_eq!(get(&world, source + IVec3::new(4, 0, 4)), Voxel::EMPTY);
+        // 床より上には広がらない
+        assert_eq!(get(&world, source + IVec3::new(1, 1, 0)), Voxel::EMPTY);
+    }
+
+    #[test]
+    fn falling_water_drains_and_refills() {
+        let (mut world, mut schedule) = fluid_world();
+        let source = IVec3::new(20, 10, 20);
+        set(&mut world, source, water(SOURCE_LEVEL));
+        settle(&mut world, &mut schedule);
+
+        for y in 1..source.y {
+            assert_eq!(get(&world, IVec3::new(source.x, y, source.z)), water(FALLING_LEVEL));
+        }
+        // 落下中の水は横に広がらず、床に着いたところから広がる
+        assert_eq!(get(&world, IVec3::new(source.x + 1, 5, source.z)), Voxel::EMPTY);
+        assert_eq!(get(&world, IVec3::new(source.x + 1, 1, source.z)), water(1));
+
+        // 水源を取り除くと流れはすべて消える
+        set(&mut world, source, Voxel::EMPTY);
+        settle(&mut world, &mut schedule);
+        assert!((1..=source.y).all(|y| get(&world, IVec3::new(source.x, y, source.z)) == Voxel::EMPTY));
+        assert_eq!(get(&world, IVec3::new(source.x + 1, 1, source.z)), Voxel::EMPTY);
+
+        // 戻すと同じように満たされる
+        set(&mut world, source, water(SOURCE_LEVEL));
+        settle(&mut world, &mut schedule);
+        assert_eq!(get(&world, IVec3::new(source.x, 1, source.z)), water(FALLING_LEVEL));
+        assert_eq!(get(&world, IVec3::new(source.x + 1, 1, source.z)), water(1));
+    }
+
+    #[test]
+    fn two_sources_fill_the_gap_between_them() {
+        let (mut world, mut schedule) = fluid_world();
+        set(&mut world, IVec3::new(20, 1, 20), water(SOURCE_LEVEL));
+        set(&mut world, IVec3::new(22, 1, 20), water(SOURCE_LEVEL));
+        settle(&mut world, &mut schedule);
+        assert_eq!(get(&world, IVec3::new(21, 1, 20)), water(SOURCE_LEVEL));
+    }
+
+    #[test]
+    fn tick_updates_at_most_the_budget() {
+        let (mut world, _) = fluid_world();
+        let scheduled = MAX_FLUID_UPDATES_PER_TICK + 100;
+        {
+            let mut simulation = world.resource_mut::<FluidSimulation>();
+            for i in 0..scheduled as i32 {
+                simulation.schedule(IVec3::new(i % 64, 1, i / 64));
+            }
+            // 同じ位置は二重に並ばない
+            simulation.schedule(IVec3::new(0, 1, 0));
+        }
+        world.run_system_once(tick_fluids).unwrap();
+
+        let simulation = world.resource::<FluidSimulation>();
+        assert_eq!(simulation.queue.len(), 100);
+        assert_eq!(simulation.scheduled.len(), 100);
+        // 残りは古い順に次のティックへ回る
+        assert_eq!(simulation.queue.front(), Some(&IVec3::new(MAX_FLUID_UPDATES_PER_TICK as i32 % 64, 1, MAX_FLUID_UPDATES_PER_TICK as i32 / 64)));
+    }
+}
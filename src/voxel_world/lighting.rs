@@ -0,0 +1,623 @@
+use std::collections::VecDeque;
+
+use bevy::{platform::collections::{HashMap, HashSet}, prelude::*};
+use block_mesh::ndshape::ConstShape;
+use itertools::iproduct;
+
+use crate::voxel_world::{
+    core::{
+        coordinates::TERRAIN_CHUNK_SIZE,
+        registry::VoxelRegistry,
+        terrain_chunk::{padding_span, PaddedTerrainChunkShape, TerrainChunkShape},
+        ChunkEntities, TerrainChunk, VoxelChanged,
+    },
+    editing::chunks_affected_by,
+    pipelines::cpu_mesh::meshing::{MeshQueued, NeedMeshUpdate},
+    storage::ChunkMap,
+};
+
+pub const MAX_LIGHT: u8 = 15;
+// まだ明るさを計算していない場所は空の光が届いているものとして扱う
+pub const UNLIT: u8 = pack_light(MAX_LIGHT, 0);
+// 1フレームで明るさを計算するチャンク数の上限
+const MAX_LIGHT_CHUNKS_PER_FRAME: usize = 4;
+
+const CHUNK_SIZE: i32 = TERRAIN_CHUNK_SIZE as i32;
+const DIRECTIONS: [IVec3; 6] = [IVec3::NEG_X, IVec3::NEG_Y, IVec3::NEG_Z, IVec3::X, IVec3::Y, IVec3::Z];
+
+// 空の光を上位4ビット、ブロックの光を下位4ビットに詰める
+pub const fn pack_light(sky: u8, block: u8) -> u8 {
+    (sky << 4) | block
+}
+
+pub fn sky_light(packed: u8) -> u8 {
+    packed >> 4
+}
+
+pub fn block_light(packed: u8) -> u8 {
+    packed & 0x0F
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LightChannel {
+    Sky,
+    Block,
+}
+
+impl LightChannel {
+    const ALL: [Self; 2] = [Self::Sky, Self::Block];
+
+    fn get(self, packed: u8) -> u8 {
+        match self {
+            Self::Sky => sky_light(packed),
+            Self::Block => block_light(packed),
+        }
+    }
+
+    fn set(self, packed: u8, level: u8) -> u8 {
+        match self {
+            Self::Sky => pack_light(level, block_light(packed)),
+            Self::Block => pack_light(sky_light(packed), level),
+        }
+    }
+}
+
+// direction 方向の隣のボクセルに伝わる明るさ
+// 空の光は遮るものがなければ真下に弱まらずに届く
+fn propagate(channel: LightChannel, level: u8, opacity: u8, direction: IVec3) -> u8 {
+    if opacity >= MAX_LIGHT {
+        return 0;
+    }
+    if channel == LightChannel::Sky && direction == IVec3::NEG_Y && level == MAX_LIGHT && opacity == 0 {
+        return MAX_LIGHT;
+    }
+    level.saturating_sub(1 + opacity)
+}
+
+fn local_index(local: IVec3) -> usize {
+    TerrainChunkShape::linearize(local.as_uvec3().to_array()) as usize
+}
+
+fn split_world_pos(world_pos: IVec3) -> (IVec3, usize) {
+    let chunk_pos = world_pos.div_euclid(IVec3::splat(CHUNK_SIZE));
+    (chunk_pos, local_index(world_pos.rem_euclid(IVec3::splat(CHUNK_SIZE))))
+}
+
+// チャンク内の各ボクセルの明るさ
+// 空気だけのチャンクや地中のチャンクは全て同じ明るさになるので値を1つだけ保持する
+#[derive(Debug, Clone)]
+pub enum ChunkLight {
+    Uniform(u8),
+    Mixed(Box<[u8]>),
+}
+
+impl ChunkLight {
+    fn from_vec(light: Vec<u8>) -> Self {
+        match light.first() {
+            Some(&first) if light.iter().all(|&level| level == first) => Self::Uniform(first),
+            _ => Self::Mixed(light.into_boxed_slice()),
+        }
+    }
+
+    pub fn get(&self, index: usize) -> u8 {
+        match self {
+            Self::Uniform(packed) => *packed,
+            Self::Mixed(data) => data[index],
+        }
+    }
+
+    fn set(&mut self, index: usize, packed: u8) {
+        if let Self::Uniform(current) = self {
+            if *current == packed {
+                return;
+            }
+            *self = Self::Mixed(vec![*current; TerrainChunkShape::USIZE].into_boxed_slice());
+        }
+        if let Self::Mixed(data) = self {
+            data[index] = packed;
+        }
+    }
+}
+
+// 読み込まれているチャンクの明るさ
+#[derive(Resource, Debug, Default)]
+pub struct LightMap {
+    chunks: HashMap<IVec3, ChunkLight>,
+}
+
+impl LightMap {
+    pub fn contains(&self, chunk_pos: &IVec3) -> bool {
+        self.chunks.contains_key(chunk_pos)
+    }
+
+    pub fn get_at(&self, world_pos: IVec3) -> Option<u8> {
+        let (chunk_pos, index) = split_world_pos(world_pos);
+        self.chunks.get(&chunk_pos).map(|light| light.get(index))
+    }
+
+    fn set_at(&mut self, world_pos: IVec3, packed: u8) {
+        let (chunk_pos, index) = split_world_pos(world_pos);
+        if let Some(light) = self.chunks.get_mut(&chunk_pos) {
+            light.set(index, packed);
+        }
+    }
+
+    // meshingする際に使用。get_padded_chunk_vec と同じ並びで周囲1ボクセルを含めた明るさを返す
+    // 明るさが計算されていない場所は UNLIT になる
+    pub fn get_padded_light(&self, chunk_pos: &IVec3) -> Vec<u8> {
+        let mut padded = vec![UNLIT; PaddedTerrainChunkShape::USIZE];
+        // 中心チャンク (オフセット0) と周囲26チャンクから、パディングに入る範囲をまとめてコピーする
+        for (dx, dy, dz) in iproduct!(-1..=1, -1..=1, -1..=1) {
+            let Some(light) = self.chunks.get(&(*chunk_pos + IVec3::new(dx, dy, dz))) else {
+                continue;
+            };
+            if let ChunkLight::Uniform(UNLIT) = light {
+                continue;
+            }
+            let [(px, lx, nx), (py, ly, ny), (pz, lz, nz)] = [padding_span(dx), padding_span(dy), padding_span(dz)];
+            for (x, y, z) in iproduct!(0..nx, 0..ny, 0..nz) {
+                let local = UVec3::new(lx + x, ly + y, lz + z).as_ivec3();
+                padded[PaddedTerrainChunkShape::linearize([px + x, py + y, pz + z]) as usize] = light.get(local_index(local));
+            }
+        }
+        padded
+    }
+}
+
+// メッシュを初めて作るチャンクのうち、まだ明るさを計算していないもの
+#[derive(Resource, Debug, Default)]
+pub struct LightingQueue(HashSet<IVec3>);
+
+impl LightingQueue {
+    pub fn is_pending(&self, chunk_pos: &IVec3) -> bool {
+        self.0.contains(chunk_pos)
+    }
+}
+
+// 明るさの増減を、読み込まれているチャンク全体に幅優先で広げる
+// 明るさが変わったボクセルを含むメッシュのチャンクを touched に集める
+struct LightPropagation<'a> {
+    light_map: &'a mut LightMap,
+    chunk_map: &'a ChunkMap,
+    registry: &'a VoxelRegistry,
+    increase: VecDeque<(IVec3, LightChannel)>,
+    decrease: VecDeque<(IVec3, LightChannel, u8)>,
+    touched: HashSet<IVec3>,
+}
+
+impl<'a> LightPropagation<'a> {
+    fn new(light_map: &'a mut LightMap, chunk_map: &'a ChunkMap, registry: &'a VoxelRegistry) -> Self {
+        Self {
+            light_map,
+            chunk_map,
+            registry,
+            increase: VecDeque::new(),
+            decrease: VecDeque::new(),
+            touched: HashSet::new(),
+        }
+    }
+
+    // 明るさが計算されていないチャンクには光を広げない
+    fn get(&self, world_pos: IVec3, channel: LightChannel) -> Option<u8> {
+        self.light_map.get_at(world_pos).map(|packed| channel.get(packed))
+    }
+
+    fn set(&mut self, world_pos: IVec3, channel: LightChannel, level: u8) {
+        let Some(packed) = self.light_map.get_at(world_pos) else {
+            return;
+        };
+        self.light_map.set_at(world_pos, channel.set(packed, level));
+        self.touched.extend(chunks_affected_by(world_pos));
+    }
+
+    fn opacity(&self, world_pos: IVec3) -> u8 {
+        self.chunk_map
+            .get_at(world_pos)
+            .map_or(MAX_LIGHT, |voxel| self.registry.light_opacity(voxel))
+    }
+
+    // 周囲に関係なくそのボクセルが持つ明るさ
+    // 自身が光るボクセルと、上のチャンクの明るさがまだ無いときのチャンク最上段の空の光
+    fn source_level(&self, world_pos: IVec3, channel: LightChannel) -> u8 {
+        match channel {
+            LightChannel::Block => self.chunk_map
+                .get_at(world_pos)
+                .map_or(0, |voxel| self.registry.light_emission(voxel)),
+            LightChannel::Sky => {
+                let above = world_pos + IVec3::Y;
+                let open_sky = above.y.rem_euclid(CHUNK_SIZE) == 0
+                    && !self.light_map.contains(&above.div_euclid(IVec3::splat(CHUNK_SIZE)));
+                if open_sky {
+                    propagate(channel, MAX_LIGHT, self.opacity(world_pos), IVec3::NEG_Y)
+                } else {
+                    0
+                }
+            },
+        }
+    }
+
+    fn add_source(&mut self, world_pos: IVec3, channel: LightChannel, level: u8) {
+        if self.get(world_pos, channel).is_some_and(|current| level > current) {
+            self.set(world_pos, channel, level);
+            self.increase.push_back((world_pos, channel));
+        }
+    }
+
+    // 明るさを取り除き、そこから光を受けていた周囲のボクセルも取り除く
+    // 取り除いたボクセルの光源は付け直す
+    fn remove(&mut self, world_pos: IVec3, channel: LightChannel) {
+        let Some(level) = self.get(world_pos, channel).filter(|&level| level > 0) else {
+            return;
+        };
+        self.set(world_pos, channel, 0);
+        self.decrease.push_back((world_pos, channel, level));
+        self.add_source(world_pos, channel, self.source_level(world_pos, channel));
+    }
+
+    // 周囲から光を受け直すように隣のボクセルを広げ直す
+    fn refill_from_neighbors(&mut self, world_pos: IVec3) {
+        for channel in LightChannel::ALL {
+            for direction in DIRECTIONS {
+                self.increase.push_back((world_pos + direction, channel));
+            }
+        }
+    }
+
+    fn run(&mut self) {
+        while let Some((world_pos, channel, level)) = self.decrease.pop_front() {
+            for direction in DIRECTIONS {
+                let neighbor = world_pos + direction;
+                let Some(neighbor_level) = self.get(neighbor, channel).filter(|&level| level > 0) else {
+                    continue;
+                };
+                let lit_by_removed = neighbor_level < level
+                    || (channel == LightChannel::Sky && direction == IVec3::NEG_Y && level == MAX_LIGHT && neighbor_level == MAX_LIGHT);
+                if lit_by_removed {
+                    self.set(neighbor, channel, 0);
+                    self.decrease.push_back((neighbor, channel, neighbor_level));
+                    self.add_source(neighbor, channel, self.source_level(neighbor, channel));
+                } else {
+                    // 別の光源から光を受けているボクセルから広げ直す
+                    self.increase.push_back((neighbor, channel));
+                }
+            }
+        }
+
+        while let Some((world_pos, channel)) = self.increase.pop_front() {
+            let Some(level) = self.get(world_pos, channel).filter(|&level| level > 0) else {
+                continue;
+            };
+            for direction in DIRECTIONS {
+                let neighbor = world_pos + direction;
+                let Some(current) = self.get(neighbor, channel) else {
+                    continue;
+                };
+                let next = propagate(channel, level, self.opacity(neighbor), direction);
+                if next > current {
+                    self.set(neighbor, channel, next);
+                    self.increase.push_back((neighbor, channel));
+                }
+            }
+        }
+    }
+}
+
+// チャンク内だけで明るさを計算する
+// 光源は真上から差し込む空の光 (上のチャンクの明るさがなければ空が開けているものとする)、
+// 光るボクセル、明るさが計算済みの隣接チャンクの境界
+fn compute_chunk_light(chunk_pos: IVec3, chunk_map: &ChunkMap, light_map: &LightMap, registry: &VoxelRegistry) -> Option<Vec<u8>> {
+    let chunk = chunk_map.get(&chunk_pos)?;
+    let voxels = chunk.chunk.to_vec();
+    let opacity: Vec<u8> = voxels.iter().map(|&voxel| registry.light_opacity(voxel)).collect();
+    let mut light = vec![0u8; TerrainChunkShape::USIZE];
+    let mut queue = VecDeque::new();
+
+    let above_light = light_map.chunks.get(&(chunk_pos + IVec3::Y));
+    for (x, z) in iproduct!(0..CHUNK_SIZE, 0..CHUNK_SIZE) {
+        let mut level = above_light.map_or(MAX_LIGHT, |light| sky_light(light.get(local_index(IVec3::new(x, 0, z)))));
+        for y in (0..CHUNK_SIZE).rev() {
+            let index = local_index(IVec3::new(x, y, z));
+            level = propagate(LightChannel::Sky, level, opacity[index], IVec3::NEG_Y);
+            if level == 0 {
+                break;
+            }
+            light[index] = pack_light(level, 0);
+            queue.push_back((index, LightChannel::Sky));
+        }
+    }
+
+    for (index, &voxel) in voxels.iter().enumerate() {
+        let emission = registry.light_emission(voxel);
+        if emission > 0 {
+            light[index] = LightChannel::Block.set(light[index], emission);
+            queue.push_back((index, LightChannel::Block));
+        }
+    }
+
+    // 隣接チャンクの境界のボクセルから光を受ける
+    for direction in DIRECTIONS {
+        let Some(neighbor_light) = light_map.chunks.get(&(chunk_pos + direction)) else {
+            continue;
+        };
+        for (a, b) in iproduct!(0..CHUNK_SIZE, 0..CHUNK_SIZE) {
+            let border = match direction {
+                IVec3 { x: 0, y: 0, .. } => IVec3::new(a, b, if direction.z > 0 { CHUNK_SIZE - 1 } else { 0 }),
+                IVec3 { x: 0, .. } => IVec3::new(a, if direction.y > 0 { CHUNK_SIZE - 1 } else { 0 }, b),
+                _ => IVec3::new(if direction.x > 0 { CHUNK_SIZE - 1 } else { 0 }, a, b),
+            };
+            let outside = (border + direction).rem_euclid(IVec3::splat(CHUNK_SIZE));
+            let index = local_index(border);
+            let neighbor_packed = neighbor_light.get(local_index(outside));
+            for channel in LightChannel::ALL {
+                let incoming = propagate(channel, channel.get(neighbor_packed), opacity[index], -direction);
+                if incoming > channel.get(light[index]) {
+                    light[index] = channel.set(light[index], incoming);
+                    queue.push_back((index, channel));
+                }
+            }
+        }
+    }
+
+    while let Some((index, channel)) = queue.pop_front() {
+        let level = channel.get(light[index]);
+        let local = IVec3::from_array(TerrainChunkShape::delinearize(index as u32).map(|c| c as i32));
+        for direction in DIRECTIONS {
+            let neighbor = local + direction;
+            if neighbor.cmplt(IVec3::ZERO).any() || neighbor.cmpge(IVec3::splat(CHUNK_SIZE)).any() {
+                continue;
+            }
+            let neighbor_index = local_index(neighbor);
+            let next = propagate(channel, level, opacity[neighbor_index], direction);
+            if next > channel.get(light[neighbor_index]) {
+                light[neighbor_index] = channel.set(light[neighbor_index], next);
+                queue.push_back((neighbor_index, channel));
+            }
+        }
+    }
+    Some(light)
+}
+
+// チャンクの明るさを計算し、隣接チャンクとの間で光をやりとりする
+// 明るさが変わってメッシュを作り直す必要があるチャンクを返す
+fn light_chunk(chunk_pos: IVec3, light_map: &mut LightMap, chunk_map: &ChunkMap, registry: &VoxelRegistry) -> HashSet<IVec3> {
+    let Some(light) = compute_chunk_light(chunk_pos, chunk_map, light_map, registry) else {
+        return HashSet::new();
+    };
+    light_map.chunks.insert(chunk_pos, ChunkLight::from_vec(light));
+
+    let mut propagation = LightPropagation::new(light_map, chunk_map, registry);
+    // 下のチャンクは空が開けているものとして計算されているので、このチャンクに遮られる空の光を取り除く
+    let origin = chunk_pos * CHUNK_SIZE;
+    for (x, z) in iproduct!(0..CHUNK_SIZE, 0..CHUNK_SIZE) {
+        let bottom = origin + IVec3::new(x, 0, z);
+        let below = bottom - IVec3::Y;
+        let (Some(bottom_level), Some(below_level)) = (
+            propagation.get(bottom, LightChannel::Sky),
+            propagation.get(below, LightChannel::Sky),
+        ) else {
+            continue;
+        };
+        let expected = propagate(LightChannel::Sky, bottom_level, propagation.opacity(below), IVec3::NEG_Y);
+        if below_level > expected {
+            propagation.remove(below, LightChannel::Sky);
+        }
+    }
+    // 境界のボクセルから隣接チャンクへ光を広げる
+    for (a, b) in iproduct!(0..CHUNK_SIZE, 0..CHUNK_SIZE) {
+        for border in [
+            IVec3::new(0, a, b), IVec3::new(CHUNK_SIZE - 1, a, b),
+            IVec3::new(a, 0, b), IVec3::new(a, CHUNK_SIZE - 1, b),
+            IVec3::new(a, b, 0), IVec3::new(a, b, CHUNK_SIZE - 1),
+        ] {
+            for channel in LightChannel::ALL {
+                propagation.increase.push_back((origin + border, channel));
+            }
+        }
+    }
+    propagation.run();
+
+    let mut dirty = propagation.touched;
+    dirty.insert(chunk_pos);
+    // このチャンクの明るさが計算される前に作られた隣接チャンクのメッシュは、パディングを UNLIT として扱っている
+    // 境界の明るさが UNLIT と異なる隣接チャンクだけを作り直す
+    let light = &light_map.chunks[&chunk_pos];
+    for (dx, dy, dz) in iproduct!(-1..=1, -1..=1, -1..=1) {
+        let offset = IVec3::new(dx, dy, dz);
+        if offset == IVec3::ZERO || dirty.contains(&(chunk_pos + offset)) {
+            continue;
+        }
+        // 隣接チャンクのパディングに入る、このチャンク側の範囲
+        let border = |offset: i32| match offset {
+            -1 => 0..=0,
+            0 => 0..=CHUNK_SIZE - 1,
+            _ => CHUNK_SIZE - 1..=CHUNK_SIZE - 1,
+        };
+        let changed = iproduct!(border(dx), border(dy), border(dz))
+            .any(|(x, y, z)| light.get(local_index(IVec3::new(x, y, z))) != UNLIT);
+        if changed {
+            dirty.insert(chunk_pos + offset);
+        }
+    }
+    dirty
+}
+
+// 既にメッシュが作られているチャンクだけを再メッシュする
+fn flag_remesh(commands: &mut Commands, chunk_entities: &ChunkEntities, dirty: HashSet<IVec3>) {
+    for chunk_pos in dirty {
+        let Some(&entity) = chunk_entities.entities.get(&chunk_pos) else {
+            continue;
+        };
+        commands.queue(move |world: &mut World| {
+            if let Ok(mut entity_world) = world.get_entity_mut(entity)
+                && entity_world.contains::<MeshQueued>()
+            {
+                entity_world.insert(NeedMeshUpdate);
+            }
+        });
+    }
+}
+
+// 初めてメッシュを作るチャンクの明るさを計算する
+// 隣接チャンクのフィーチャーが書き込まれた後なので、木などによる影も含まれる
+pub fn queue_chunk_lighting(
+    chunks: Query<&TerrainChunk, (Added<NeedMeshUpdate>, Without<MeshQueued>)>,
+    mut queue: ResMut<LightingQueue>,
+) {
+    for chunk in chunks.iter() {
+        queue.0.insert(chunk.position);
+    }
+}
+
+// 上のチャンクから順に、1フレームあたり MAX_LIGHT_CHUNKS_PER_FRAME 個ずつ明るさを計算する
+pub fn light_queued_chunks(
+    mut commands: Commands,
+    mut queue: ResMut<LightingQueue>,
+    mut light_map: ResMut<LightMap>,
+    chunk_map: Res<ChunkMap>,
+    registry: Res<VoxelRegistry>,
+    chunk_entities: Res<ChunkEntities>,
+) {
+    if queue.0.is_empty() {
+        return;
+    }
+    queue.0.retain(|chunk_pos| chunk_map.chunks.contains_key(chunk_pos));
+    let mut ready: Vec<IVec3> = queue.0
+        .iter()
+        .copied()
+        .filter(|chunk_pos| !queue.0.contains(&(*chunk_pos + IVec3::Y)))
+        .collect();
+    ready.sort_by_key(|chunk_pos| (-chunk_pos.y, chunk_pos.x, chunk_pos.z));
+    for chunk_pos in ready.into_iter().take(MAX_LIGHT_CHUNKS_PER_FRAME) {
+        queue.0.remove(&chunk_pos);
+        let dirty = light_chunk(chunk_pos, &mut light_map, &chunk_map, &registry);
+        flag_remesh(&mut commands, &chunk_entities, dirty);
+    }
+}
+
+// 書き換えられたボクセルの周囲の明るさを更新する
+pub fn update_light_for_changes(
+    mut commands: Commands,
+    mut events: MessageReader<VoxelChanged>,
+    mut light_map: ResMut<LightMap>,
+    chunk_map: Res<ChunkMap>,
+    registry: Res<VoxelRegistry>,
+    chunk_entities: Res<ChunkEntities>,
+) {
+    let changed: HashSet<IVec3> = events.read().map(|event| event.position).collect();
+    if changed.is_empty() {
+        return;
+    }
+    let mut propagation = LightPropagation::new(&mut light_map, &chunk_map, &registry);
+    for &world_pos in &changed {
+        for channel in LightChannel::ALL {
+            propagation.remove(world_pos, channel);
+            let source = propagation.source_level(world_pos, channel);
+            propagation.add_source(world_pos, channel, source);
+        }
+    }
+    propagation.run();
+    for &world_pos in &changed {
+        propagation.refill_from_neighbors(world_pos);
+    }
+    propagation.run();
+    let dirty = propagation.touched;
+    flag_remesh(&mut commands, &chunk_entities, dirty);
+}
+
+// アンロードされたチャンクの明るさを破棄する
+pub fn drop_unloaded_light(
+    mut light_map: ResMut<LightMap>,
+    chunk_map: Res<ChunkMap>,
+) {
+    light_map.chunks.retain(|chunk_pos, _| chunk_map.chunks.contains_key(chunk_pos));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::voxel_world::core::{terrain_chunk::TerrainChunkData, voxel::Voxel};
+
+    fn chunk_map(chunks: &[IVec3], voxels: &[(IVec3, Voxel)]) -> ChunkMap {
+        let mut chunk_map = ChunkMap::default();
+        for &position in chunks {
+            chunk_map.insert(TerrainChunkData::new_empty(position));
+        }
+        for &(world_pos, voxel) in voxels {
+            chunk_map.set_at(world_pos, voxel).unwrap();
+        }
+        chunk_map
+    }
+
+    fn sky_at(light_map: &LightMap, world_pos: IVec3) -> u8 {
+        sky_light(light_map.get_at(world_pos).unwrap())
+    }
+
+    fn block_at(light_map: &LightMap, world_pos: IVec3) -> u8 {
+        block_light(light_map.get_at(world_pos).unwrap())
+    }
+
+    #[test]
+    fn propagate_keeps_full_sky_light_only_straight_down() {
+        assert_eq!(propagate(LightChannel::Sky, MAX_LIGHT, 0, IVec3::NEG_Y), MAX_LIGHT);
+        assert_eq!(propagate(LightChannel::Sky, MAX_LIGHT, 0, IVec3::X), MAX_LIGHT - 1);
+        assert_eq!(propagate(LightChannel::Sky, MAX_LIGHT - 1, 0, IVec3::NEG_Y), MAX_LIGHT - 2);
+        assert_eq!(propagate(LightChannel::Block, MAX_LIGHT, 0, IVec3::NEG_Y), MAX_LIGHT - 1);
+        assert_eq!(propagate(LightChannel::Block, 5, 2, IVec3::X), 2);
+        assert_eq!(propagate(LightChannel::Sky, MAX_LIGHT, MAX_LIGHT, IVec3::NEG_Y), 0);
+    }
+
+    #[test]
+    fn sky_light_reaches_the_bottom_at_full_strength() {
+        let roof = IVec3::new(10, 50, 10);
+        let registry = VoxelRegistry::default();
+        let chunk_map = chunk_map(&[IVec3::ZERO], &[(roof, Voxel::STONE)]);
+        let mut light_map = LightMap::default();
+        light_chunk(IVec3::ZERO, &mut light_map, &chunk_map, &registry);
+
+        assert_eq!(sky_at(&light_map, IVec3::new(5, CHUNK_SIZE - 1, 5)), MAX_LIGHT);
+        assert_eq!(sky_at(&light_map, IVec3::new(5, 0, 5)), MAX_LIGHT);
+        assert_eq!(sky_at(&light_map, roof), 0);
+        // 遮られた真下には横から1段弱まった光が回り込む
+        assert_eq!(sky_at(&light_map, roof - IVec3::Y), MAX_LIGHT - 1);
+        assert_eq!(sky_at(&light_map, IVec3::new(10, 0, 10)), MAX_LIGHT - 1);
+    }
+
+    #[test]
+    fn block_light_drops_by_one_per_step() {
+        let torch = IVec3::new(20, 20, 20);
+        let registry = VoxelRegistry::default();
+        let emission = registry.light_emission(Voxel::TORCH);
+        let chunk_map = chunk_map(&[IVec3::ZERO], &[(torch, Voxel::TORCH)]);
+        let mut light_map = LightMap::default();
+        light_chunk(IVec3::ZERO, &mut light_map, &chunk_map, &registry);
+
+        assert_eq!(block_at(&light_map, torch), emission);
+        for distance in 1..=emission as i32 {
+            let expected = emission - distance as u8;
+            assert_eq!(block_at(&light_map, torch + IVec3::X * distance), expected);
+            // 距離はマンハッタン距離で数える
+            assert_eq!(block_at(&light_map, torch + IVec3::new(0, distance - distance / 2, -(distance / 2))), expected);
+        }
+        assert_eq!(block_at(&light_map, torch + IVec3::X * (emission as i32 + 1)), 0);
+    }
+
+    #[test]
+    fn light_carries_into_neighbouring_chunk_in_either_order() {
+        let torch = IVec3::new(CHUNK_SIZE - 2, 20, 20);
+        let registry = VoxelRegistry::default();
+        let emission = registry.light_emission(Voxel::TORCH);
+        let chunk_map = chunk_map(&[IVec3::ZERO, IVec3::X], &[(torch, Voxel::TORCH)]);
+
+        for order in [[IVec3::ZERO, IVec3::X], [IVec3::X, IVec3::ZERO]] {
+            let mut light_map = LightMap::default();
+            let mut dirty = HashSet::new();
+            for chunk_pos in order {
+                dirty.extend(light_chunk(chunk_pos, &mut light_map, &chunk_map, &registry));
+            }
+            // 光源から3歩先は隣のチャンクの2ボクセル目
+            assert_eq!(block_at(&light_map, torch + IVec3::new(3, 0, 0)), emission - 3);
+            assert_eq!(block_at(&light_map, torch + IVec3::new(3, 1, 0)), emission - 4);
+            assert!(dirty.contains(&IVec3::X));
+        }
+    }
+}
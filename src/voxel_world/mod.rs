@@ -2,18 +2,29 @@ pub mod core;
 pub mod storage;
 pub mod pipelines;
 pub mod chunking;
+pub mod editing;
+pub mod raycast;
 pub mod player;
+pub mod fluid;
+pub mod falling;
+pub mod lighting;
 
 use bevy::{light::CascadeShadowConfigBuilder, prelude::*};
 use bevy::time::common_conditions::on_timer;
 use std::time::Duration;
-use core::{RenderDistanceParams, ChunkEntities};
-use storage::ChunkMap;
+use core::{RenderDistanceParams, ChunkEntities, VoxelChanged, VoxelDefinitionsAsset, VoxelDefinitionsLoader, VoxelRegistry, load_voxel_definitions, apply_voxel_definitions};
+use storage::{ChunkMap, RegionStore};
 use chunking::*;
 use player::*;
-use pipelines::{cpu_noise::CpuNoiseTerrainGenerationPlugin, cpu_mesh::CpuMeshRenderingPlugin};
+use fluid::{FluidSimulation, FLUID_TICK_MILLIS, schedule_fluid_updates, tick_fluids};
+use falling::{PendingGravityChecks, schedule_gravity_checks, start_falling_blocks, update_falling_blocks};
+use lighting::{LightMap, LightingQueue, drop_unloaded_light, light_queued_chunks, queue_chunk_lighting, update_light_for_changes};
+use pipelines::{cpu_noise::CpuNoiseTerrainGenerationPlugin, cpu_mesh::{CpuMeshRenderingPlugin, meshing::{immediate_mesh_update, queue_mesh_tasks}}};
 use std::marker::PhantomData;
 
+// 編集済みチャンクを保存するリージョンファイルのディレクトリ
+pub const REGION_DIRECTORY: &str = "saves/world/region";
+
 pub type DefaultVoxelWorldPlugin = VoxelWorldPlugin<CpuNoiseTerrainGenerationPlugin, CpuMeshRenderingPlugin>;
 
 pub struct VoxelWorldPlugin<G = CpuNoiseTerrainGenerationPlugin, R = CpuMeshRenderingPlugin> {
@@ -37,14 +48,46 @@ impl<G: Plugin + Default, R: Plugin + Default> Plugin for VoxelWorldPlugin<G, R>
             .insert_resource(RenderDistanceParams::default())
             .insert_resource(ChunkEntities::default())
             .insert_resource(ChunkMap::default())
+            .insert_resource(RegionStore::new(REGION_DIRECTORY))
+            .insert_resource(VoxelRegistry::default())
+            .insert_resource(FluidSimulation::default())
+            .insert_resource(PendingGravityChecks::default())
+            .insert_resource(LightMap::default())
+            .insert_resource(LightingQueue::default())
+            .init_asset::<VoxelDefinitionsAsset>()
+            .init_asset_loader::<VoxelDefinitionsLoader>()
+            .add_message::<VoxelChanged>()
             .add_systems(Startup, (
                 setup_world,
+                load_voxel_definitions,
             ))
             .add_systems(Update, (
                 update_chunk_entities.run_if(resource_changed::<RenderDistanceParams>),
+                apply_voxel_definitions,
+                (
+                    schedule_fluid_updates,
+                    tick_fluids.run_if(on_timer(Duration::from_millis(FLUID_TICK_MILLIS))),
+                ).chain(),
+                (
+                    schedule_gravity_checks,
+                    start_falling_blocks,
+                    update_falling_blocks,
+                ).chain(),
+                // 明るさが変わったチャンクは同じフレームで作り直す
+                (
+                    queue_chunk_lighting,
+                    light_queued_chunks,
+                    update_light_for_changes,
+                ).chain()
+                    .before(queue_mesh_tasks)
+                    .before(immediate_mesh_update),
             ))
             .add_systems(PostUpdate, (
                 unload_distant_chunks.run_if(on_timer(Duration::from_secs(5))),
+                drop_unloaded_light.run_if(on_timer(Duration::from_secs(5))),
+            ))
+            .add_systems(Last, (
+                save_dirty_chunks_on_exit,
             ))
             ;
     }
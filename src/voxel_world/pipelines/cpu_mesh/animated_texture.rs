@@ -0,0 +1,180 @@
+use std::time::Duration;
+
+use bevy::{asset::RenderAssetUsages, image::{ImageAddressMode, ImageSampler, ImageSamplerDescriptor}, platform::collections::HashMap, prelude::*};
+use bevy::render::render_resource::{Extent3d, TextureDimension, TextureFormat};
+use bevy_aseprite_ultra::prelude::Aseprite;
+
+use super::{vegetation::VegetationMaterial, water::WaterMaterial};
+
+// アニメーションするテクスチャを貼るマテリアル
+#[derive(Debug, Clone)]
+pub enum AnimatedMaterial {
+    Standard(Handle<StandardMaterial>),
+    Water(Handle<WaterMaterial>),
+    Vegetation(Handle<VegetationMaterial>),
+}
+
+// .aseprite ファイル1つ分のアニメーション
+#[derive(Debug)]
+struct AnimatedTexture {
+    aseprite: Handle<Aseprite>,
+    // フレームごとに切り出した画像 (読み込みが終わるまでは空)
+    frames: Vec<Handle<Image>>,
+    durations: Vec<Duration>,
+    materials: Vec<AnimatedMaterial>,
+    current: Option<usize>,
+}
+
+impl AnimatedTexture {
+    // 起動からの経過時間で表示するフレーム
+    // 全てのマテリアルが同じ時計を使うので、同じファイルを使う面は同期して動く
+    fn frame_at(&self, elapsed: Duration) -> usize {
+        let total: Duration = self.durations.iter().sum();
+        if total.is_zero() {
+            return 0;
+        }
+        let mut time = Duration::from_nanos((elapsed.as_nanos() % total.as_nanos()) as u64);
+        for (frame, duration) in self.durations.iter().enumerate() {
+            if time < *duration {
+                return frame;
+            }
+            time -= *duration;
+        }
+        self.durations.len() - 1
+    }
+}
+
+// .aseprite ファイルのパスごとのアニメーション
+// material_setup でマテリアルを作るたびに登録し直す
+#[derive(Resource, Debug, Default)]
+pub struct AnimatedTextures {
+    textures: HashMap<String, AnimatedTexture>,
+}
+
+impl AnimatedTextures {
+    pub fn is_animated(path: &str) -> bool {
+        path.ends_with(".aseprite") || path.ends_with(".ase")
+    }
+
+    pub fn clear(&mut self) {
+        self.textures.clear();
+    }
+
+    pub fn register(&mut self, path: &str, material: AnimatedMaterial, asset_server: &AssetServer) {
+        self.textures
+            .entry(path.to_string())
+            .or_insert_with(|| AnimatedTexture {
+                aseprite: asset_server.load(path.to_string()),
+                frames: Vec::new(),
+                durations: Vec::new(),
+                materials: Vec::new(),
+                current: None,
+            })
+            .materials
+            .push(material);
+    }
+}
+
+// アトラスから rect の部分を切り出した画像
+// 貪欲法でまとめた面ではUVが1を超えるので、繰り返せるように1フレームずつ別の画像にする
+fn crop_frame(atlas: &Image, rect: URect) -> Option<Image> {
+    if atlas.texture_descriptor.format != TextureFormat::Rgba8UnormSrgb {
+        return None;
+    }
+    let pixels = atlas.data.as_ref()?;
+    let atlas_width = atlas.width() as usize;
+    let size = rect.size();
+    let mut data = Vec::with_capacity((size.x * size.y * 4) as usize);
+    for y in rect.min.y..rect.max.y {
+        let start = (y as usize * atlas_width + rect.min.x as usize) * 4;
+        data.extend_from_slice(&pixels[start..start + size.x as usize * 4]);
+    }
+    let mut image = Image::new(
+        Extent3d { width: size.x, height: size.y, depth_or_array_layers: 1 },
+        TextureDimension::D2,
+        data,
+        TextureFormat::Rgba8UnormSrgb,
+        RenderAssetUsages::RENDER_WORLD,
+    );
+    image.sampler = ImageSampler::Descriptor(ImageSamplerDescriptor {
+        address_mode_u: ImageAddressMode::Repeat,
+        address_mode_v: ImageAddressMode::Repeat,
+        ..default()
+    });
+    Some(image)
+}
+
+// 読み込みが終わった .aseprite ファイルをフレームごとの画像に分ける
+pub fn extract_animation_frames(
+    mut animated: ResMut<AnimatedTextures>,
+    aseprites: Res<Assets<Aseprite>>,
+    layouts: Res<Assets<TextureAtlasLayout>>,
+    mut images: ResMut<Assets<Image>>,
+) {
+    for (path, texture) in animated.textures.iter_mut() {
+        if !texture.frames.is_empty() {
+            continue;
+        }
+        let Some(aseprite) = aseprites.get(&texture.aseprite) else {
+            continue;
+        };
+        let (Some(layout), Some(atlas)) = (layouts.get(&aseprite.atlas_layout), images.get(&aseprite.atlas_image)) else {
+            continue;
+        };
+        let frames: Option<Vec<Image>> = (0..aseprite.frame_durations.len())
+            .map(|frame| {
+                let rect = *layout.textures.get(aseprite.get_atlas_index(frame))?;
+                crop_frame(atlas, rect)
+            })
+            .collect();
+        let Some(frames) = frames.filter(|frames| !frames.is_empty()) else {
+            warn!("Failed to extract animation frames from {path}");
+            // 何度も試さないように空のアニメーションとして扱う
+            texture.durations.clear();
+            texture.frames.push(Handle::default());
+            continue;
+        };
+        texture.frames = frames.into_iter().map(|frame| images.add(frame)).collect();
+        texture.durations = aseprite.frame_durations.clone();
+    }
+}
+
+// 表示するフレームが変わったマテリアルのテクスチャを差し替える
+pub fn animate_textures(
+    mut animated: ResMut<AnimatedTextures>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut water_materials: ResMut<Assets<WaterMaterial>>,
+    mut vegetation_materials: ResMut<Assets<VegetationMaterial>>,
+    time: Res<Time>,
+) {
+    for texture in animated.textures.values_mut() {
+        if texture.durations.is_empty() {
+            continue;
+        }
+        let frame = texture.frame_at(time.elapsed());
+        if texture.current == Some(frame) {
+            continue;
+        }
+        texture.current = Some(frame);
+        let image = texture.frames[frame].clone();
+        for material in &texture.materials {
+            match material {
+                AnimatedMaterial::Standard(handle) => {
+                    if let Some(material) = materials.get_mut(handle) {
+                        material.base_color_texture = Some(image.clone());
+                    }
+                },
+                AnimatedMaterial::Water(handle) => {
+                    if let Some(material) = water_materials.get_mut(handle) {
+                        material.base.base_color_texture = Some(image.clone());
+                    }
+                },
+                AnimatedMaterial::Vegetation(handle) => {
+                    if let Some(material) = vegetation_materials.get_mut(handle) {
+                        material.base.base_color_texture = Some(image.clone());
+                    }
+                },
+            }
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use std::sync::Arc;
+
+use bevy::{ecs::system::SystemParam, prelude::*};
+use crate::voxel_world::{
+    core::{coordinates::{PADDED_TERRAIN_CHUNK_SIZE, TERRAIN_CHUNK_SIZE}, voxel::BiomeTint},
+    pipelines::cpu_noise::{biomes::BiomeRegistry, storage::TerrainGenerationStorage, WorldGenConfig},
+};
+
+// バイオームの境界で色を混ぜる範囲
+// 頂点の色は、頂点から各方向にこの列数までのバイオームの色の平均になる
+const TINT_BLEND_RADIUS: i32 = 4;
+const CHUNK_SIZE: i32 = TERRAIN_CHUNK_SIZE as i32;
+// パディングを含むチャンクの1辺の頂点の数
+const CORNER_COUNT: usize = PADDED_TERRAIN_CHUNK_SIZE as usize + 1;
+
+// 線形RGBを 0..=255 にしたもの
+// 同じ色の面だけを貪欲法でまとめられるように整数で持つ
+pub type TintColor = [u8; 3];
+pub const WHITE_TINT: TintColor = [255; 3];
+
+pub fn tint_color(tint: TintColor) -> Vec3 {
+    Vec3::from_array(tint.map(|c| c as f32 / 255.0))
+}
+
+// パディングを含むチャンクの各頂点 (x, z) のバイオームの色
+// 空の場合は全て白 (落下中のボクセルなど)
+#[derive(Debug, Clone, Default)]
+pub struct ChunkTints {
+    corners: Vec<[TintColor; BiomeTint::COUNT]>,
+}
+
+impl ChunkTints {
+    // corner はチャンク内の頂点の座標 (y は使わない)
+    pub fn get(&self, corner: UVec3, tint: BiomeTint) -> TintColor {
+        let (x, z) = (corner.x as usize, corner.z as usize);
+        if x >= CORNER_COUNT || z >= CORNER_COUNT {
+            return WHITE_TINT;
+        }
+        self.corners.get(z * CORNER_COUNT + x).map_or(WHITE_TINT, |colors| colors[tint.index()])
+    }
+}
+
+// チャンクの色を計算するのに必要なバイオームの情報
+// メインスレッドで集めて、メッシュを作るタスクで計算する
+pub struct BiomeTintSource {
+    chunk_xz: IVec2,
+    // 周囲 3x3 チャンクのバイオームマップ ([dz + 1][dx + 1])
+    biome_maps: [[Option<Arc<[u8]>>; 3]; 3],
+    registry: Arc<BiomeRegistry>,
+}
+
+impl BiomeTintSource {
+    // 列 (ワールド座標) のバイオームID
+    // まだ生成されていないチャンクの列は、このチャンクの最も近い列で代用する
+    fn biome_at(&self, column: IVec2) -> u8 {
+        let min = self.chunk_xz * CHUNK_SIZE;
+        let offset = column.div_euclid(IVec2::splat(CHUNK_SIZE)) - self.chunk_xz;
+        let neighbor = (offset.abs().max_element() <= 1)
+            .then(|| self.biome_maps[(offset.y + 1) as usize][(offset.x + 1) as usize].as_ref())
+            .flatten();
+        let (map, column) = match neighbor {
+            Some(map) => (map, column),
+            None => match &self.biome_maps[1][1] {
+                Some(map) => (map, column.clamp(min, min + CHUNK_SIZE - 1)),
+                None => return 0,
+            },
+        };
+        let local = column.rem_euclid(IVec2::splat(CHUNK_SIZE));
+        map[(local.y * CHUNK_SIZE + local.x) as usize]
+    }
+
+    pub fn compute(&self) -> ChunkTints {
+        // バイオームIDごとの線形RGB
+        let palette: Vec<[Vec3; BiomeTint::COUNT]> = (0..=u8::MAX)
+            .map(|id| {
+                let biome = self.registry.get_biome_data_by_id(id);
+                BiomeTint::ALL.map(|tint| biome.tint_color(tint).to_linear().to_vec3())
+            })
+            .collect();
+        let color_at = |column: IVec2| palette[self.biome_at(column) as usize];
+
+        // 頂点 0 はパディングの列の最小側の角
+        // 頂点 c の色は列 c - R ..= c + R - 1 の平均なので、その範囲の列の色を集める
+        let radius = TINT_BLEND_RADIUS as usize;
+        let width = CORNER_COUNT - 1 + radius * 2;
+        let first_column = self.chunk_xz * CHUNK_SIZE - IVec2::ONE - IVec2::splat(TINT_BLEND_RADIUS);
+        let columns: Vec<[Vec3; BiomeTint::COUNT]> = (0..width * width)
+            .map(|i| color_at(first_column + IVec2::new((i % width) as i32, (i / width) as i32)))
+            .collect();
+
+        let sum = |colors: &mut dyn Iterator<Item = [Vec3; BiomeTint::COUNT]>| {
+            colors.fold([Vec3::ZERO; BiomeTint::COUNT], |acc, colors| std::array::from_fn(|t| acc[t] + colors[t]))
+        };
+        // x 方向に平均してから z 方向に平均する
+        let mut rows = Vec::with_capacity(width * CORNER_COUNT);
+        for z in 0..width {
+            for x in 0..CORNER_COUNT {
+                rows.push(sum(&mut (x..x + radius * 2).map(|cx| columns[z * width + cx])));
+            }
+        }
+        let count = (radius * radius * 4) as f32;
+        let mut corners = Vec::with_capacity(CORNER_COUNT * CORNER_COUNT);
+        for z in 0..CORNER_COUNT {
+            for x in 0..CORNER_COUNT {
+                let total = sum(&mut (z..z + radius * 2).map(|cz| rows[cz * CORNER_COUNT + x]));
+                corners.push(total.map(|color| {
+                    (color / count).to_array().map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8)
+                }));
+            }
+        }
+        ChunkTints { corners }
+    }
+}
+
+// メッシュを作るシステムでバイオームの色を集めるためのSystemParam
+#[derive(SystemParam)]
+pub struct BiomeTints<'w> {
+    storage: Res<'w, TerrainGenerationStorage>,
+    config: Option<Res<'w, WorldGenConfig>>,
+}
+
+impl BiomeTints<'_> {
+    // バイオーム定義が読み込まれていなければ None (色を付けない)
+    pub fn source(&self, chunk_pos: IVec3) -> Option<BiomeTintSource> {
+        let config = self.config.as_ref()?;
+        let chunk_xz = chunk_pos.xz();
+        let biome_maps = std::array::from_fn(|dz| std::array::from_fn(|dx| {
+            let offset = IVec2::new(dx as i32 - 1, dz as i32 - 1);
+            self.storage.biome_maps.get(&(chunk_xz + offset)).cloned()
+        }));
+        Some(BiomeTintSource {
+            chunk_xz,
+            biome_maps,
+            registry: config.biome_registry.clone(),
+        })
+    }
+}
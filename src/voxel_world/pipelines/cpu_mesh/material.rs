@@ -1,8 +1,10 @@
-use bevy::{asset::RenderAssetUsages, ecs::relationship::RelatedSpawnerCommands, image::{ImageAddressMode, ImageLoaderSettings, ImageSampler, ImageSamplerDescriptor}, light::NotShadowCaster, mesh::{Indices, PrimitiveTopology}, platform::collections::HashMap, prelude::*};
+use std::f32::consts::FRAC_1_SQRT_2;
+
+use bevy::{asset::RenderAssetUsages, ecs::{relationship::RelatedSpawnerCommands, system::SystemParam}, image::{ImageAddressMode, ImageLoaderSettings, ImageSampler, ImageSamplerDescriptor}, light::NotShadowCaster, mesh::{Indices, PrimitiveTopology}, platform::collections::HashMap, prelude::*};
 use block_mesh::{Axis, GreedyQuadsBuffer, MergeVoxel, OrientedBlockFace, RIGHT_HANDED_Y_UP_CONFIG, UnorientedQuad, VoxelVisibility, greedy_quads, ndshape::Shape};
 use itertools::Itertools;
-use crate::voxel_world::{core::{chunk::Chunk, coordinates::VOXEL_SIZE, voxel::{self, VoxelMaterial}}, pipelines::cpu_mesh::water::WaterExtension};
-use super::water::WaterMaterial;
+use crate::voxel_world::{core::{coordinates::VOXEL_SIZE, registry::VoxelRegistry, voxel::{self, BiomeTint, Voxel, VoxelMaterial}}, fluid::{SOURCE_LEVEL, water_surface_height}, lighting::{MAX_LIGHT, block_light, sky_light}, pipelines::cpu_mesh::{vegetation::VegetationExtension, water::WaterExtension}};
+use super::{animated_texture::{AnimatedMaterial, AnimatedTextures}, biome_tint::{ChunkTints, TintColor, WHITE_TINT, tint_color}, terrain_array::{TerrainArrayAssets, TerrainArrayBuilder, TerrainArrayMaterial}, vegetation::VegetationMaterial, water::WaterMaterial};
 
 #[derive(Component)]
 pub struct TerrainMesh;
@@ -11,9 +13,22 @@ pub struct TerrainMesh;
 pub enum VoxelMaterialHandle {
     Standard(Handle<StandardMaterial>),
     Water(Handle<WaterMaterial>),
+    // 風で揺れる草花と葉
+    Vegetation(Handle<VegetationMaterial>),
+    // テクスチャ配列のマテリアルとレイヤー番号
+    Terrain(Handle<TerrainArrayMaterial>, u32),
 }
 
 impl VoxelMaterialHandle {
+    // メッシュをまとめる単位のマテリアルとテクスチャ配列のレイヤー番号に分ける
+    // テクスチャ配列のマテリアルはレイヤーが異なっても1つのメッシュにする
+    fn split_layer(self) -> (Self, u32) {
+        match self {
+            VoxelMaterialHandle::Terrain(handle, layer) => (VoxelMaterialHandle::Terrain(handle, 0), layer),
+            handle => (handle, 0),
+        }
+    }
+
     pub fn spawn(&self, parent: &mut RelatedSpawnerCommands<'_, ChildOf>, mesh: Handle<Mesh>) {
         match self {
             VoxelMaterialHandle::Standard(handle) => {
@@ -31,6 +46,20 @@ impl VoxelMaterialHandle {
                     NotShadowCaster,
                 ));
             },
+            VoxelMaterialHandle::Vegetation(handle) => {
+                parent.spawn((
+                    Mesh3d(mesh),
+                    MeshMaterial3d(handle.clone()),
+                    TerrainMesh,
+                ));
+            },
+            VoxelMaterialHandle::Terrain(handle, _) => {
+                parent.spawn((
+                    Mesh3d(mesh),
+                    MeshMaterial3d(handle.clone()),
+                    TerrainMesh,
+                ));
+            },
         }
     }
 }
@@ -42,36 +71,86 @@ pub enum VoxelMeshKind {
     Water,
 }
 
-#[derive(Debug, Resource, Clone)]
+// 上面の向きごとの各面のマテリアル
+// 面のインデックスは RIGHT_HANDED_Y_UP_CONFIG と同じ (-X, -Y, -Z, +X, +Y, +Z)
+pub type OrientedFaceMaterials = [[VoxelMaterialHandle; 6]; 6];
+// 上面の向きごとの各面のバイオームの色の種類
+pub type OrientedFaceTints = [[Option<BiomeTint>; 6]; 6];
+
+const FACE_DIRECTIONS: [IVec3; 6] = [IVec3::NEG_X, IVec3::NEG_Y, IVec3::NEG_Z, IVec3::X, IVec3::Y, IVec3::Z];
+const UP_FACE_INDEX: usize = 4;
+
+// AOの値 (0: 最も暗い ..= 3: 遮るものなし) ごとの頂点カラーの明るさ
+const AO_BRIGHTNESS: [f32; 4] = [0.45, 0.65, 0.82, 1.0];
+// 4頂点とも遮るものがない面のAO (2ビットずつ、頂点の順)
+const UNOCCLUDED_FACE_AO: u8 = 0xFF;
+// 明るさが1段階下がるごとに掛ける値
+const LIGHT_FALLOFF: f32 = 0.8;
+// 4頂点とも最も明るい面
+const FULLY_LIT_FACE: [u8; 4] = [MAX_LIGHT; 4];
+// 4頂点とも色を付けない面
+const UNTINTED_FACE: [TintColor; 4] = [WHITE_TINT; 4];
+// 葉の面の揺れの重み (草花は根元の 0 から先端の 1 まで)
+// 葉は枝に付いているので、草花の先端ほどは揺らさない
+const LEAF_SWAY_WEIGHT: f32 = 0.5;
+
+// 空の光とブロックの光のうち明るい方で頂点カラーの明るさを決める
+fn light_level(packed: u8) -> u8 {
+    sky_light(packed).max(block_light(packed))
+}
+
+fn light_brightness(level: u8) -> f32 {
+    LIGHT_FALLOFF.powi((MAX_LIGHT - level.min(MAX_LIGHT)) as i32)
+}
+
+fn face_index(direction: IVec3) -> usize {
+    FACE_DIRECTIONS.iter().position(|&d| d == direction).unwrap_or(UP_FACE_INDEX)
+}
+
+// +Y を上面として定義された面の値を、上面が各方向を向いた場合の配置に回転させる
+fn orient_faces<T: Clone>(faces: [T; 6]) -> [[T; 6]; 6] {
+    std::array::from_fn(|up_index| {
+        let rotation = Quat::from_rotation_arc(Vec3::Y, FACE_DIRECTIONS[up_index].as_vec3());
+        std::array::from_fn(|face_i| {
+            let source = (rotation.inverse() * FACE_DIRECTIONS[face_i].as_vec3()).round().as_ivec3();
+            faces[face_index(source)].clone()
+        })
+    })
+}
+
+#[derive(Debug, Resource, Clone, Default)]
 pub struct MaterialRepository {
     // if default_material is shown, some error occurred
     pub default_material: Handle<StandardMaterial>,
-    pub materials: Vec<[VoxelMaterialHandle; 6]>,
+    pub materials: Vec<OrientedFaceMaterials>,
     pub visibilities: Vec<VoxelVisibility>,
     pub voxel_kinds: Vec<VoxelMeshKind>,
-}
-
-impl Default for MaterialRepository {
-    fn default() -> Self {
-        Self {
-            default_material: Handle::default(),
-            materials: Vec::new(),
-            visibilities: Vec::new(),
-            voxel_kinds: Vec::new(),
-        }
-    }
+    pub tints: Vec<OrientedFaceTints>,
 }
 
 #[derive(Clone, Copy, PartialEq, Eq)]
 struct MeshingVoxel {
     id: u16,
+    state: u16,
     visibility: VoxelVisibility,
+    // 面ごとの4頂点のAO
+    ao: [u8; 6],
+    // 面ごとの4頂点の明るさ (0..=MAX_LIGHT)
+    light: [[u8; 4]; 6],
+    // 面ごとの4頂点のバイオームの色
+    tint: [[TintColor; 4]; 6],
+    // 流体用のメッシュで面を作るボクセル (貪欲法で作った面は捨てる)
+    fluid_mesh: bool,
+    // 風で揺れるボクセルは自身のインデックスを持ち、他のボクセルの面とまとめない
+    // 大きな面にまとめると隣の面の辺の途中に頂点ができ、頂点ごとに揺れがずれて隙間ができる
+    unmerged: Option<u32>,
 }
 
 impl MergeVoxel for MeshingVoxel {
-    type MergeValue = u16;
+    // AOや明るさ、色が異なる面をまとめると頂点の色が合わなくなるので、それらも一致するものだけまとめる
+    type MergeValue = (u16, u16, [u8; 6], [[u8; 4]; 6], [[TintColor; 4]; 6], bool, Option<u32>);
     fn merge_value(&self) -> Self::MergeValue {
-        self.id
+        (self.id, self.state, self.ao, self.light, self.tint, self.fluid_mesh, self.unmerged)
     }
 }
 
@@ -81,12 +160,82 @@ impl block_mesh::Voxel for MeshingVoxel {
     }
 }
 
+// (indices, positions, normals, uvs, colors)
+type FluidMeshBuffers = (Vec<u32>, Vec<[f32; 3]>, Vec<[f32; 3]>, Vec<[f32; 2]>, Vec<[f32; 4]>);
+// (indices, positions, normals, uvs, colors, uv_1 (y は揺れの重み))
+type CrossMeshBuffers = (Vec<u32>, Vec<[f32; 3]>, Vec<[f32; 3]>, Vec<[f32; 2]>, Vec<[f32; 4]>, Vec<[f32; 2]>);
+
+// (面, 矩形, テクスチャの上方向, 各頂点のAO, 各頂点の明るさ, テクスチャ配列のレイヤー, 各頂点のバイオームの色, 揺れの重み)
+type OrientedQuad = (OrientedBlockFace, UnorientedQuad, IVec3, [u8; 4], [u8; 4], u32, [TintColor; 4], f32);
+
 struct MeshBuilder{
-    quads: Vec<(OrientedBlockFace, UnorientedQuad)>,
+    quads: Vec<OrientedQuad>,
+}
+
+// 回転したボクセルのUV
+// テクスチャの上方向が up になるように、面上の座標をボクセル単位で投影する
+fn rotated_tex_coords(face: &OrientedBlockFace, quad: &UnorientedQuad, up: IVec3) -> [[f32; 2]; 4] {
+    let normal = Vec3::from_array(face.quad_mesh_normals()[0]);
+    let up = up.as_vec3();
+    // 上面と下面では、テクスチャの上方向を面に平行な別の軸から選ぶ
+    let texture_up = if normal.dot(up).abs() > 0.5 {
+        if up.y == 0.0 { Vec3::Y } else { Vec3::Z }
+    } else {
+        up
+    };
+    let u_dir = texture_up.cross(normal);
+    let v_dir = -texture_up;
+    face.quad_mesh_positions(quad, 1.0).map(|p| {
+        let p = Vec3::from_array(p);
+        [p.dot(u_dir), p.dot(v_dir)]
+    })
+}
+
+// 面の法線と、quad_corners の頂点の並びに対応する面上の u, v 方向
+fn face_axes(face: &OrientedBlockFace) -> (IVec3, IVec3, IVec3) {
+    // block_mesh は別バージョンの glam を使っているので配列で受け渡す
+    let corners = face
+        .quad_corners(&UnorientedQuad { minimum: [1, 1, 1], width: 1, height: 1 })
+        .map(|c| IVec3::from_array(c.as_ivec3().to_array()));
+    (IVec3::from_array(face.signed_normal().to_array()), corners[1] - corners[0], corners[2] - corners[0])
+}
+
+// 4頂点ずつ並んだ四角形ごとに、UVの u 方向に沿った接線を求める (法線マップ用)
+// w は従法線の向き (bevy では bitangent = cross(normal, tangent) * w)
+fn quad_tangents(positions: &[[f32; 3]], normals: &[[f32; 3]], uvs: &[[f32; 2]]) -> Vec<[f32; 4]> {
+    positions
+        .chunks_exact(4)
+        .zip(normals.chunks_exact(4))
+        .zip(uvs.chunks_exact(4))
+        .flat_map(|((positions, normals), uvs)| {
+            let [p0, p1, p2] = [0, 1, 2].map(|i| Vec3::from_array(positions[i]));
+            let [uv0, uv1, uv2] = [0, 1, 2].map(|i| Vec2::from_array(uvs[i]));
+            let normal = Vec3::from_array(normals[0]);
+            let (edge1, edge2) = (p1 - p0, p2 - p0);
+            let (duv1, duv2) = (uv1 - uv0, uv2 - uv0);
+            let det = duv1.x * duv2.y - duv2.x * duv1.y;
+            let tangent = (edge1 * duv2.y - edge2 * duv1.y) / det;
+            let bitangent = (edge2 * duv1.x - edge1 * duv2.x) / det;
+            let tangent = tangent.reject_from(normal).try_normalize().unwrap_or_else(|| normal.any_orthonormal_vector());
+            let w = if normal.cross(tangent).dot(bitangent) < 0.0 { -1.0 } else { 1.0 };
+            [tangent.extend(w).to_array(); 4]
+        })
+        .collect()
+}
+
+// 頂点のAOが明るい方の対角線で四角形を分割する
+// quad_mesh_indices は頂点 1 と 2 を結ぶ対角線で分割しているので、0 と 3 を結ぶ分割に置き換える
+fn flipped_quad_indices(indices: [u32; 6]) -> [u32; 6] {
+    let start = indices[0];
+    if indices[1] == start + 1 {
+        [start, start + 1, start + 3, start, start + 3, start + 2]
+    } else {
+        [start, start + 3, start + 1, start, start + 2, start + 3]
+    }
 }
 
 impl MeshBuilder {
-    fn new(quads: Vec<(OrientedBlockFace, UnorientedQuad)>) -> Self {
+    fn new(quads: Vec<OrientedQuad>) -> Self {
         Self { quads }
     }
     fn get_mesh(&self) -> Mesh {
@@ -96,43 +245,82 @@ impl MeshBuilder {
         let mut positions = Vec::with_capacity(num_vertices);
         let mut normals = Vec::with_capacity(num_vertices);
         let mut uvs = Vec::with_capacity(num_vertices);
-
-        for (face, quad) in self.quads.iter() {
-            indices.extend_from_slice(&face.quad_mesh_indices(positions.len() as u32));
+        let mut colors = Vec::with_capacity(num_vertices);
+        let mut layers = Vec::with_capacity(num_vertices);
+
+        for (face, quad, up, ao, light, layer, tint, sway) in self.quads.iter() {
+            let quad_indices = face.quad_mesh_indices(positions.len() as u32);
+            let brightness: [f32; 4] = std::array::from_fn(|i| AO_BRIGHTNESS[ao[i] as usize] * light_brightness(light[i]));
+            if brightness[0] + brightness[3] > brightness[1] + brightness[2] {
+                indices.extend_from_slice(&flipped_quad_indices(quad_indices));
+            } else {
+                indices.extend_from_slice(&quad_indices);
+            }
             positions.extend_from_slice(&face.quad_mesh_positions(quad, VOXEL_SIZE));
+            colors.extend((0..4).map(|i| (tint_color(tint[i]) * brightness[i]).extend(1.0).to_array()));
+            layers.extend([[*layer as f32, *sway]; 4]);
             normals.extend_from_slice(&face.quad_mesh_normals());
-            uvs.extend_from_slice(&face.tex_coords(Axis::X, true, quad));
+            if *up == IVec3::Y {
+                uvs.extend_from_slice(&face.tex_coords(Axis::X, true, quad));
+            } else {
+                uvs.extend_from_slice(&rotated_tex_coords(face, quad, *up));
+            }
         }
 
+        let tangents = quad_tangents(&positions, &normals, &uvs);
         Mesh::new(PrimitiveTopology::TriangleList, RenderAssetUsages::MAIN_WORLD | RenderAssetUsages::RENDER_WORLD)
             .with_inserted_indices(Indices::U32(indices))
             .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
             .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, normals)
             .with_inserted_attribute(Mesh::ATTRIBUTE_UV_0, uvs)
+            .with_inserted_attribute(Mesh::ATTRIBUTE_UV_1, layers)
+            .with_inserted_attribute(Mesh::ATTRIBUTE_TANGENT, tangents)
+            .with_inserted_attribute(Mesh::ATTRIBUTE_COLOR, colors)
     }
 }
 
 impl MaterialRepository {
-    pub fn set_material(&mut self, id: u16, handles: [VoxelMaterialHandle; 6], visibility: VoxelVisibility, kind: VoxelMeshKind) {
+    pub fn set_material(&mut self, id: u16, handles: OrientedFaceMaterials, tints: OrientedFaceTints, visibility: VoxelVisibility, kind: VoxelMeshKind) {
         let id = id as usize;
         if id >= self.materials.len() {
-            self.materials.resize(id + 1, std::array::from_fn(|_| VoxelMaterialHandle::Standard(self.default_material.clone())));
+            self.materials.resize(id + 1, std::array::from_fn(|_| std::array::from_fn(|_| VoxelMaterialHandle::Standard(self.default_material.clone()))));
             self.visibilities.resize(id + 1, VoxelVisibility::Empty);
             self.voxel_kinds.resize(id + 1, VoxelMeshKind::Cube);
+            self.tints.resize(id + 1, [[None; 6]; 6]);
         }
         self.materials[id] = handles;
+        self.tints[id] = tints;
         self.visibilities[id] = visibility;
         self.voxel_kinds[id] = kind;
     }
 
     pub fn get_material_handle(&self, material_index: usize, face_index: usize) -> VoxelMaterialHandle {
+        self.get_oriented_material_handle(material_index, UP_FACE_INDEX, face_index)
+    }
+
+    pub fn get_oriented_material_handle(&self, material_index: usize, up_index: usize, face_index: usize) -> VoxelMaterialHandle {
         if material_index >= self.materials.len() {
             VoxelMaterialHandle::Standard(self.default_material.clone())
         } else {
-            self.materials[material_index][face_index].clone()
+            self.materials[material_index][up_index][face_index].clone()
         }
     }
 
+    // ブロックステートの向きを考慮した面のマテリアル
+    fn get_voxel_face_handle(&self, voxel: Voxel, face_i: usize) -> VoxelMaterialHandle {
+        let up_index = face_index(voxel.up_direction());
+        self.get_oriented_material_handle(voxel.id as usize, up_index, face_i)
+    }
+
+    // 面のいずれかが風で揺れるマテリアルのボクセル
+    fn sways(&self, voxel: Voxel) -> bool {
+        (0..6).any(|face_i| matches!(self.get_voxel_face_handle(voxel, face_i), VoxelMaterialHandle::Vegetation(_)))
+    }
+
+    fn get_face_tint(&self, voxel_id: u16, up_index: usize, face_i: usize) -> Option<BiomeTint> {
+        self.tints.get(voxel_id as usize).and_then(|tints| tints[up_index][face_i])
+    }
+
     fn get_visibility(&self, voxel_id: u16) -> VoxelVisibility {
         if (voxel_id as usize) < self.visibilities.len() {
             self.visibilities[voxel_id as usize]
@@ -149,44 +337,181 @@ impl MaterialRepository {
         }
     }
 
-    pub fn create_mesh<S: Shape<3, Coord = u32>>(&self, chunk: Chunk<S>) -> Vec<(VoxelMaterialHandle, Mesh)> {
-        let mut meshing_voxels = Vec::with_capacity(chunk.voxels.len());
-        let mut cross_voxels = Vec::new();
+    // AOで周囲を暗くするボクセル
+    fn is_occluder(&self, voxel: Voxel) -> bool {
+        self.get_voxel_kind(voxel.id) == VoxelMeshKind::Cube && self.get_visibility(voxel.id) == VoxelVisibility::Opaque
+    }
 
-        for (i, v) in chunk.voxels.iter().enumerate() {
+    // 各面の4頂点のAO (2ビットずつ、quad_corners の頂点の順)
+    // 面の前にあるボクセルの周囲8個のうち、頂点に接する辺の2個と角の1個で決まる
+    fn voxel_ao<S: Shape<3, Coord = u32>>(&self, shape: &S, occluders: &[bool], index: usize) -> [u8; 6] {
+        let dims = IVec3::from_array(shape.as_array().map(|d| d as i32));
+        let pos = IVec3::from_array(shape.delinearize(index as u32).map(|c| c as i32));
+        let occluded = |p: IVec3| {
+            p.cmpge(IVec3::ZERO).all() && p.cmplt(dims).all()
+                && occluders[shape.linearize(p.as_uvec3().to_array()) as usize]
+        };
+        std::array::from_fn(|face_i| {
+            let (normal, u, v) = face_axes(&RIGHT_HANDED_Y_UP_CONFIG.faces[face_i]);
+            let front = pos + normal;
+            // 見えない面はまとめやすいように遮るものなしとして扱う
+            if occluded(front) {
+                return UNOCCLUDED_FACE_AO;
+            }
+            [(-u, -v), (u, -v), (-u, v), (u, v)]
+                .into_iter()
+                .enumerate()
+                .fold(0, |packed, (corner_i, (du, dv))| {
+                    let side1 = occluded(front + du);
+                    let side2 = occluded(front + dv);
+                    let corner = occluded(front + du + dv);
+                    let ao = if side1 && side2 {
+                        0
+                    } else {
+                        3 - (side1 as u8 + side2 as u8 + corner as u8)
+                    };
+                    packed | (ao << (corner_i * 2))
+                })
+        })
+    }
+
+    // 各面の4頂点の明るさ (quad_corners の頂点の順)
+    // 面の前にあるボクセルと、頂点に接する辺の2個と角の1個のうち光を通すものの明るさを平均する
+    fn voxel_light<S: Shape<3, Coord = u32>>(&self, shape: &S, occluders: &[bool], light: &[u8], index: usize) -> [[u8; 4]; 6] {
+        let dims = IVec3::from_array(shape.as_array().map(|d| d as i32));
+        let pos = IVec3::from_array(shape.delinearize(index as u32).map(|c| c as i32));
+        let level_at = |p: IVec3| {
+            (p.cmpge(IVec3::ZERO).all() && p.cmplt(dims).all()).then(|| {
+                let i = shape.linearize(p.as_uvec3().to_array()) as usize;
+                (!occluders[i]).then(|| light_level(light[i]))
+            })
+        };
+        std::array::from_fn(|face_i| {
+            let (normal, u, v) = face_axes(&RIGHT_HANDED_Y_UP_CONFIG.faces[face_i]);
+            let front = pos + normal;
+            // 見えない面はまとめやすいように最も明るいものとして扱う
+            let Some(Some(front_level)) = level_at(front) else {
+                return FULLY_LIT_FACE;
+            };
+            [(-u, -v), (u, -v), (-u, v), (u, v)].map(|(du, dv)| {
+                let side1 = level_at(front + du).flatten();
+                let side2 = level_at(front + dv).flatten();
+                // 両側の辺が塞がれているときは角から光は回り込まない
+                let corner = if side1.is_none() && side2.is_none() {
+                    None
+                } else {
+                    level_at(front + du + dv).flatten()
+                };
+                let levels = [Some(front_level), side1, side2, corner];
+                let (sum, count) = levels.iter().flatten().fold((0u32, 0u32), |(sum, count), &level| (sum + level as u32, count + 1));
+                ((sum + count / 2) / count) as u8
+            })
+        })
+    }
+
+    // 各面の4頂点のバイオームの色 (quad_corners の頂点の順)
+    fn voxel_tint<S: Shape<3, Coord = u32>>(&self, shape: &S, voxel: Voxel, tints: &ChunkTints, index: usize) -> [[TintColor; 4]; 6] {
+        let pos = IVec3::from_array(shape.delinearize(index as u32).map(|c| c as i32));
+        let up_index = face_index(voxel.up_direction());
+        std::array::from_fn(|face_i| {
+            let Some(tint) = self.get_face_tint(voxel.id, up_index, face_i) else {
+                return UNTINTED_FACE;
+            };
+            let (normal, u, v) = face_axes(&RIGHT_HANDED_Y_UP_CONFIG.faces[face_i]);
+            // 頂点はボクセルの最小の角から各軸に 0 か 1 ずれた位置にある
+            [(-u, -v), (u, -v), (-u, v), (u, v)].map(|(du, dv)| {
+                let corner = pos + (IVec3::ONE + normal + du + dv) / 2;
+                tints.get(corner.as_uvec3(), tint)
+            })
+        })
+    }
+
+    // 流体用のメッシュで1ボクセルずつ面を作る水
+    // 水位によって高さが変わる水源以外の水と、上が空気で水面を下げる水
+    // 水面は波で頂点を動かすので、貪欲法で大きな面にまとめない
+    fn uses_fluid_mesh(&self, voxel: Voxel, above: Voxel) -> bool {
+        self.get_voxel_kind(voxel.id) == VoxelMeshKind::Water
+            && (voxel.level().is_some_and(|level| level != SOURCE_LEVEL) || self.get_visibility(above.id) == VoxelVisibility::Empty)
+    }
+
+    // light はボクセルと同じ並びの明るさ (lighting::pack_light で詰めたもの)
+    // tints は tint を指定した面に掛けるバイオームの色
+    // voxels は shape の並びのボクセル
+    pub fn create_mesh<S: Shape<3, Coord = u32>>(&self, shape: &S, voxels: &[Voxel], light: &[u8], tints: &ChunkTints) -> Vec<(VoxelMaterialHandle, Mesh)> {
+        let mut meshing_voxels = Vec::with_capacity(voxels.len());
+        let mut cross_voxels = Vec::new();
+        let mut fluid_voxels = Vec::new();
+        let occluders: Vec<bool> = voxels.iter().map(|v| self.is_occluder(*v)).collect();
+        let height = shape.as_array()[1];
+        let voxel_above = |i: usize| {
+            let [x, y, z] = shape.delinearize(i as u32);
+            if y + 1 < height { voxels[shape.linearize([x, y + 1, z]) as usize] } else { Voxel::EMPTY }
+        };
+
+        for (i, v) in voxels.iter().enumerate() {
             let kind = self.get_voxel_kind(v.id);
             match kind {
                 VoxelMeshKind::Cross => {
                     meshing_voxels.push(MeshingVoxel {
                         id: v.id,
+                        state: v.state,
                         visibility: VoxelVisibility::Empty,
+                        ao: [UNOCCLUDED_FACE_AO; 6],
+                        light: [FULLY_LIT_FACE; 6],
+                        tint: [UNTINTED_FACE; 6],
+                        fluid_mesh: false,
+                        unmerged: None,
                     });
                     cross_voxels.push((i, v.id));
                 },
                 _ => {
+                    // 流れる水や水面も隣のボクセルの面を決めるために Translucent のまま残し、面は別に作る
+                    let fluid_mesh = self.uses_fluid_mesh(*v, voxel_above(i));
+                    if fluid_mesh {
+                        fluid_voxels.push(i);
+                    }
+                    // 水面は暗くしない
+                    let ao = if kind == VoxelMeshKind::Cube && self.get_visibility(v.id) != VoxelVisibility::Empty {
+                        self.voxel_ao(shape, &occluders, i)
+                    } else {
+                        [UNOCCLUDED_FACE_AO; 6]
+                    };
+                    let (light, tint) = if self.get_visibility(v.id) != VoxelVisibility::Empty {
+                        (self.voxel_light(shape, &occluders, light, i), self.voxel_tint(shape, *v, tints, i))
+                    } else {
+                        ([FULLY_LIT_FACE; 6], [UNTINTED_FACE; 6])
+                    };
+                    let sways = self.get_visibility(v.id) != VoxelVisibility::Empty && self.sways(*v);
                     meshing_voxels.push(MeshingVoxel {
                         id: v.id,
+                        state: v.state,
                         visibility: self.get_visibility(v.id),
+                        ao,
+                        light,
+                        tint,
+                        fluid_mesh,
+                        unmerged: sways.then_some(i as u32),
                     });
                 }
             }
         }
 
-        let mut meshes = self.generate_greedy_mesh(&chunk, &meshing_voxels);
-        meshes.extend(self.generate_cross_mesh(&chunk, &cross_voxels));
+        let mut meshes = self.generate_greedy_mesh(shape, voxels, &meshing_voxels);
+        meshes.extend(self.generate_cross_mesh(shape, &cross_voxels, light, tints));
+        meshes.extend(self.generate_fluid_mesh(shape, voxels, &fluid_voxels, light, tints));
         meshes
     }
 
-    fn generate_greedy_mesh<S: Shape<3, Coord = u32>>(&self, chunk: &Chunk<S>, meshing_voxels: &[MeshingVoxel]) -> Vec<(VoxelMaterialHandle, Mesh)> {
+    fn generate_greedy_mesh<S: Shape<3, Coord = u32>>(&self, shape: &S, voxels: &[Voxel], meshing_voxels: &[MeshingVoxel]) -> Vec<(VoxelMaterialHandle, Mesh)> {
         let faces = RIGHT_HANDED_Y_UP_CONFIG.faces;
-        let mut buffer = GreedyQuadsBuffer::new(chunk.voxels.len());
-        let dims = chunk.shape.as_array();
+        let mut buffer = GreedyQuadsBuffer::new(shape.usize());
+        let dims = shape.as_array();
         let min = [0, 0, 0];
         let max = [dims[0] - 1, dims[1] - 1, dims[2] - 1];
 
         greedy_quads(
             meshing_voxels,
-            &chunk.shape,
+            shape,
             min,
             max,
             &faces,
@@ -196,12 +521,18 @@ impl MaterialRepository {
         buffer.quads.groups.into_iter()
             .zip(faces.into_iter().enumerate())
             .flat_map(|(quads, (face_i, face))| {
-                let chunk = &chunk;
-                quads.into_iter().map(move |quad| {
-                    let local_pos = quad.minimum;
-                    let voxel = chunk.get_at(UVec3 { x: local_pos[0], y: local_pos[1], z: local_pos[2] });
-                    let handle = self.get_material_handle(voxel.id as usize, face_i);
-                    (handle, (face, quad))
+                quads.into_iter().filter_map(move |quad| {
+                    let index = shape.linearize(quad.minimum) as usize;
+                    let voxel = voxels[index];
+                    let meshing_voxel = &meshing_voxels[index];
+                    if meshing_voxel.fluid_mesh {
+                        return None;
+                    }
+                    let (handle, layer) = self.get_voxel_face_handle(voxel, face_i).split_layer();
+                    let packed = meshing_voxel.ao[face_i];
+                    let ao = std::array::from_fn(|corner_i| (packed >> (corner_i * 2)) & 0b11);
+                    let sway = if matches!(handle, VoxelMaterialHandle::Vegetation(_)) { LEAF_SWAY_WEIGHT } else { 0.0 };
+                    Some((handle, (face, quad, voxel.up_direction(), ao, meshing_voxel.light[face_i], layer, meshing_voxel.tint[face_i], sway)))
                 })
             })
             .into_group_map()
@@ -210,9 +541,9 @@ impl MaterialRepository {
             .collect()
     }
 
-    fn generate_cross_mesh<S: Shape<3, Coord = u32>>(&self, chunk: &Chunk<S>, cross_voxels: &[(usize, u16)]) -> Vec<(VoxelMaterialHandle, Mesh)> {
-        let mut cross_groups: HashMap<VoxelMaterialHandle, (Vec<u32>, Vec<[f32; 3]>, Vec<[f32; 3]>, Vec<[f32; 2]>)> = HashMap::new();
-        let dims = chunk.shape.as_array();
+    fn generate_cross_mesh<S: Shape<3, Coord = u32>>(&self, shape: &S, cross_voxels: &[(usize, u16)], light: &[u8], tints: &ChunkTints) -> Vec<(VoxelMaterialHandle, Mesh)> {
+        let mut cross_groups: HashMap<VoxelMaterialHandle, CrossMeshBuffers> = HashMap::new();
+        let dims = shape.as_array();
         let min = [0, 0, 0];
         let max = [dims[0] - 1, dims[1] - 1, dims[2] - 1];
 
@@ -220,11 +551,11 @@ impl MaterialRepository {
         let planes = [
             (
                 [[0.0, 0.0, 0.0], [1.0, 0.0, 1.0], [1.0, 1.0, 1.0], [0.0, 1.0, 0.0]], // Positions
-                [-0.70710678, 0.0, 0.70710678] // Normal
+                [-FRAC_1_SQRT_2, 0.0, FRAC_1_SQRT_2] // Normal
             ),
             (
                 [[0.0, 0.0, 1.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0], [0.0, 1.0, 1.0]], // Positions
-                [0.70710678, 0.0, 0.70710678] // Normal
+                [FRAC_1_SQRT_2, 0.0, FRAC_1_SQRT_2] // Normal
             )
         ];
         
@@ -232,7 +563,7 @@ impl MaterialRepository {
         let indices_pattern = [0, 1, 2, 0, 2, 3];
 
         for &(index, voxel_id) in cross_voxels {
-            let pos_arr = chunk.shape.delinearize(index as u32);
+            let pos_arr = shape.delinearize(index as u32);
             if pos_arr[0] <= min[0] || pos_arr[0] >= max[0] ||
                pos_arr[1] <= min[1] || pos_arr[1] >= max[1] ||
                pos_arr[2] <= min[2] || pos_arr[2] >= max[2] {
@@ -240,8 +571,13 @@ impl MaterialRepository {
             }
             let pos = UVec3::new(pos_arr[0], pos_arr[1], pos_arr[2]).as_vec3() * VOXEL_SIZE;
             let handle = self.get_material_handle(voxel_id as usize, 0);
+            // 根元を固定して先端ほど大きく揺らす
+            let sways = matches!(handle, VoxelMaterialHandle::Vegetation(_));
             
-            let (indices, positions, normals, uvs) = cross_groups.entry(handle).or_insert_with(|| (Vec::new(), Vec::new(), Vec::new(), Vec::new()));
+            let (indices, positions, normals, uvs, colors, sway) = cross_groups.entry(handle).or_insert_with(|| (Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new()));
+            // 草花はボクセル自身の明るさで一様に照らす
+            let brightness = light_brightness(light_level(light[index]));
+            let tint = self.get_face_tint(voxel_id, UP_FACE_INDEX, 0);
             
             for (plane_positions, normal) in &planes {
                 let start_index = positions.len() as u32;
@@ -253,6 +589,10 @@ impl MaterialRepository {
                         pos.z + p[2] * VOXEL_SIZE
                     ]);
                     normals.push(*normal);
+                    let corner = UVec3::new(pos_arr[0] + p[0] as u32, pos_arr[1], pos_arr[2] + p[2] as u32);
+                    let tint = tint.map_or(Vec3::ONE, |tint| tint_color(tints.get(corner, tint)));
+                    colors.push((tint * brightness).extend(1.0).to_array());
+                    sway.push([0.0, if sways { p[1] } else { 0.0 }]);
                 }
                 
                 uvs.extend_from_slice(&uvs_pattern);
@@ -263,142 +603,356 @@ impl MaterialRepository {
             }
         }
 
-        cross_groups.into_iter().map(|(handle, (indices, positions, normals, uvs))| {
+        cross_groups.into_iter().map(|(handle, (indices, positions, normals, uvs, colors, sway))| {
+            let tangents = quad_tangents(&positions, &normals, &uvs);
             let mesh = Mesh::new(PrimitiveTopology::TriangleList, RenderAssetUsages::MAIN_WORLD | RenderAssetUsages::RENDER_WORLD)
                 .with_inserted_indices(Indices::U32(indices))
                 .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
                 .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, normals)
-                .with_inserted_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
+                .with_inserted_attribute(Mesh::ATTRIBUTE_UV_0, uvs)
+                .with_inserted_attribute(Mesh::ATTRIBUTE_UV_1, sway)
+                .with_inserted_attribute(Mesh::ATTRIBUTE_TANGENT, tangents)
+                .with_inserted_attribute(Mesh::ATTRIBUTE_COLOR, colors);
+            (handle, mesh)
+        }).collect()
+    }
+
+    // 流れる水と水面のメッシュ。水面の高さは水位で決まる
+    // 上面は上が空気のときだけ作り、側面は隣の水面との高さの差の部分だけ作る
+    fn generate_fluid_mesh<S: Shape<3, Coord = u32>>(&self, shape: &S, voxels: &[Voxel], fluid_voxels: &[usize], light: &[u8], tints: &ChunkTints) -> Vec<(VoxelMaterialHandle, Mesh)> {
+        let mut fluid_groups: HashMap<VoxelMaterialHandle, FluidMeshBuffers> = HashMap::new();
+        let dims = shape.as_array();
+        let get = |pos: IVec3| voxels[shape.linearize(pos.as_uvec3().to_array()) as usize];
+        let is_water = |voxel: Voxel| self.get_voxel_kind(voxel.id) == VoxelMeshKind::Water;
+        let is_opaque = |voxel: Voxel| self.get_visibility(voxel.id) == VoxelVisibility::Opaque;
+        let in_fluid_mesh = |pos: IVec3| self.uses_fluid_mesh(get(pos), get(pos + IVec3::Y));
+        // 貪欲法で作る水はボクセルいっぱいまで満たす
+        let surface_height = |pos: IVec3| if in_fluid_mesh(pos) {
+            water_surface_height(get(pos), is_water(get(pos + IVec3::Y)))
+        } else {
+            1.0
+        };
+
+        for &index in fluid_voxels {
+            let pos_arr = shape.delinearize(index as u32);
+            // パディング部分は隣のチャンクが作る
+            if (0..3).any(|axis| pos_arr[axis] == 0 || pos_arr[axis] >= dims[axis] - 1) {
+                continue;
+            }
+            let pos = IVec3::new(pos_arr[0] as i32, pos_arr[1] as i32, pos_arr[2] as i32);
+            let voxel = get(pos);
+            let height = surface_height(pos);
+            let brightness = light_brightness(light_level(light[index]));
+            let tint = self.get_face_tint(voxel.id, UP_FACE_INDEX, 0);
+            let vertex_color = |corner: Vec3| {
+                let tint = tint.map_or(Vec3::ONE, |tint| tint_color(tints.get(corner.round().as_uvec3(), tint)));
+                (tint * brightness).extend(1.0).to_array()
+            };
+            let buffers = fluid_groups
+                .entry(self.get_material_handle(voxel.id as usize, 0))
+                .or_insert_with(|| (Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new()));
+
+            for direction in [IVec3::Y, IVec3::NEG_Y] {
+                let neighbor = get(pos + direction);
+                if is_water(neighbor) || is_opaque(neighbor) {
+                    continue;
+                }
+                if direction.y > 0 && self.get_visibility(neighbor.id) != VoxelVisibility::Empty {
+                    continue;
+                }
+                let y = if direction.y > 0 { height } else { 0.0 };
+                push_fluid_quad(buffers, pos, direction, direction, y, y, &vertex_color);
+            }
+
+            for direction in [IVec3::NEG_X, IVec3::NEG_Z, IVec3::X, IVec3::Z] {
+                let neighbor_pos = pos + direction;
+                let neighbor = get(neighbor_pos);
+                if is_opaque(neighbor) {
+                    continue;
+                }
+                if !is_water(neighbor) {
+                    push_fluid_quad(buffers, pos, direction, direction, 0.0, height, &vertex_color);
+                    continue;
+                }
+                let neighbor_height = surface_height(neighbor_pos);
+                if neighbor_height < height {
+                    push_fluid_quad(buffers, pos, direction, direction, neighbor_height, height, &vertex_color);
+                } else if neighbor_height > height && !in_fluid_mesh(neighbor_pos) {
+                    // 水源の側面はグリーディメッシュで作られないので、こちら側から見える面を作る
+                    push_fluid_quad(buffers, pos, direction, -direction, height, neighbor_height, &vertex_color);
+                }
+            }
+        }
+
+        fluid_groups.into_iter().map(|(handle, (indices, positions, normals, uvs, colors))| {
+            let tangents = quad_tangents(&positions, &normals, &uvs);
+            let mesh = Mesh::new(PrimitiveTopology::TriangleList, RenderAssetUsages::MAIN_WORLD | RenderAssetUsages::RENDER_WORLD)
+                .with_inserted_indices(Indices::U32(indices))
+                .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
+                .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, normals)
+                .with_inserted_attribute(Mesh::ATTRIBUTE_UV_0, uvs)
+                .with_inserted_attribute(Mesh::ATTRIBUTE_TANGENT, tangents)
+                .with_inserted_attribute(Mesh::ATTRIBUTE_COLOR, colors);
             (handle, mesh)
         }).collect()
     }
 }
 
+// ボクセル pos の direction 側の境界に、高さ bottom..top の面を normal 向きに追加する
+// 上面と下面では bottom と top に面の高さを渡す
+// vertex_color は頂点の位置 (ボクセル単位) から頂点カラーを決める
+fn push_fluid_quad(buffers: &mut FluidMeshBuffers, pos: IVec3, direction: IVec3, normal: IVec3, bottom: f32, top: f32, vertex_color: &dyn Fn(Vec3) -> [f32; 4]) {
+    let (indices, positions, normals, uvs, colors) = buffers;
+    let direction = direction.as_vec3();
+    let normal = normal.as_vec3();
+    let mut center = pos.as_vec3() + Vec3::splat(0.5) + direction * 0.5;
+    // u × v が normal の向きになるようにして、表側から見て反時計回りにする
+    let u = if direction.y == 0.0 {
+        center.y = pos.y as f32 + (bottom + top) * 0.5;
+        Vec3::Y * (top - bottom)
+    } else {
+        center.y = pos.y as f32 + top;
+        Vec3::Z
+    };
+    let u_axis = u.normalize_or_zero();
+    let v = normal.cross(u_axis);
+    let start_index = positions.len() as u32;
+    for corner in [center - u * 0.5 - v * 0.5, center + u * 0.5 - v * 0.5, center + u * 0.5 + v * 0.5, center - u * 0.5 + v * 0.5] {
+        positions.push((corner * VOXEL_SIZE).to_array());
+        normals.push(normal.to_array());
+        colors.push(vertex_color(corner));
+        // 隣のボクセルと模様がつながるように、面上の座標をそのまま使う
+        uvs.push([corner.dot(v.abs()), -corner.dot(u_axis)]);
+    }
+    indices.extend([0, 1, 2, 0, 2, 3].map(|i| start_index + i));
+}
+
+// ボクセルの面に使うマテリアルのアセット
+#[derive(SystemParam)]
+pub struct VoxelMaterialAssets<'w> {
+    standard: ResMut<'w, Assets<StandardMaterial>>,
+    water: ResMut<'w, Assets<WaterMaterial>>,
+    vegetation: ResMut<'w, Assets<VegetationMaterial>>,
+}
+
+// ボクセルのテクスチャはタイルとして繰り返せるように読み込む
+fn voxel_image_settings(s: &mut ImageLoaderSettings) {
+    *s = ImageLoaderSettings {
+        sampler: ImageSampler::Descriptor(ImageSamplerDescriptor {
+            address_mode_u: ImageAddressMode::Repeat,
+            address_mode_v: ImageAddressMode::Repeat,
+            ..default()
+        }),
+        ..default()
+    }
+}
+
 pub fn material_setup(
-    mut materials: ResMut<Assets<StandardMaterial>>,
-    mut water_materials: ResMut<Assets<WaterMaterial>>,
+    mut materials: VoxelMaterialAssets,
+    mut terrain_array: TerrainArrayAssets,
     mut material_repo: ResMut<MaterialRepository>,
+    mut animated_textures: ResMut<AnimatedTextures>,
     asset_server: Res<AssetServer>,
+    registry: Res<VoxelRegistry>,
 ) {
-    let loading_settings = |s: &mut ImageLoaderSettings| {
-        *s = ImageLoaderSettings {
-            sampler: ImageSampler::Descriptor(ImageSamplerDescriptor {
-                address_mode_u: ImageAddressMode::Repeat,
-                address_mode_v: ImageAddressMode::Repeat,
-                ..default()
-            }),
-            ..default()
-        }
-    };
-    
-    let default_material = materials.add(StandardMaterial {
-        base_color_texture: Some(asset_server.load_with_settings("textures/default.png", loading_settings)),
+    let default_material = materials.standard.add(StandardMaterial {
+        base_color_texture: Some(asset_server.load_with_settings("textures/default.png", voxel_image_settings)),
         ..default()
     });
 
-    material_repo.default_material = default_material.clone();
-
-    let definitions = voxel::get_voxel_definitions();
+    // 定義から消えたボクセルのマテリアルが残らないように作り直す
+    *material_repo = MaterialRepository {
+        default_material,
+        ..default()
+    };
 
-    for (id, visibility, material_def) in definitions {
+    let mut terrain_layers = TerrainArrayBuilder::default();
+    let terrain_material = terrain_array.material();
+    animated_textures.clear();
+    for def in registry.definitions() {
         let (handles, kind) = create_voxel_material_handles(
-            material_def,
+            def.material.clone(),
             &mut materials,
-            &mut water_materials,
+            (&mut terrain_layers, &terrain_material),
             &asset_server,
             &material_repo.default_material,
         );
-        material_repo.set_material(id, handles, visibility, kind);
+        register_animated_faces(&def.material, &handles[UP_FACE_INDEX], &mut animated_textures, &asset_server);
+        material_repo.set_material(def.id, handles, orient_faces(face_tints(&def.material)), def.visibility, kind);
     }
+    terrain_array.rebuild(terrain_layers);
 }
 
 fn create_voxel_material_handles(
     def: VoxelMaterial,
-    materials: &mut Assets<StandardMaterial>,
-    water_materials: &mut Assets<WaterMaterial>,
+    materials: &mut VoxelMaterialAssets,
+    (terrain_layers, terrain_material): (&mut TerrainArrayBuilder, &Handle<TerrainArrayMaterial>),
     asset_server: &AssetServer,
     default_material: &Handle<StandardMaterial>,
-) -> ([VoxelMaterialHandle; 6], VoxelMeshKind) {
-    let loading_settings = |s: &mut ImageLoaderSettings| {
-        *s = ImageLoaderSettings {
-            sampler: ImageSampler::Descriptor(ImageSamplerDescriptor {
-                address_mode_u: ImageAddressMode::Repeat,
-                address_mode_v: ImageAddressMode::Repeat,
-                ..default()
-            }),
-            ..default()
-        }
-    };
-
-    match def {
+) -> (OrientedFaceMaterials, VoxelMeshKind) {
+    let (faces, kind) = match def {
         VoxelMaterial::None => (
             std::array::from_fn(|_| VoxelMaterialHandle::Standard(default_material.clone())),
             VoxelMeshKind::Cube
         ),
         VoxelMaterial::Uniform(def) => {
-            let material = create_standard_material(materials, asset_server, def, loading_settings);
-            (std::array::from_fn(|_| VoxelMaterialHandle::Standard(material.clone())), VoxelMeshKind::Cube)
+            let material = create_cube_face_handle(materials, terrain_layers, terrain_material, asset_server, def, voxel_image_settings);
+            (std::array::from_fn(|_| material.clone()), VoxelMeshKind::Cube)
         },
         VoxelMaterial::Column { top, side, bottom } => {
-            let top_mat = create_standard_material(materials, asset_server, top, loading_settings);
-            let side_mat = create_standard_material(materials, asset_server, side, loading_settings);
-            let bottom_mat = create_standard_material(materials, asset_server, bottom, loading_settings);
+            let top_mat = create_cube_face_handle(materials, terrain_layers, terrain_material, asset_server, top, voxel_image_settings);
+            let side_mat = create_cube_face_handle(materials, terrain_layers, terrain_material, asset_server, side, voxel_image_settings);
+            let bottom_mat = create_cube_face_handle(materials, terrain_layers, terrain_material, asset_server, bottom, voxel_image_settings);
             ([
-                VoxelMaterialHandle::Standard(side_mat.clone()),
-                VoxelMaterialHandle::Standard(bottom_mat),
-                VoxelMaterialHandle::Standard(side_mat.clone()),
-                VoxelMaterialHandle::Standard(side_mat.clone()),
-                VoxelMaterialHandle::Standard(top_mat),
-                VoxelMaterialHandle::Standard(side_mat)
+                side_mat.clone(),
+                bottom_mat,
+                side_mat.clone(),
+                side_mat.clone(),
+                top_mat,
+                side_mat
             ], VoxelMeshKind::Cube)
         },
         VoxelMaterial::Cross(def) => {
             let mut def = def;
             def.alpha_mode = AlphaMode::Mask(0.5);
-            let texture = def.texture.map(|path| asset_server.load_with_settings(path, loading_settings));
-            let handle = materials.add(StandardMaterial {
-                base_color: def.base_color,
-                base_color_texture: texture,
-                perceptual_roughness: def.perceptual_roughness,
-                reflectance: def.reflectance,
-                alpha_mode: def.alpha_mode,
+            let sway = def.sway;
+            let base = StandardMaterial {
                 cull_mode: None, // Double sided
                 double_sided: true,
-                ..default()
-            });
-            (std::array::from_fn(|_| VoxelMaterialHandle::Standard(handle.clone())), VoxelMeshKind::Cross)
+                ..standard_material_from_def(asset_server, def, voxel_image_settings)
+            };
+            let handle = if sway {
+                VoxelMaterialHandle::Vegetation(materials.vegetation.add(VegetationMaterial {
+                    base,
+                    extension: VegetationExtension::default(),
+                }))
+            } else {
+                VoxelMaterialHandle::Standard(materials.standard.add(base))
+            };
+            (std::array::from_fn(|_| handle.clone()), VoxelMeshKind::Cross)
         },
         VoxelMaterial::Water(def) => {
-            let material = water_materials.add(WaterMaterial {
+            let material = materials.water.add(WaterMaterial {
                 base: StandardMaterial { 
                     base_color: Color::linear_rgba(0.0, 0.5, 1.0, 0.2),
-                    base_color_texture: def.texture.map(|path| asset_server.load_with_settings(path, loading_settings)),
+                    base_color_texture: def.texture
+                        .filter(|path| !AnimatedTextures::is_animated(path))
+                        .map(|path| asset_server.load_with_settings(path, voxel_image_settings)),
                     perceptual_roughness: 0.08,
                     metallic: 0.1,
                     reflectance: 1.0,
-                    alpha_mode: AlphaMode::Blend,
+                    // 半透明の合成と屈折はシェーダーで行う
+                    // 透過を有効にすると不透明な物体を描いた後に描画され、その画面を読める
+                    alpha_mode: AlphaMode::Opaque,
+                    specular_transmission: 1.0,
+                    // 水中から水面を見上げたときにも描画する
+                    cull_mode: None,
+                    double_sided: true,
                     ..default()
                  },
                 extension: WaterExtension::default(),
             });
             (std::array::from_fn(|_| VoxelMaterialHandle::Water(material.clone())), VoxelMeshKind::Water)
         },
+    };
+    (orient_faces(faces), kind)
+}
+
+// +Y を上面としたときの各面のバイオームの色の種類
+fn face_tints(def: &VoxelMaterial) -> [Option<BiomeTint>; 6] {
+    match def {
+        VoxelMaterial::None => [None; 6],
+        VoxelMaterial::Uniform(def) | VoxelMaterial::Cross(def) | VoxelMaterial::Water(def) => [def.tint; 6],
+        VoxelMaterial::Column { top, side, bottom } => [side.tint, bottom.tint, side.tint, side.tint, top.tint, side.tint],
     }
 }
 
+// .aseprite のテクスチャを使う面のマテリアルをアニメーションに登録する
+// faces は上面が +Y のときの各面のマテリアル
+fn register_animated_faces(def: &VoxelMaterial, faces: &[VoxelMaterialHandle; 6], animated: &mut AnimatedTextures, asset_server: &AssetServer) {
+    let defs_and_faces: Vec<(&voxel::MaterialDef, usize)> = match def {
+        VoxelMaterial::None => vec![],
+        VoxelMaterial::Uniform(def) | VoxelMaterial::Cross(def) | VoxelMaterial::Water(def) => vec![(def, 0)],
+        VoxelMaterial::Column { top, side, bottom } => vec![(top, UP_FACE_INDEX), (side, 0), (bottom, 1)],
+    };
+    for (def, face_i) in defs_and_faces {
+        let Some(path) = def.texture.as_ref().filter(|path| AnimatedTextures::is_animated(path)) else {
+            continue;
+        };
+        let material = match &faces[face_i] {
+            VoxelMaterialHandle::Standard(handle) => AnimatedMaterial::Standard(handle.clone()),
+            VoxelMaterialHandle::Water(handle) => AnimatedMaterial::Water(handle.clone()),
+            VoxelMaterialHandle::Vegetation(handle) => AnimatedMaterial::Vegetation(handle.clone()),
+            // テクスチャ配列にはアニメーションする面を割り当てない
+            VoxelMaterialHandle::Terrain(..) => continue,
+        };
+        animated.register(path, material, asset_server);
+    }
+}
+
+// 不透明な面はテクスチャ配列のレイヤーに割り当て、チャンクごとに1つのメッシュで描画する
+// 割り当てられない面は個別のマテリアルを作る
+fn create_cube_face_handle(
+    materials: &mut VoxelMaterialAssets,
+    terrain_layers: &mut TerrainArrayBuilder,
+    terrain_material: &Handle<TerrainArrayMaterial>,
+    asset_server: &AssetServer,
+    def: voxel::MaterialDef,
+    loading_settings: impl Fn(&mut ImageLoaderSettings) + Copy + Send + Sync + 'static,
+) -> VoxelMaterialHandle {
+    if TerrainArrayBuilder::supports(&def) {
+        let texture = def.texture.as_ref().map(|path| asset_server.load_with_settings(path.clone(), loading_settings));
+        if let Some(layer) = terrain_layers.add_layer(&def, texture) {
+            return VoxelMaterialHandle::Terrain(terrain_material.clone(), layer);
+        }
+    }
+    if def.sway {
+        return VoxelMaterialHandle::Vegetation(materials.vegetation.add(VegetationMaterial {
+            base: standard_material_from_def(asset_server, def, loading_settings),
+            extension: VegetationExtension::default(),
+        }));
+    }
+    VoxelMaterialHandle::Standard(create_standard_material(&mut materials.standard, asset_server, def, loading_settings))
+}
+
 fn create_standard_material(
     materials: &mut Assets<StandardMaterial>,
     asset_server: &AssetServer,
     def: voxel::MaterialDef,
     loading_settings: impl Fn(&mut ImageLoaderSettings) + Copy + Send + Sync + 'static,
 ) -> Handle<StandardMaterial> {
-    let texture = def.texture.map(|path| asset_server.load_with_settings(path, loading_settings));
-    materials.add(StandardMaterial {
+    materials.add(standard_material_from_def(asset_server, def, loading_settings))
+}
+
+fn standard_material_from_def(
+    asset_server: &AssetServer,
+    def: voxel::MaterialDef,
+    loading_settings: impl Fn(&mut ImageLoaderSettings) + Copy + Send + Sync + 'static,
+) -> StandardMaterial {
+    // 法線やラフネスなどの値を持つテクスチャは色ではないので sRGB として扱わない
+    let linear_settings = move |s: &mut ImageLoaderSettings| {
+        loading_settings(s);
+        s.is_srgb = false;
+    };
+    let emissive = def.emissive_linear();
+    // .aseprite のテクスチャは読み込み後に AnimatedTextures が差し込む
+    let load = |path: Option<String>| path
+        .filter(|path| !AnimatedTextures::is_animated(path))
+        .map(|path| asset_server.load_with_settings(path, loading_settings));
+    let load_linear = |path: Option<String>| path.map(|path| asset_server.load_with_settings(path, linear_settings));
+    StandardMaterial {
         base_color: def.base_color,
-        base_color_texture: texture,
+        base_color_texture: load(def.texture),
+        normal_map_texture: load_linear(def.normal_map),
+        metallic_roughness_texture: load_linear(def.metallic_roughness_map),
+        occlusion_texture: load_linear(def.occlusion_map),
+        depth_map: load_linear(def.depth_map),
+        parallax_depth_scale: def.parallax_depth_scale,
+        metallic: def.metallic,
         perceptual_roughness: def.perceptual_roughness,
         reflectance: def.reflectance,
         alpha_mode: def.alpha_mode,
+        emissive,
         ..default()
-    })
+    }
 }
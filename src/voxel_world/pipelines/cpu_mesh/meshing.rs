@@ -2,10 +2,11 @@ use bevy::prelude::*;
 use bevy::tasks::futures::check_ready;
 use bevy::tasks::{AsyncComputeTaskPool, Task};
 use crate::voxel_world::{
-    core::TerrainChunk,
+    core::{TerrainChunk, terrain_chunk::PaddedTerrainChunkShape},
+    lighting::{LightMap, LightingQueue},
     storage::ChunkMap,
 };
-use super::material::{MaterialRepository, VoxelMaterialHandle};
+use super::{biome_tint::BiomeTints, material::{MaterialRepository, VoxelMaterialHandle}};
 
 // メッシュが作成中または既に作成されたチャンクに付与されるコンポーネント
 #[derive(Component)]
@@ -20,19 +21,31 @@ pub struct NeedImmediateMeshUpdate;
 #[derive(Component)]
 pub struct ComputingMesh(Task<Vec<(VoxelMaterialHandle, Mesh)>>);
 
+type MeshTaskFilter = (With<NeedMeshUpdate>, Without<ComputingMesh>, Without<NeedImmediateMeshUpdate>);
+
 pub fn queue_mesh_tasks(
     mut commands: Commands,
     chunk_map: Res<ChunkMap>,
+    light_map: Res<LightMap>,
+    lighting_queue: Res<LightingQueue>,
     material_repo: Res<MaterialRepository>,
-    chunks: Query<(Entity, &TerrainChunk), (With<NeedMeshUpdate>, Without<ComputingMesh>, Without<NeedImmediateMeshUpdate>)>,
+    biome_tints: BiomeTints,
+    chunks: Query<(Entity, &TerrainChunk), MeshTaskFilter>,
 ) {
     let thread_pool = AsyncComputeTaskPool::get();
 
     for (entity, chunk) in chunks.iter() {
-        if let Some(padded_chunk) = chunk_map.get_padded_chunk_vec(&chunk.position) {
+        // 明るさの計算を待ってから作る
+        if lighting_queue.is_pending(&chunk.position) {
+            continue;
+        }
+        if let Some(padded_voxels) = chunk_map.get_padded_chunk_vec(&chunk.position) {
             let material_repo = material_repo.clone();
+            let light = light_map.get_padded_light(&chunk.position);
+            let tint_source = biome_tints.source(chunk.position);
             let task = thread_pool.spawn(async move {
-                material_repo.create_mesh(padded_chunk)
+                let tints = tint_source.map(|source| source.compute()).unwrap_or_default();
+                material_repo.create_mesh(&PaddedTerrainChunkShape {}, &padded_voxels, &light, &tints)
             });
             commands.entity(entity)
                 .remove::<NeedMeshUpdate>()
@@ -71,13 +84,17 @@ pub fn handle_mesh_tasks(
 pub fn immediate_mesh_update(
     mut commands: Commands,
     chunk_map: Res<ChunkMap>,
+    light_map: Res<LightMap>,
     material_repo: Res<MaterialRepository>,
+    biome_tints: BiomeTints,
     mut meshes: ResMut<Assets<Mesh>>,
     chunks: Query<(Entity, &TerrainChunk, Option<&Children>), With<NeedImmediateMeshUpdate>>,
 ) {
     for (entity, chunk, children) in chunks.iter() {
-        if let Some(padded_chunk) = chunk_map.get_padded_chunk_vec(&chunk.position) {
-            let generated_meshes = material_repo.create_mesh(padded_chunk);
+        if let Some(padded_voxels) = chunk_map.get_padded_chunk_vec(&chunk.position) {
+            let light = light_map.get_padded_light(&chunk.position);
+            let tints = biome_tints.source(chunk.position).map(|source| source.compute()).unwrap_or_default();
+            let generated_meshes = material_repo.create_mesh(&PaddedTerrainChunkShape {}, &padded_voxels, &light, &tints);
             
             // Despawn old meshes
             if let Some(children) = children {
@@ -95,6 +112,7 @@ pub fn immediate_mesh_update(
         commands.entity(entity)
             .remove::<NeedImmediateMeshUpdate>()
             .remove::<NeedMeshUpdate>() // Also remove NeedMeshUpdate if present
+            .remove::<ComputingMesh>() // Drop any in-flight task so it can't overwrite the newer mesh
             .insert(MeshQueued);
     }
 }
\ No newline at end of file
@@ -1,15 +1,20 @@
+pub mod animated_texture;
+pub mod biome_tint;
 pub mod meshing;
 pub mod material;
+pub mod terrain_array;
+pub mod vegetation;
 pub mod water;
 
 use bevy::prelude::*;
+use bevy_aseprite_ultra::AsepriteUltraPlugin;
 use itertools::iproduct;
 
 use crate::voxel_world::{
-    core::{ChunkEntities, ChunkGeneratedEvent},
+    core::{ChunkEntities, ChunkGeneratedEvent, VoxelRegistry},
     pipelines::{
         cpu_noise::storage::TerrainGenerationStorage,
-        cpu_mesh::{material::*, meshing::*, water::WaterMaterial},
+        cpu_mesh::{animated_texture::*, material::*, meshing::*, terrain_array::*, vegetation::{apply_wind_settings, VegetationMaterial, WindSettings}, water::{apply_water_settings, WaterMaterial, WaterSettings}},
     }
 };
 
@@ -18,19 +23,56 @@ pub struct CpuMeshRenderingPlugin;
 
 impl Plugin for CpuMeshRenderingPlugin {
     fn build(&self, app: &mut App) {
+        // .aseprite ファイルのローダー
+        if !app.is_plugin_added::<AsepriteUltraPlugin>() {
+            app.add_plugins(AsepriteUltraPlugin);
+        }
         app
-            .add_plugins(MaterialPlugin::<WaterMaterial>::default())
+            .add_plugins((
+                MaterialPlugin::<WaterMaterial>::default(),
+                MaterialPlugin::<TerrainArrayMaterial>::default(),
+                MaterialPlugin::<VegetationMaterial>::default(),
+            ))
             .insert_resource(MaterialRepository::default())
-            .add_systems(Startup, material_setup)
+            .insert_resource(AnimatedTextures::default())
+            .insert_resource(TerrainTextureArray::default())
+            .init_resource::<WaterSettings>()
+            .register_type::<WaterSettings>()
+            .init_resource::<WindSettings>()
+            .register_type::<WindSettings>()
+            .add_systems(Startup, setup_terrain_array_material)
             .add_systems(Update, (
+                (material_setup, remesh_loaded_chunks)
+                    .chain()
+                    .run_if(resource_changed::<VoxelRegistry>)
+                    .before(queue_mesh_tasks)
+                    .before(immediate_mesh_update),
                 queue_mesh_tasks,
                 handle_mesh_tasks,
                 immediate_mesh_update,
                 trigger_mesh_update,
+                build_terrain_texture_array.after(material_setup),
+                (extract_animation_frames, animate_textures).chain().after(material_setup),
+                apply_water_settings
+                    .after(material_setup)
+                    .run_if(resource_changed::<WaterSettings>.or(resource_changed::<VoxelRegistry>)),
+                apply_wind_settings
+                    .after(material_setup)
+                    .run_if(resource_changed::<WindSettings>.or(resource_changed::<VoxelRegistry>)),
             ));
     }
 }
 
+// ボクセル定義が変わったときに、既にメッシュがあるチャンクを新しいマテリアルで作り直す
+fn remesh_loaded_chunks(
+    mut commands: Commands,
+    chunks: Query<Entity, With<MeshQueued>>,
+) {
+    for entity in chunks.iter() {
+        commands.entity(entity).try_insert(NeedMeshUpdate);
+    }
+}
+
 // 他のチャンクの生成完了イベントを受け取り、メッシュ更新が必要なチャンクにNeedMeshUpdateコンポーネントを追加する
 fn trigger_mesh_update(
     mut commands: Commands,
@@ -50,24 +92,24 @@ fn trigger_mesh_update(
         }
 
         for pos in candidates {
-            if storage.fully_generated.contains(&pos) {
-                if let Some(entity) = chunk_entities.entities.get(&pos) {
-                    let has_mesh = mesh_queued_query.get(*entity).is_ok();
+            if storage.fully_generated.contains(&pos)
+                && let Some(entity) = chunk_entities.entities.get(&pos)
+            {
+                let has_mesh = mesh_queued_query.get(*entity).is_ok();
 
-                    let all_neighbors_ready = iproduct!(-1..=1, -1..=1, -1..=1)
-                        .all(|(dx, dy, dz)| {
-                            let neighbor_pos = pos + IVec3::new(dx, dy, dz);
-                            storage.fully_generated.contains(&neighbor_pos)
-                        });
+                let all_neighbors_ready = iproduct!(-1..=1, -1..=1, -1..=1)
+                    .all(|(dx, dy, dz)| {
+                        let neighbor_pos = pos + IVec3::new(dx, dy, dz);
+                        storage.fully_generated.contains(&neighbor_pos)
+                    });
 
-                    if !has_mesh && all_neighbors_ready {
-                        let entity = *entity;
-                        commands.queue(move |world: &mut World| {
-                            if let Ok(mut entity_world) = world.get_entity_mut(entity) {
-                                entity_world.insert(NeedMeshUpdate);
-                            }
-                        });
-                    }
+                if !has_mesh && all_neighbors_ready {
+                    let entity = *entity;
+                    commands.queue(move |world: &mut World| {
+                        if let Ok(mut entity_world) = world.get_entity_mut(entity) {
+                            entity_world.insert(NeedMeshUpdate);
+                        }
+                    });
                 }
             }
         }
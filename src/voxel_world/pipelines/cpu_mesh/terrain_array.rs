@@ -0,0 +1,228 @@
+use bevy::{ecs::system::SystemParam, prelude::*};
+use bevy::asset::{LoadState, RenderAssetUsages};
+use bevy::image::{ImageAddressMode, ImageSampler, ImageSamplerDescriptor};
+use bevy::pbr::{ExtendedMaterial, MaterialExtension};
+use bevy::render::render_resource::{AsBindGroup, Extent3d, ShaderType, TextureDimension, TextureFormat, TextureViewDescriptor, TextureViewDimension};
+
+use crate::voxel_world::core::voxel::MaterialDef;
+use super::animated_texture::AnimatedTextures;
+
+// テクスチャ配列のレイヤー数の上限 (シェーダー側の配列の長さと合わせる)
+pub const MAX_TERRAIN_LAYERS: usize = 256;
+// 各レイヤーの大きさ。異なる大きさのテクスチャは最近傍で拡大縮小する
+const TERRAIN_LAYER_SIZE: u32 = 16;
+
+// レイヤーごとのマテリアルの値
+#[derive(ShaderType, Debug, Clone)]
+pub struct TerrainLayerParams {
+    // 線形RGBA
+    pub base_color: [Vec4; MAX_TERRAIN_LAYERS],
+    // x: perceptual_roughness, y: reflectance, z: metallic
+    pub surface: [Vec4; MAX_TERRAIN_LAYERS],
+    // 線形RGB (強さを掛けたもの)
+    pub emissive: [Vec4; MAX_TERRAIN_LAYERS],
+}
+
+impl Default for TerrainLayerParams {
+    fn default() -> Self {
+        Self {
+            base_color: [Vec4::ONE; MAX_TERRAIN_LAYERS],
+            surface: [Vec4::new(0.9, 0.1, 0.0, 0.0); MAX_TERRAIN_LAYERS],
+            emissive: [Vec4::ZERO; MAX_TERRAIN_LAYERS],
+        }
+    }
+}
+
+// 全ての不透明な面のテクスチャを1つのテクスチャ配列にまとめたマテリアル
+// 頂点の UV_1 の x にレイヤー番号を入れる
+#[derive(Asset, TypePath, AsBindGroup, Debug, Clone)]
+pub struct TerrainArrayExtension {
+    #[texture(100, dimension = "2d_array")]
+    #[sampler(101)]
+    pub layers: Handle<Image>,
+    #[uniform(102)]
+    pub params: TerrainLayerParams,
+}
+
+impl MaterialExtension for TerrainArrayExtension {
+    fn fragment_shader() -> bevy::shader::ShaderRef {
+        "shaders/terrain_array.wgsl".into()
+    }
+}
+
+pub type TerrainArrayMaterial = ExtendedMaterial<StandardMaterial, TerrainArrayExtension>;
+
+// テクスチャ配列とそれを使うマテリアル
+// テクスチャの読み込みが終わるまでは白いレイヤーで描画される
+#[derive(Resource, Debug, Default)]
+pub struct TerrainTextureArray {
+    pub material: Handle<TerrainArrayMaterial>,
+    image: Handle<Image>,
+    // レイヤーの元になるテクスチャ (None なら白)
+    sources: Vec<Option<Handle<Image>>>,
+    built: bool,
+}
+
+// material_setup でボクセルの面をレイヤーに割り当てる
+#[derive(Default)]
+pub struct TerrainArrayBuilder {
+    sources: Vec<Option<Handle<Image>>>,
+    params: TerrainLayerParams,
+}
+
+impl TerrainArrayBuilder {
+    // テクスチャ配列で描画できる面か
+    // 半透明の面、法線マップなどを使う面、アニメーションする面、風で揺れる面は個別のマテリアルで描画する
+    pub fn supports(def: &MaterialDef) -> bool {
+        def.alpha_mode == AlphaMode::Opaque
+            && def.normal_map.is_none()
+            && def.metallic_roughness_map.is_none()
+            && def.occlusion_map.is_none()
+            && def.depth_map.is_none()
+            && !def.texture.as_ref().is_some_and(|path| AnimatedTextures::is_animated(path))
+            && !def.sway
+    }
+
+    // 面をレイヤーに追加してレイヤー番号を返す。上限に達した場合はNone
+    pub fn add_layer(&mut self, def: &MaterialDef, texture: Option<Handle<Image>>) -> Option<u32> {
+        let layer = self.sources.len();
+        if layer >= MAX_TERRAIN_LAYERS {
+            return None;
+        }
+        self.sources.push(texture);
+        self.params.base_color[layer] = def.base_color.to_linear().to_vec4();
+        self.params.surface[layer] = Vec4::new(def.perceptual_roughness, def.reflectance, def.metallic, 0.0);
+        self.params.emissive[layer] = def.emissive_linear().to_vec4();
+        Some(layer as u32)
+    }
+
+}
+
+// material_setup で使うテクスチャ配列のリソース
+#[derive(SystemParam)]
+pub struct TerrainArrayAssets<'w> {
+    array: ResMut<'w, TerrainTextureArray>,
+    images: ResMut<'w, Assets<Image>>,
+    materials: ResMut<'w, Assets<TerrainArrayMaterial>>,
+}
+
+impl TerrainArrayAssets<'_> {
+    pub fn material(&self) -> Handle<TerrainArrayMaterial> {
+        self.array.material.clone()
+    }
+
+    // 割り当てたレイヤーでテクスチャ配列とマテリアルを作り直す
+    // テクスチャの中身は読み込みが終わってから build_terrain_texture_array で書き込む
+    pub fn rebuild(&mut self, builder: TerrainArrayBuilder) {
+        let image = self.images.add(white_layers(builder.sources.len()));
+        if let Some(material) = self.materials.get_mut(&self.array.material) {
+            material.extension = TerrainArrayExtension {
+                layers: image.clone(),
+                params: builder.params,
+            };
+        }
+        self.array.image = image;
+        self.array.sources = builder.sources;
+        self.array.built = false;
+    }
+}
+
+// 白で塗りつぶしたテクスチャ配列
+fn white_layers(layer_count: usize) -> Image {
+    let mut image = Image::new_fill(
+        Extent3d {
+            width: TERRAIN_LAYER_SIZE,
+            height: TERRAIN_LAYER_SIZE,
+            depth_or_array_layers: layer_count.max(1) as u32,
+        },
+        TextureDimension::D2,
+        &[255, 255, 255, 255],
+        TextureFormat::Rgba8UnormSrgb,
+        RenderAssetUsages::MAIN_WORLD | RenderAssetUsages::RENDER_WORLD,
+    );
+    // レイヤーが1枚でも配列として扱う
+    image.texture_view_descriptor = Some(TextureViewDescriptor {
+        dimension: Some(TextureViewDimension::D2Array),
+        ..default()
+    });
+    image.sampler = ImageSampler::Descriptor(ImageSamplerDescriptor {
+        address_mode_u: ImageAddressMode::Repeat,
+        address_mode_v: ImageAddressMode::Repeat,
+        ..default()
+    });
+    image
+}
+
+// テクスチャ配列のマテリアルを作る (レイヤーは material_setup で割り当てる)
+pub fn setup_terrain_array_material(
+    mut array: ResMut<TerrainTextureArray>,
+    mut images: ResMut<Assets<Image>>,
+    mut materials: ResMut<Assets<TerrainArrayMaterial>>,
+) {
+    array.image = images.add(white_layers(1));
+    array.material = materials.add(TerrainArrayMaterial {
+        base: StandardMaterial::default(),
+        extension: TerrainArrayExtension {
+            layers: array.image.clone(),
+            params: TerrainLayerParams::default(),
+        },
+    });
+    array.built = true;
+}
+
+// レイヤーの元になるテクスチャが全て読み込まれたら、テクスチャ配列に書き込む
+pub fn build_terrain_texture_array(
+    mut array: ResMut<TerrainTextureArray>,
+    mut images: ResMut<Assets<Image>>,
+    mut materials: ResMut<Assets<TerrainArrayMaterial>>,
+    asset_server: Res<AssetServer>,
+) {
+    if array.built {
+        return;
+    }
+    // 読み込みに失敗したテクスチャは白のままにする
+    let loading = array.sources.iter().flatten().any(|handle| {
+        !matches!(asset_server.get_load_state(handle), Some(LoadState::Loaded | LoadState::Failed(_)))
+    });
+    if loading {
+        return;
+    }
+
+    let layer_bytes = (TERRAIN_LAYER_SIZE * TERRAIN_LAYER_SIZE * 4) as usize;
+    let mut data = vec![255; layer_bytes * array.sources.len().max(1)];
+    for (layer, source) in array.sources.iter().enumerate() {
+        let Some(texture) = source.as_ref().and_then(|handle| images.get(handle)) else {
+            continue;
+        };
+        let converted;
+        let texture = if texture.texture_descriptor.format == TextureFormat::Rgba8UnormSrgb {
+            texture
+        } else {
+            let Some(image) = texture.convert(TextureFormat::Rgba8UnormSrgb) else {
+                warn!("Unsupported texture format for terrain texture array: {:?}", texture.texture_descriptor.format);
+                continue;
+            };
+            converted = image;
+            &converted
+        };
+        let Some(pixels) = texture.data.as_ref() else {
+            continue;
+        };
+        let (width, height) = (texture.width(), texture.height());
+        let layer_data = &mut data[layer * layer_bytes..(layer + 1) * layer_bytes];
+        for (y, x) in itertools::iproduct!(0..TERRAIN_LAYER_SIZE, 0..TERRAIN_LAYER_SIZE) {
+            let src_x = x * width / TERRAIN_LAYER_SIZE;
+            let src_y = y * height / TERRAIN_LAYER_SIZE;
+            let src = ((src_y * width + src_x) * 4) as usize;
+            let dst = ((y * TERRAIN_LAYER_SIZE + x) * 4) as usize;
+            layer_data[dst..dst + 4].copy_from_slice(&pixels[src..src + 4]);
+        }
+    }
+
+    if let Some(image) = images.get_mut(&array.image) {
+        image.data = Some(data);
+    }
+    // テクスチャの差し替えをマテリアルのバインドグループに反映させる
+    materials.get_mut(&array.material);
+    array.built = true;
+}
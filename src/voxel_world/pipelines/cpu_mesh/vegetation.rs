@@ -0,0 +1,77 @@
+use bevy::prelude::*;
+use bevy::pbr::{ExtendedMaterial, MaterialExtension};
+use bevy::render::render_resource::AsBindGroup;
+
+// 風で揺れる草花と葉のマテリアル
+// 頂点の UV_1 の y に揺れの重み (0: 動かない ..= 1: 最も大きく揺れる) を入れる
+#[derive(Asset, TypePath, AsBindGroup, Debug, Clone)]
+pub struct VegetationExtension {
+    // 風が吹いていく向き (xz)
+    #[uniform(100)]
+    pub direction: Vec2,
+    #[uniform(100)]
+    pub strength: f32,
+    #[uniform(100)]
+    pub speed: f32,
+}
+
+impl Default for VegetationExtension {
+    fn default() -> Self {
+        Self::from(&WindSettings::default())
+    }
+}
+
+impl From<&WindSettings> for VegetationExtension {
+    fn from(settings: &WindSettings) -> Self {
+        Self {
+            direction: settings.direction.normalize_or(Vec2::X),
+            strength: settings.strength,
+            speed: settings.speed,
+        }
+    }
+}
+
+// 風の設定。インスペクターから実行中に変更できる
+#[derive(Resource, Reflect, Debug, Clone)]
+#[reflect(Resource)]
+pub struct WindSettings {
+    // 風が吹いていく向き (xz)
+    pub direction: Vec2,
+    // 重み1の頂点が揺れる最大の幅 (ブロック単位)
+    pub strength: f32,
+    // 突風の模様が風下へ流れる速さ (ブロック/秒)
+    pub speed: f32,
+}
+
+impl Default for WindSettings {
+    fn default() -> Self {
+        Self {
+            direction: Vec2::new(1.0, 0.3),
+            strength: 0.12,
+            speed: 3.0,
+        }
+    }
+}
+
+// 設定の変更を全ての草花と葉のマテリアルに反映する
+pub fn apply_wind_settings(
+    settings: Res<WindSettings>,
+    mut materials: ResMut<Assets<VegetationMaterial>>,
+) {
+    for (_, material) in materials.iter_mut() {
+        material.extension = VegetationExtension::from(&*settings);
+    }
+}
+
+impl MaterialExtension for VegetationExtension {
+    fn vertex_shader() -> bevy::shader::ShaderRef {
+        "shaders/vegetation.wgsl".into()
+    }
+
+    // 深度のプリパスと影も同じだけ動かさないと、描画した位置とずれる
+    fn prepass_vertex_shader() -> bevy::shader::ShaderRef {
+        "shaders/vegetation.wgsl".into()
+    }
+}
+
+pub type VegetationMaterial = ExtendedMaterial<StandardMaterial, VegetationExtension>;
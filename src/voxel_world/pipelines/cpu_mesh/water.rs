@@ -9,24 +9,86 @@ pub struct WaterExtension {
     #[uniform(100)]
     pub shallow_color: LinearRgba,
     #[uniform(100)]
+    pub foam_color: LinearRgba,
+    #[uniform(100)]
     pub depth_scale: f32,
+    #[uniform(100)]
+    pub foam_depth: f32,
+    #[uniform(100)]
+    pub refraction_strength: f32,
     // パディング
     #[uniform(100)]
-    pub _padding: Vec3,
+    pub _padding: f32,
 }
 
 impl Default for WaterExtension {
     fn default() -> Self {
+        Self::from(&WaterSettings::default())
+    }
+}
+
+impl From<&WaterSettings> for WaterExtension {
+    fn from(settings: &WaterSettings) -> Self {
         Self {
-            deep_color: LinearRgba::from(Color::srgba(0.0, 0.05, 0.5, 0.99)),
-            shallow_color: LinearRgba::from(Color::srgba(0.05, 0.1, 0.4, 0.0)),
-            depth_scale: 0.5,
-            _padding: Vec3::ZERO,
+            deep_color: settings.deep_color.to_linear(),
+            shallow_color: settings.shallow_color.to_linear(),
+            foam_color: settings.foam_color.to_linear(),
+            depth_scale: settings.depth_scale,
+            foam_depth: settings.foam_depth,
+            refraction_strength: settings.refraction_strength,
+            _padding: 0.0,
         }
     }
 }
 
+// 水の見た目の設定。インスペクターから実行中に変更できる
+// バイオームごとの違いは頂点カラー (BiomeTint::Water) で両方の色に掛ける
+#[derive(Resource, Reflect, Debug, Clone)]
+#[reflect(Resource)]
+pub struct WaterSettings {
+    // 十分に深い水の色 (アルファは不透明度)
+    pub deep_color: Color,
+    // 浅瀬の色
+    pub shallow_color: Color,
+    // 深さ1ブロックあたりの吸収の強さ (大きいほどすぐに深い色になる)
+    pub depth_scale: f32,
+    // 岸辺の泡の色 (アルファは泡の濃さ)
+    pub foam_color: Color,
+    // 地形までの深さがこれより浅いところに泡が出る (ブロック単位)
+    pub foam_depth: f32,
+    // 波で水面の向こうの景色をずらす強さ (画面のUV単位)
+    pub refraction_strength: f32,
+}
+
+impl Default for WaterSettings {
+    fn default() -> Self {
+        Self {
+            deep_color: Color::linear_rgba(0.0, 0.03, 0.2, 0.99),
+            shallow_color: Color::linear_rgba(0.1, 0.25, 0.6, 0.45),
+            depth_scale: 0.7,
+            foam_color: Color::linear_rgba(0.9, 0.95, 1.0, 0.85),
+            foam_depth: 0.6,
+            refraction_strength: 0.25,
+        }
+    }
+}
+
+// 設定の変更を全ての水のマテリアルに反映する
+pub fn apply_water_settings(
+    settings: Res<WaterSettings>,
+    mut materials: ResMut<Assets<WaterMaterial>>,
+) {
+    for (_, material) in materials.iter_mut() {
+        material.extension = WaterExtension::from(&*settings);
+    }
+}
+
 impl MaterialExtension for WaterExtension {
+    // 水面の頂点を波の高さだけ動かす
+    fn vertex_shader() -> bevy::shader::ShaderRef {
+        "shaders/water_material.wgsl".into()
+    }
+
     fn fragment_shader() -> bevy::shader::ShaderRef {
         "shaders/water_material.wgsl".into()
     }
@@ -1,241 +1,326 @@
-use bevy::platform::collections::HashMap;
+use bevy::{asset::{io::Reader, AssetLoader, LoadContext}, prelude::*};
+use noise::{Fbm, MultiFractal, NoiseFn, OpenSimplex};
+use serde::Deserialize;
 use std::sync::Arc;
-use crate::voxel_world::{pipelines::cpu_noise::feature::BigOakTreeFeature, core::voxel::Voxel};
-use super::feature::{Feature, OakTreeFeature, CactusFeature, FlowerFeature, PineTreeFeature, BirchTreeFeature, IceSpikeFeature, BambooFeature, AcaciaTreeFeature, JungleTreeFeature, MegaJungleTreeFeature, JungleBushFeature};
+use crate::voxel_world::core::{registry::VoxelRegistry, voxel::{BiomeTint, Voxel}};
+use super::feature::{get_feature_by_name, Feature};
+
+// バイオームを定義するアセット
+pub const BIOME_DEFINITIONS_PATH: &str = "biomes/default.biomes.ron";
+
+// 高度1ブロックの差を、気候パラメータ (気温・湿度・希少度) のどれだけの差とみなすか
+const ALTITUDE_SCALE: f64 = 0.01;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Biome {
+    pub id: u8,
+}
+
+impl Biome {
+    pub fn new(id: u8) -> Self {
+        Self { id }
+    }
+}
 
 pub struct BiomeData {
     pub id: u8,
     #[allow(dead_code)]
-    pub name: &'static str,
+    pub name: String,
     pub surface_block: Voxel,
     pub sub_surface_block: Voxel,
+    // 海面 (y = -1) のブロック (凍った海など)
+    pub water_surface_block: Voxel,
     pub features: Vec<(Arc<dyn Feature>, f32)>,
+    pub climate: ClimateRange,
+    // 草・葉・水の面に掛ける色 (BiomeTint の順)
+    pub tint_colors: [Color; BiomeTint::COUNT],
 }
 
-macro_rules! define_biomes {
-    (
-        $(
-            $name:ident = $id:expr => {
-                name: $biome_name:expr,
-                surface: $surface:expr,
-                sub_surface: $sub:expr,
-                features: $features:expr
-            }
-        ),* $(,)?
-    ) => {
-        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-        pub struct Biome {
-            pub id: u8,
-        }
+impl BiomeData {
+    pub fn tint_color(&self, tint: BiomeTint) -> Color {
+        self.tint_colors[tint.index()]
+    }
+}
 
-        impl Biome {
-            $(
-                pub const $name: Self = Self { id: $id };
-            )*
-            
-            #[allow(dead_code)]
-            pub fn new(id: u8) -> Self {
-                Self { id }
-            }
-        }
+// バイオームが現れる気候パラメータの範囲 (両端を含む)
+// 省略した軸は気温・湿度・希少度が -1.0..1.0、高度が陸上 (0..1000) になる
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct ClimateRange {
+    pub temperature: (f64, f64),
+    pub humidity: (f64, f64),
+    pub rarity: (f64, f64),
+    pub altitude: (i32, i32),
+}
 
-        pub fn get_biome_definitions() -> Vec<BiomeData> {
-            vec![
-                $(
-                    BiomeData {
-                        id: $id,
-                        name: $biome_name,
-                        surface_block: $surface,
-                        sub_surface_block: $sub,
-                        features: $features,
-                    },
-                )*
-            ]
+impl Default for ClimateRange {
+    fn default() -> Self {
+        Self {
+            temperature: (-1.0, 1.0),
+            humidity: (-1.0, 1.0),
+            rarity: (-1.0, 1.0),
+            altitude: (0, 1000),
         }
     }
 }
 
-define_biomes! {
-    PLAINS = 0 => {
-        name: "Plains",
-        surface: Voxel::GRASS,
-        sub_surface: Voxel::DIRT,
-        features: vec![(Arc::new(BigOakTreeFeature), 0.0001)]
-    },
-    DESERT = 1 => {
-        name: "Desert",
-        surface: Voxel::SAND,
-        sub_surface: Voxel::SAND,
-        features: vec![(Arc::new(CactusFeature), 0.01)]
-    },
-    MOUNTAINS = 2 => {
-        name: "Mountains",
-        surface: Voxel::STONE,
-        sub_surface: Voxel::STONE,
-        features: vec![]
-    },
-    SNOW = 3 => {
-        name: "Snow",
-        surface: Voxel::SNOW,
-        sub_surface: Voxel::DIRT,
-        features: vec![(Arc::new(PineTreeFeature), 0.02)]
-    },
-    OCEAN = 4 => {
-        name: "Ocean",
-        surface: Voxel::GRAVEL,
-        sub_surface: Voxel::STONE,
-        features: vec![]
-    },
-    OAK_FOREST = 5 => {
-        name: "Oak Forest",
-        surface: Voxel::GRASS,
-        sub_surface: Voxel::DIRT,
-        features: vec![
-            (Arc::new(OakTreeFeature), 0.02),
-            (Arc::new(FlowerFeature), 0.02)
-        ]
-    },
-    BIRCH_FOREST = 6 => {
-        name: "Birch Forest",
-        surface: Voxel::GRASS,
-        sub_surface: Voxel::DIRT,
-        features: vec![
-            (Arc::new(BirchTreeFeature), 0.02),
-            (Arc::new(FlowerFeature), 0.02)
-        ]
-    },
-    FLOWER_FIELD = 7 => {
-        name: "Flower Field",
-        surface: Voxel::GRASS,
-        sub_surface: Voxel::DIRT,
-        features: vec![(Arc::new(FlowerFeature), 0.3)]
-    },
-    SNOW_FIELD = 8 => {
-        name: "Snow Field",
-        surface: Voxel::SNOW,
-        sub_surface: Voxel::SNOW,
-        features: vec![]
-    },
-    SAVANNA = 9 => {
-        name: "Savanna",
-        surface: Voxel::GRASS,
-        sub_surface: Voxel::DIRT,
-        features: vec![(Arc::new(AcaciaTreeFeature), 0.002)]
-    },
-    JUNGLE = 10 => {
-        name: "Jungle",
-        surface: Voxel::GRASS,
-        sub_surface: Voxel::DIRT,
-        features: vec![
-            (Arc::new(MegaJungleTreeFeature), 0.005),
-            (Arc::new(JungleTreeFeature), 0.03),
-            (Arc::new(JungleBushFeature), 0.05),
-            (Arc::new(FlowerFeature), 0.01)
-        ]
-    },
-    BEACH = 11 => {
-        name: "Beach",
-        surface: Voxel::SAND,
-        sub_surface: Voxel::SAND,
-        features: vec![]
-    },
-    COLD_OCEAN = 12 => {
-        name: "Cold Ocean",
-        surface: Voxel::GRAVEL,
-        sub_surface: Voxel::STONE,
-        features: vec![]
-    },
-    SUNFLOWER_PLAINS = 13 => {
-        name: "Sunflower Plains",
-        surface: Voxel::GRASS,
-        sub_surface: Voxel::DIRT,
-        features: vec![
-            (Arc::new(BigOakTreeFeature), 0.001),
-            (Arc::new(FlowerFeature), 0.2)
-        ]
-    },
-    ICE_SPIKES = 14 => {
-        name: "Ice Spikes",
-        surface: Voxel::SNOW,
-        sub_surface: Voxel::PACKED_ICE,
-        features: vec![(Arc::new(IceSpikeFeature), 0.01)]
-    },
-    RED_DESERT = 15 => {
-        name: "Red Desert",
-        surface: Voxel::RED_SAND,
-        sub_surface: Voxel::RED_SAND,
-        features: vec![(Arc::new(CactusFeature), 0.01)]
-    },
-    BAMBOO_JUNGLE = 16 => {
-        name: "Bamboo Jungle",
-        surface: Voxel::GRASS,
-        sub_surface: Voxel::DIRT,
-        features: vec![
-            (Arc::new(BambooFeature), 0.1),
-            (Arc::new(JungleTreeFeature), 0.005),
-            (Arc::new(JungleBushFeature), 0.01)
+impl ClimateRange {
+    fn axes(&self) -> [(f64, f64); 4] {
+        [
+            self.temperature,
+            self.humidity,
+            self.rarity,
+            (self.altitude.0 as f64 * ALTITUDE_SCALE, self.altitude.1 as f64 * ALTITUDE_SCALE),
         ]
     }
+
+    // (範囲までの距離, 範囲の中心までの距離)
+    // 範囲の内側なら前者は0になり、範囲が重なる場合は中心に近い方を選ぶ
+    fn distance(&self, point: [f64; 4]) -> (f64, f64) {
+        let mut outside = 0.0;
+        let mut center = 0.0;
+        for ((min, max), value) in self.axes().into_iter().zip(point) {
+            let gap = (min - value).max(value - max).max(0.0);
+            outside += gap * gap;
+            let offset = value - (min + max) * 0.5;
+            center += offset * offset;
+        }
+        (outside, center)
+    }
 }
 
 pub struct BiomeRegistry {
-    // Using a simple grid or logic for now, but storing biomes by ID
-    pub biomes: HashMap<u8, BiomeData>,
+    pub biomes: Vec<BiomeData>,
+    // バイオームIDごとの biomes のインデックス (未定義のIDは先頭のバイオーム)
+    indices_by_id: Box<[usize]>,
+    temperature: Fbm<OpenSimplex>,
+    humidity: Fbm<OpenSimplex>,
+    rarity: Fbm<OpenSimplex>,
 }
 
 impl BiomeRegistry {
-    pub fn new(_seed: u32) -> Self {
-        let mut biomes = HashMap::new();
-        for biome_data in get_biome_definitions() {
-            biomes.insert(biome_data.id, biome_data);
+    pub fn new(seed: u32, biomes: Vec<BiomeData>) -> Self {
+        // Temperature: Controls biome temperature
+        let temperature = Fbm::<OpenSimplex>::new(seed.wrapping_add(100))
+            .set_frequency(0.0015)
+            .set_octaves(4);
+        // Humidity: Controls biome humidity
+        let humidity = Fbm::<OpenSimplex>::new(seed.wrapping_add(200))
+            .set_frequency(0.0015)
+            .set_octaves(4);
+        // Rarity: Controls rare biome variants
+        let rarity = Fbm::<OpenSimplex>::new(seed.wrapping_add(300))
+            .set_frequency(0.001)
+            .set_octaves(4);
+
+        let mut indices_by_id = vec![0; u8::MAX as usize + 1].into_boxed_slice();
+        for (index, biome) in biomes.iter().enumerate() {
+            indices_by_id[biome.id as usize] = index;
+        }
+
+        Self { biomes, indices_by_id, temperature, humidity, rarity }
+    }
+
+    // アセットの定義からバイオームを作る。ブロック名は VoxelRegistry で解決する
+    pub fn from_asset(seed: u32, asset: &BiomeDefinitionsAsset, voxels: &VoxelRegistry) -> Result<Self, String> {
+        if asset.biomes.is_empty() || asset.biomes.len() > u8::MAX as usize + 1 {
+            return Err(format!("expected 1 to 256 biomes, found {}", asset.biomes.len()));
         }
-        Self { biomes }
+        let voxel = |name: &str| {
+            voxels
+                .id_by_name(name)
+                .map(Voxel::new)
+                .ok_or_else(|| format!("unknown voxel {name:?}"))
+        };
+
+        let mut biomes: Vec<BiomeData> = Vec::with_capacity(asset.biomes.len());
+        for spec in &asset.biomes {
+            if let Some(other) = biomes.iter().find(|biome| biome.id == spec.id) {
+                return Err(format!("biome {:?}: id {} is already used by {:?}", spec.name, spec.id, other.name));
+            }
+            let mut features = Vec::with_capacity(spec.features.len());
+            for (name, probability) in &spec.features {
+                let feature = get_feature_by_name(name)
+                    .ok_or_else(|| format!("biome {:?}: unknown feature {name:?}", spec.name))?;
+                features.push((feature, *probability));
+            }
+            biomes.push(BiomeData {
+                id: spec.id,
+                name: spec.name.clone(),
+                surface_block: voxel(&spec.surface)?,
+                sub_surface_block: voxel(&spec.sub_surface)?,
+                water_surface_block: match &spec.water_surface {
+                    Some(name) => voxel(name)?,
+                    None => Voxel::WATER,
+                },
+                features,
+                climate: spec.climate,
+                tint_colors: [spec.grass_color, spec.foliage_color, spec.water_color]
+                    .map(|(r, g, b)| Color::srgb(r, g, b)),
+            });
+        }
+        Ok(Self::new(seed, biomes))
     }
 
     #[allow(dead_code)]
     pub fn get_biome_data(&self, biome: Biome) -> &BiomeData {
-        self.biomes.get(&biome.id).unwrap_or_else(|| self.biomes.get(&0).unwrap())
+        self.get_biome_data_by_id(biome.id)
     }
-    
+
     pub fn get_biome_data_by_id(&self, id: u8) -> &BiomeData {
-        self.biomes.get(&id).unwrap_or_else(|| self.biomes.get(&0).unwrap())
+        &self.biomes[self.indices_by_id[id as usize]]
     }
 
+    // (気温, 湿度, 希少度) のノイズ値
+    pub fn sample_climate(&self, x: f64, z: f64) -> (f64, f64, f64) {
+        (
+            self.temperature.get([x, z]),
+            self.humidity.get([x, z]),
+            self.rarity.get([x, z]),
+        )
+    }
+
+    // (気温, 湿度, 希少度, 高度) の空間で最も近いバイオームを選ぶ
     pub fn resolve_biome(&self, temp: f64, humidity: f64, rarity: f64, altitude: i32) -> Biome {
-        if altitude >= -3 && altitude < 0 && temp > -0.1 {
-            return Biome::BEACH;
-        } else if altitude < 0 {
-            if temp < -0.3 {
-                return Biome::COLD_OCEAN;
-            } else {
-                return Biome::OCEAN;
-            }
-        } else if temp < -0.3 {
-            if humidity < 0.0 {
-                if rarity > 0.3 { return Biome::ICE_SPIKES; } else { return Biome::SNOW_FIELD; }
-            } else {
-                return Biome::SNOW;
-            }
-        } else if temp < 0.2 {
-            if humidity < -0.3 {
-                return Biome::MOUNTAINS;
-            } else if humidity < -0.1 {
-                if rarity > 0.3 { return Biome::SUNFLOWER_PLAINS; } else { return Biome::PLAINS; }
-            } else if humidity < 0.0 {
-                return Biome::BIRCH_FOREST;
-            } else if humidity < 0.3 {
-                if rarity > 0.3 { return Biome::FLOWER_FIELD; } else { return Biome::OAK_FOREST; }
-            } else {
-                return Biome::OAK_FOREST;
-            }
-        } else {
-            if humidity < -0.15 {
-                if rarity > 0.3 { return Biome::RED_DESERT; } else { return Biome::DESERT; }
-            } else if humidity < 0.15 {
-                return Biome::SAVANNA;
-            } else {
-                if rarity > 0.3 { return Biome::BAMBOO_JUNGLE; } else { return Biome::JUNGLE; }
-            }
-        }
+        let point = [temp, humidity, rarity, altitude as f64 * ALTITUDE_SCALE];
+        let closest = self.biomes
+            .iter()
+            .min_by(|a, b| {
+                let (a_outside, a_center) = a.climate.distance(point);
+                let (b_outside, b_center) = b.climate.distance(point);
+                a_outside.total_cmp(&b_outside).then(a_center.total_cmp(&b_center))
+            })
+            .map_or(0, |biome| biome.id);
+        Biome::new(closest)
+    }
+}
+
+// アセットファイルの形式
+#[derive(Asset, TypePath, Debug, Deserialize)]
+pub struct BiomeDefinitionsAsset {
+    pub biomes: Vec<BiomeDefinitionSpec>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BiomeDefinitionSpec {
+    // 生成済みのワールドのバイオームマップに使われるので、一度決めたら変えないこと
+    pub id: u8,
+    pub name: String,
+    pub surface: String,
+    pub sub_surface: String,
+    #[serde(default)]
+    pub water_surface: Option<String>,
+    // (フィーチャー名, 地表1ブロックあたりの確率)
+    #[serde(default)]
+    pub features: Vec<(String, f32)>,
+    #[serde(default)]
+    pub climate: ClimateRange,
+    // sRGB。省略時は白 (ボクセルの色のまま)
+    #[serde(default = "white")]
+    pub grass_color: (f32, f32, f32),
+    #[serde(default = "white")]
+    pub foliage_color: (f32, f32, f32),
+    #[serde(default = "white")]
+    pub water_color: (f32, f32, f32),
+}
+
+fn white() -> (f32, f32, f32) {
+    (1.0, 1.0, 1.0)
+}
+
+#[derive(Default, TypePath)]
+pub struct BiomeDefinitionsLoader;
+
+impl AssetLoader for BiomeDefinitionsLoader {
+    type Asset = BiomeDefinitionsAsset;
+    type Settings = ();
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        _settings: &(),
+        _load_context: &mut LoadContext<'_>,
+    ) -> Result<Self::Asset, Self::Error> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        Ok(ron::de::from_bytes(&bytes)?)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["biomes.ron"]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(biomes: &str) -> BiomeDefinitionsAsset {
+        ron::from_str(&format!("#![enable(implicit_some)]\n(biomes: [{biomes}])")).unwrap()
+    }
+
+    fn from_asset(biomes: &str) -> Result<BiomeRegistry, String> {
+        BiomeRegistry::from_asset(0, &parse(biomes), &VoxelRegistry::default())
+    }
+
+    #[test]
+    fn overlapping_ranges_pick_the_nearer_centre() {
+        let registry = from_asset(
+            r#"(id: 3, name: "Cold", surface: "SNOW", sub_surface: "DIRT", climate: (temperature: (-1.0, 0.5))),
+               (id: 7, name: "Warm", surface: "SAND", sub_surface: "SAND", climate: (temperature: (-0.5, 1.0))),
+               (id: 9, name: "Hot", surface: "RED_SAND", sub_surface: "SAND", climate: (temperature: (0.9, 1.0)))"#,
+        )
+        .unwrap();
+
+        // Cold と Warm の両方の範囲に入る
+        assert_eq!(registry.resolve_biome(-0.4, 0.0, 0.0, 64).id, 3);
+        assert_eq!(registry.resolve_biome(0.4, 0.0, 0.0, 64).id, 7);
+        // 3つとも範囲に入る場合も中心が最も近いもの
+        assert_eq!(registry.resolve_biome(0.95, 0.0, 0.0, 64).id, 9);
+        // どの範囲にも入らない場合は範囲に最も近いもの
+        assert_eq!(registry.resolve_biome(-2.0, 0.0, 0.0, 64).id, 3);
+        assert_eq!(registry.resolve_biome(0.95, 0.0, 0.0, -50).id, 9);
+    }
+
+    #[test]
+    fn biomes_are_looked_up_by_id() {
+        let registry = from_asset(
+            r#"(id: 4, name: "Plains", surface: "GRASS", sub_surface: "DIRT"),
+               (id: 2, name: "Frozen", surface: "SNOW", sub_surface: "DIRT", water_surface: "ICE", features: [("PINE_TREE", 0.02)])"#,
+        )
+        .unwrap();
+
+        let frozen = registry.get_biome_data_by_id(2);
+        assert_eq!(frozen.name, "Frozen");
+        assert_eq!(frozen.water_surface_block, Voxel::ICE);
+        assert_eq!(frozen.features.len(), 1);
+        assert_eq!(registry.get_biome_data_by_id(4).water_surface_block, Voxel::WATER);
+        // 未定義の id は先頭のバイオームになる
+        assert_eq!(registry.get_biome_data_by_id(200).name, "Plains");
+    }
+
+    #[test]
+    fn invalid_definitions_are_rejected() {
+        let duplicate = from_asset(
+            r#"(id: 1, name: "A", surface: "GRASS", sub_surface: "DIRT"),
+               (id: 1, name: "B", surface: "SAND", sub_surface: "SAND")"#,
+        );
+        assert!(duplicate.err().unwrap().contains("id 1 is already used by \"A\""));
+
+        let unknown_voxel = from_asset(r#"(id: 0, name: "A", surface: "GRASSS", sub_surface: "DIRT")"#);
+        assert!(unknown_voxel.err().unwrap().contains("unknown voxel \"GRASSS\""));
+
+        let unknown_feature = from_asset(r#"(id: 0, name: "A", surface: "GRASS", sub_surface: "DIRT", features: [("PALM_TREE", 0.1)])"#);
+        assert!(unknown_feature.err().unwrap().contains("unknown feature \"PALM_TREE\""));
+
+        assert!(from_asset("").is_err());
+    }
+
+    #[test]
+    fn default_biome_file_loads() {
+        let asset: BiomeDefinitionsAsset = ron::from_str(include_str!("../../../../assets/biomes/default.biomes.ron")).unwrap();
+        let registry = BiomeRegistry::from_asset(0, &asset, &VoxelRegistry::default()).unwrap();
+        assert_eq!(registry.biomes.len(), asset.biomes.len());
     }
 }
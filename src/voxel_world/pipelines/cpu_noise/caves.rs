@@ -0,0 +1,259 @@
+use bevy::prelude::*;
+use block_mesh::ndshape::{AbstractShape, ConstShape2u32, ConstShape3u32};
+use noise::{Fbm, MultiFractal, NoiseFn, OpenSimplex};
+use crate::voxel_world::core::coordinates::{TERRAIN_CHUNK_SIZE, VOXEL_SIZE};
+
+type AltitudeMapShape = ConstShape2u32<TERRAIN_CHUNK_SIZE, TERRAIN_CHUNK_SIZE>;
+pub type CaveMaskShape = ConstShape3u32<TERRAIN_CHUNK_SIZE, TERRAIN_CHUNK_SIZE, TERRAIN_CHUNK_SIZE>;
+
+// ノイズはこの間隔の格子点でだけ計算し、間は線形補間する
+// 格子点はワールド座標で決まるので、チャンク境界でも洞窟がつながる
+const SAMPLE_STEP: i32 = 4;
+const SAMPLES_PER_AXIS: usize = (TERRAIN_CHUNK_SIZE as i32 / SAMPLE_STEP) as usize + 1;
+type SampleShape = ConstShape3u32<{ SAMPLES_PER_AXIS as u32 }, { SAMPLES_PER_AXIS as u32 }, { SAMPLES_PER_AXIS as u32 }>;
+
+// 大きな空洞 (cheese): ノイズがこの値を超える場所を掘る
+const CHEESE_THRESHOLD: f64 = 0.25;
+// 曲がりくねったトンネル (spaghetti): 2つのノイズが同時に0に近い場所を掘る
+const SPAGHETTI_RADIUS: f64 = 0.03;
+// 地表付近は入口以外では掘らずに残す厚さ
+const SURFACE_CRUST: i32 = 5;
+// 海面より下では、周囲の最も低い地表からこの厚さを残して掘る
+// 洞窟が海底や隣の海の柱に開いて水が流れ込まないようにする
+const OCEAN_CRUST: i32 = 8;
+// 周囲の地表の高さを調べる範囲 (隣のチャンクの列も含む)
+const OCEAN_CHECK_RADIUS: i32 = 2;
+// carve_mask に渡す高度マップがチャンクの周囲に含む列の数
+pub const CAVE_ALTITUDE_MARGIN: i32 = OCEAN_CHECK_RADIUS;
+// 入口ノイズがこの値を超える陸地では、トンネルが地表まで貫通する
+const ENTRANCE_THRESHOLD: f64 = 0.1;
+// 入口を作る地表の最低高度 (水辺には入口を作らない)
+const ENTRANCE_MIN_ALTITUDE: i32 = 4;
+
+// 列ごとの掘ってよい範囲
+#[derive(Clone, Copy)]
+struct ColumnLimits {
+    // 空洞はこの高さ未満だけ
+    cheese: i32,
+    // トンネルはこの高さ未満だけ (入口では地表まで)
+    spaghetti: i32,
+    // 海面より下ではこの高さ未満だけ
+    ocean: i32,
+}
+
+impl ColumnLimits {
+    #[inline]
+    fn allows(limit: i32, ocean: i32, world_y: i32) -> bool {
+        world_y < limit && (world_y >= 0 || world_y < ocean)
+    }
+}
+
+pub struct CaveGenerator {
+    cheese: Fbm<OpenSimplex>,
+    spaghetti_a: Fbm<OpenSimplex>,
+    spaghetti_b: Fbm<OpenSimplex>,
+    entrance: Fbm<OpenSimplex>,
+}
+
+impl CaveGenerator {
+    pub fn new(seed: u32) -> Self {
+        let cheese = Fbm::<OpenSimplex>::new(seed.wrapping_add(400))
+            .set_frequency(0.012)
+            .set_octaves(3);
+        let spaghetti_a = Fbm::<OpenSimplex>::new(seed.wrapping_add(401))
+            .set_frequency(0.015)
+            .set_octaves(2);
+        let spaghetti_b = Fbm::<OpenSimplex>::new(seed.wrapping_add(402))
+            .set_frequency(0.015)
+            .set_octaves(2);
+        let entrance = Fbm::<OpenSimplex>::new(seed.wrapping_add(403))
+            .set_frequency(0.01)
+            .set_octaves(2);
+        Self { cheese, spaghetti_a, spaghetti_b, entrance }
+    }
+
+    // (cheese, spaghetti_a, spaghetti_b)
+    fn sample(&self, world_pos: IVec3) -> [f64; 3] {
+        let p = world_pos.as_vec3() * VOXEL_SIZE;
+        let (x, y, z) = (p.x as f64, p.y as f64, p.z as f64);
+        [
+            // 空洞は縦方向に潰して横に広がるようにする
+            self.cheese.get([x, y * 2.0, z]),
+            self.spaghetti_a.get([x, y, z]),
+            self.spaghetti_b.get([x, y, z]),
+        ]
+    }
+
+    fn column_limits(&self, origin: IVec3, padded_altitude_map: &[i32]) -> Vec<ColumnLimits> {
+        let size = TERRAIN_CHUNK_SIZE as i32;
+        let padded_size = size + 2 * CAVE_ALTITUDE_MARGIN;
+        let altitude_at = |x: i32, z: i32| {
+            padded_altitude_map[((z + CAVE_ALTITUDE_MARGIN) * padded_size + x + CAVE_ALTITUDE_MARGIN) as usize]
+        };
+
+        let mut limits = Vec::with_capacity((size * size) as usize);
+        for z in 0..size {
+            for x in 0..size {
+                let altitude = altitude_at(x, z);
+                let mut nearby_min = altitude;
+                for dz in -OCEAN_CHECK_RADIUS..=OCEAN_CHECK_RADIUS {
+                    for dx in -OCEAN_CHECK_RADIUS..=OCEAN_CHECK_RADIUS {
+                        nearby_min = nearby_min.min(altitude_at(x + dx, z + dz));
+                    }
+                }
+
+                let world_xz = (IVec2::new(x, z) + origin.xz()).as_vec2() * VOXEL_SIZE;
+                let entrance = nearby_min >= ENTRANCE_MIN_ALTITUDE
+                    && self.entrance.get([world_xz.x as f64, world_xz.y as f64]) > ENTRANCE_THRESHOLD;
+                let cheese = altitude - SURFACE_CRUST;
+                // 周囲が十分に高い陸地なら水が入り込むことはない
+                let ocean = if nearby_min >= OCEAN_CRUST {
+                    i32::MAX
+                } else {
+                    nearby_min.min(0) - OCEAN_CRUST
+                };
+                limits.push(ColumnLimits {
+                    cheese,
+                    spaghetti: if entrance { altitude + 1 } else { cheese },
+                    ocean,
+                });
+            }
+        }
+        limits
+    }
+
+    // チャンク内で空気にするボクセルのマスク (TerrainChunkData と同じ順番で線形化)
+    // 掘るボクセルが1つもない場合はNone
+    // 高度マップはチャンクの周囲に CAVE_ALTITUDE_MARGIN 列ずつ広げたもの (x が先に変わる順)
+    // 結果はシードとチャンクの位置 (と決定的な高度マップ) だけで決まる
+    pub fn carve_mask(&self, chunk_pos: IVec3, padded_altitude_map: &[i32]) -> Option<Vec<bool>> {
+        let size = TERRAIN_CHUNK_SIZE as i32;
+        let origin = chunk_pos * size;
+        let limits = self.column_limits(origin, padded_altitude_map);
+        let highest = limits.iter().map(|l| l.spaghetti).max()?;
+        if origin.y >= highest {
+            return None;
+        }
+
+        let mut samples = vec![[0.0; 3]; SampleShape {}.size() as usize];
+        for (i, sample) in samples.iter_mut().enumerate() {
+            let [sx, sy, sz] = SampleShape {}.delinearize(i as u32);
+            let offset = IVec3::new(sx as i32, sy as i32, sz as i32) * SAMPLE_STEP;
+            *sample = self.sample(origin + offset);
+        }
+
+        let mut mask = vec![false; CaveMaskShape {}.size() as usize];
+        let mut any = false;
+        for z in 0..size {
+            for x in 0..size {
+                let column = limits[AltitudeMapShape {}.linearize([x as u32, z as u32]) as usize];
+                for y in 0..size {
+                    let world_y = origin.y + y;
+                    let cheese_allowed = ColumnLimits::allows(column.cheese, column.ocean, world_y);
+                    let spaghetti_allowed = ColumnLimits::allows(column.spaghetti, column.ocean, world_y);
+                    if !cheese_allowed && !spaghetti_allowed {
+                        continue;
+                    }
+                    let [cheese, a, b] = interpolate(&samples, IVec3::new(x, y, z));
+                    let carved = (cheese_allowed && cheese > CHEESE_THRESHOLD)
+                        || (spaghetti_allowed && (a * a + b * b).sqrt() < SPAGHETTI_RADIUS);
+                    if carved {
+                        mask[CaveMaskShape {}.linearize([x as u32, y as u32, z as u32]) as usize] = true;
+                        any = true;
+                    }
+                }
+            }
+        }
+        any.then_some(mask)
+    }
+}
+
+// チャンク内のローカル座標での格子点の値の三線形補間
+fn interpolate(samples: &[[f64; 3]], local: IVec3) -> [f64; 3] {
+    let cell = local / SAMPLE_STEP;
+    let t = (local - cell * SAMPLE_STEP).as_dvec3() / SAMPLE_STEP as f64;
+    let at = |dx: i32, dy: i32, dz: i32| {
+        let p = cell + IVec3::new(dx, dy, dz);
+        samples[SampleShape {}.linearize([p.x as u32, p.y as u32, p.z as u32]) as usize]
+    };
+    let mut result = [0.0; 3];
+    for (i, value) in result.iter_mut().enumerate() {
+        let lerp = |a: f64, b: f64, t: f64| a + (b - a) * t;
+        let x00 = lerp(at(0, 0, 0)[i], at(1, 0, 0)[i], t.x);
+        let x10 = lerp(at(0, 1, 0)[i], at(1, 1, 0)[i], t.x);
+        let x01 = lerp(at(0, 0, 1)[i], at(1, 0, 1)[i], t.x);
+        let x11 = lerp(at(0, 1, 1)[i], at(1, 1, 1)[i], t.x);
+        let y0 = lerp(x00, x10, t.y);
+        let y1 = lerp(x01, x11, t.y);
+        *value = lerp(y0, y1, t.z);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: i32 = TERRAIN_CHUNK_SIZE as i32;
+    const PADDED_SIZE: i32 = SIZE + 2 * CAVE_ALTITUDE_MARGIN;
+
+    // ローカル座標 (周囲の列は負や SIZE 以上) から高度を決める高度マップ
+    fn padded_map(altitude: impl Fn(i32, i32) -> i32) -> Vec<i32> {
+        let range = -CAVE_ALTITUDE_MARGIN..SIZE + CAVE_ALTITUDE_MARGIN;
+        let map = range.clone().flat_map(|z| range.clone().map(move |x| (x, z))).map(|(x, z)| altitude(x, z)).collect::<Vec<_>>();
+        assert_eq!(map.len(), (PADDED_SIZE * PADDED_SIZE) as usize);
+        map
+    }
+
+    fn is_carved(mask: &[bool], x: i32, y: i32, z: i32) -> bool {
+        mask[CaveMaskShape {}.linearize([x as u32, y as u32, z as u32]) as usize]
+    }
+
+    #[test]
+    fn carve_mask_is_deterministic() {
+        let map = padded_map(|_, _| 100);
+        let a = CaveGenerator::new(7);
+        let b = CaveGenerator::new(7);
+        let mut carved_chunks = 0;
+        for chunk_pos in [IVec3::new(0, 0, 0), IVec3::new(3, -1, -2), IVec3::new(-5, 1, 4), IVec3::new(10, -2, 10)] {
+            let mask = a.carve_mask(chunk_pos, &map);
+            assert_eq!(mask, b.carve_mask(chunk_pos, &map));
+            // 同じ生成器で何度計算しても同じ
+            assert_eq!(mask, a.carve_mask(chunk_pos, &map));
+            carved_chunks += mask.is_some() as i32;
+        }
+        assert!(carved_chunks > 0);
+
+        // 地表より高いチャンクは掘らない
+        assert!(a.carve_mask(IVec3::new(0, 3, 0), &map).is_none());
+    }
+
+    #[test]
+    fn ocean_columns_keep_their_crust() {
+        let floor = -20;
+        // チャンクの x = 0 側に隣接する列だけが海で、残りは陸地
+        let map = padded_map(|x, _| if x < 0 { floor } else { 100 });
+        let caves = CaveGenerator::new(7);
+        let mut carved_below = false;
+        for chunk_pos in (-2..2).flat_map(|x| (-2..2).map(move |z| IVec3::new(x, -1, z))) {
+            let Some(mask) = caves.carve_mask(chunk_pos, &map) else {
+                continue;
+            };
+            for z in 0..SIZE {
+                for y in 0..SIZE {
+                    let world_y = chunk_pos.y * SIZE + y;
+                    // 海の列から OCEAN_CHECK_RADIUS 以内の列は海底から OCEAN_CRUST を残す
+                    for x in 0..OCEAN_CHECK_RADIUS {
+                        if world_y >= floor - OCEAN_CRUST {
+                            assert!(!is_carved(&mask, x, y, z), "carved ({x}, {world_y}, {z}) in {chunk_pos}");
+                        } else {
+                            carved_below |= is_carved(&mask, x, y, z);
+                        }
+                    }
+                }
+            }
+        }
+        // 地殻より下は掘られる
+        assert!(carved_below);
+    }
+}
@@ -1,3 +1,4 @@
+use std::sync::Arc;
 use bevy::math::IVec3;
 use crate::voxel_world::core::voxel::Voxel;
 
@@ -5,6 +6,26 @@ pub trait Feature: Send + Sync {
     fn place(&self, origin: IVec3, seed: u32) -> Vec<(IVec3, Voxel)>;
 }
 
+// バイオーム定義から名前で参照されるフィーチャー
+pub fn get_feature_by_name(name: &str) -> Option<Arc<dyn Feature>> {
+    let feature: Arc<dyn Feature> = match name {
+        "OAK_TREE" => Arc::new(OakTreeFeature),
+        "BIG_OAK_TREE" => Arc::new(BigOakTreeFeature),
+        "CACTUS" => Arc::new(CactusFeature),
+        "FLOWER" => Arc::new(FlowerFeature),
+        "PINE_TREE" => Arc::new(PineTreeFeature),
+        "BIRCH_TREE" => Arc::new(BirchTreeFeature),
+        "ICE_SPIKE" => Arc::new(IceSpikeFeature),
+        "BAMBOO" => Arc::new(BambooFeature),
+        "ACACIA_TREE" => Arc::new(AcaciaTreeFeature),
+        "JUNGLE_TREE" => Arc::new(JungleTreeFeature),
+        "MEGA_JUNGLE_TREE" => Arc::new(MegaJungleTreeFeature),
+        "JUNGLE_BUSH" => Arc::new(JungleBushFeature),
+        _ => return None,
+    };
+    Some(feature)
+}
+
 pub struct OakTreeFeature;
 
 impl Feature for OakTreeFeature {
@@ -22,11 +43,11 @@ impl Feature for OakTreeFeature {
         // Leaves
         let leaves_start = height - 2;
         for y in leaves_start..(height + 2) {
-            let range = if y >= height { 1 } else { 2 };
-            for x in -(range as i32)..=(range as i32) {
-                for z in -(range as i32)..=(range as i32) {
+            let range: i32 = if y >= height { 1 } else { 2 };
+            for x in -range..=range {
+                for z in -range..=range {
                     // Skip corners for rounder look
-                    if x.abs() == range as i32 && z.abs() == range as i32 {
+                    if x.abs() == range && z.abs() == range {
                         continue;
                     }
                     
@@ -69,7 +90,7 @@ pub struct FlowerFeature;
 impl Feature for FlowerFeature {
     fn place(&self, origin: IVec3, seed: u32) -> Vec<(IVec3, Voxel)> {
         let h_hash = hash(origin.x, origin.z, seed);
-        let flower_type = if h_hash % 2 == 0 { Voxel::FLOWER_RED } else { Voxel::FLOWER_YELLOW };
+        let flower_type = if h_hash.is_multiple_of(2) { Voxel::FLOWER_RED } else { Voxel::FLOWER_YELLOW };
         vec![(origin, flower_type)]
     }
 }
@@ -91,12 +112,12 @@ impl Feature for PineTreeFeature {
         // Leaves (Cone shape)
         let leaves_start = 3;
         for y in leaves_start..=height {
-            let radius = if y == height { 0 } else if y > height - 3 { 1 } else { 2 };
+            let radius: i32 = if y == height { 0 } else if y > height - 3 { 1 } else { 2 };
             
-            for x in -(radius as i32)..=(radius as i32) {
-                for z in -(radius as i32)..=(radius as i32) {
+            for x in -radius..=radius {
+                for z in -radius..=radius {
                     if x == 0 && z == 0 && y < height { continue; } // Don't replace trunk
-                    if x.abs() + z.abs() > radius as i32 + 1 { continue; } // Diamond/Cone shape
+                    if x.abs() + z.abs() > radius + 1 { continue; } // Diamond/Cone shape
 
                     let leaf_pos = origin + IVec3::new(x, y as i32, z);
                     changes.push((leaf_pos, Voxel::PINE_LEAVES));
@@ -127,10 +148,10 @@ impl Feature for BirchTreeFeature {
         // Leaves (Similar to normal tree but maybe slightly different)
         let leaves_start = height - 2;
         for y in leaves_start..(height + 2) {
-            let range = if y >= height { 1 } else { 2 };
-            for x in -(range as i32)..=(range as i32) {
-                for z in -(range as i32)..=(range as i32) {
-                    if x.abs() == range as i32 && z.abs() == range as i32 {
+            let range: i32 = if y >= height { 1 } else { 2 };
+            for x in -range..=range {
+                for z in -range..=range {
+                    if x.abs() == range && z.abs() == range {
                         continue;
                     }
                     
@@ -169,7 +190,7 @@ impl Feature for BigOakTreeFeature {
             let start_pos = origin + IVec3::new(0, start_h as i32, 0);
 
             // Direction
-            let angle = (i as f32 / num_branches as f32) * 6.283 + ((branch_seed % 10) as f32 * 0.1);
+            let angle = (i as f32 / num_branches as f32) * std::f32::consts::TAU + ((branch_seed % 10) as f32 * 0.1);
             let dir_x = angle.cos();
             let dir_z = angle.sin();
             
@@ -219,10 +240,10 @@ impl Feature for IceSpikeFeature {
         let height = 10 + (h_hash % 10); // 10 to 19
         
         for i in 0..height {
-            let radius = if i < height / 2 { 2 } else if i < height - 2 { 1 } else { 0 };
-            for x in -(radius as i32)..=(radius as i32) {
-                for z in -(radius as i32)..=(radius as i32) {
-                     if x.abs() + z.abs() > radius as i32 + 1 { continue; }
+            let radius: i32 = if i < height / 2 { 2 } else if i < height - 2 { 1 } else { 0 };
+            for x in -radius..=radius {
+                for z in -radius..=radius {
+                     if x.abs() + z.abs() > radius + 1 { continue; }
                      let pos = origin + IVec3::new(x, i as i32, z);
                      changes.push((pos, Voxel::PACKED_ICE));
                 }
@@ -336,10 +357,10 @@ impl Feature for JungleTreeFeature {
         // Leaves
         let leaves_start = height - 3;
         for y in leaves_start..(height + 2) {
-            let range = if y >= height { 1 } else { 2 };
-            for x in -(range as i32)..=(range as i32) {
-                for z in -(range as i32)..=(range as i32) {
-                    if x.abs() == range as i32 && z.abs() == range as i32 {
+            let range: i32 = if y >= height { 1 } else { 2 };
+            for x in -range..=range {
+                for z in -range..=range {
+                    if x.abs() == range && z.abs() == range {
                         continue;
                     }
                     let leaf_pos = origin + IVec3::new(x, y as i32, z);
@@ -370,9 +391,9 @@ impl Feature for MegaJungleTreeFeature {
         // Leaves (Large canopy)
         let leaves_start = height - 5;
         for y in leaves_start..(height + 2) {
-            let range = if y >= height { 2 } else { 4 };
-            for x in -(range as i32)..=(range as i32 + 1) {
-                for z in -(range as i32)..=(range as i32 + 1) {
+            let range: i32 = if y >= height { 2 } else { 4 };
+            for x in -range..=(range + 1) {
+                for z in -range..=(range + 1) {
                     // Rounding
                     let dist = ((x as f32 - 0.5).powi(2) + (z as f32 - 0.5).powi(2)).sqrt();
                     if dist > range as f32 + 0.5 { continue; }
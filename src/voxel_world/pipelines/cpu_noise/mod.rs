@@ -1,4 +1,5 @@
 pub mod biomes;
+pub mod caves;
 pub mod storage;
 mod feature;
 pub mod generation;
@@ -6,15 +7,18 @@ pub mod generation;
 use bevy::{
     prelude::*,
     tasks::{futures::check_ready, AsyncComputeTaskPool, Task},
+    time::common_conditions::on_timer,
 };
 use itertools::Itertools;
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 use crate::voxel_world::{
-    core::{chunk_range::is_within_active_chunk_range, terrain_chunk::TerrainChunkData, voxel::Voxel, ChunkEntities, ChunkGeneratedEvent, RenderDistanceParams, TerrainChunk},
-    pipelines::cpu_noise::storage::TerrainGenerationStorage,
-    storage::ChunkMap,
+    core::{chunk_range::{is_within_active_chunk_range, should_unload_chunk}, coordinates::TERRAIN_CHUNK_SIZE, registry::{apply_voxel_definitions, VoxelDefinitionsHandle, VoxelRegistry}, terrain_chunk::TerrainChunkData, voxel::Voxel, ChunkEntities, ChunkGeneratedEvent, RenderDistanceParams, TerrainChunk},
+    editing::VoxelWorld,
+    pipelines::cpu_noise::storage::{FeatureChanges, TerrainGenerationStorage},
+    storage::{ChunkMap, RegionStore},
 };
-use self::biomes::BiomeRegistry;
+use self::caves::{CaveGenerator, CAVE_ALTITUDE_MARGIN};
+use self::biomes::{BiomeDefinitionsAsset, BiomeDefinitionsLoader, BiomeRegistry, BIOME_DEFINITIONS_PATH};
 
 #[derive(Default)]
 pub struct CpuNoiseTerrainGenerationPlugin;
@@ -23,16 +27,24 @@ impl Plugin for CpuNoiseTerrainGenerationPlugin {
     fn build(&self, app: &mut App) {
         app
             .insert_resource(TerrainGenerationStorage::default())
+            .init_asset::<BiomeDefinitionsAsset>()
+            .init_asset_loader::<BiomeDefinitionsLoader>()
             .add_message::<ChunkGeneratedEvent>()
             .add_systems(Startup, setup_terrain_generation)
             .add_systems(Update, (
-                queue_altitude_tasks,
-                handle_altitude_tasks,
-                queue_base_terrain_tasks,
-                handle_base_terrain_tasks,
-                queue_feature_tasks,
-                handle_feature_tasks,
+                init_world_gen_config
+                    .run_if(not(resource_exists::<WorldGenConfig>))
+                    .after(apply_voxel_definitions),
+                (
+                    queue_altitude_tasks,
+                    handle_altitude_tasks,
+                    queue_base_terrain_tasks,
+                    handle_base_terrain_tasks,
+                    queue_feature_tasks,
+                    handle_feature_tasks,
+                ).run_if(resource_exists::<WorldGenConfig>),
             ))
+            .add_systems(PostUpdate, drop_unloaded_features.run_if(on_timer(Duration::from_secs(5))))
             ;
     }
 }
@@ -41,14 +53,55 @@ impl Plugin for CpuNoiseTerrainGenerationPlugin {
 pub struct WorldGenConfig {
     pub seed: u32,
     pub biome_registry: Arc<BiomeRegistry>,
+    pub caves: Arc<CaveGenerator>,
 }
 
-fn setup_terrain_generation(mut commands: Commands) {
-    let seed = 12345;
-    commands.insert_resource(WorldGenConfig {
-        seed,
-        biome_registry: Arc::new(BiomeRegistry::new(seed)),
-    });
+const WORLD_SEED: u32 = 12345;
+
+#[derive(Resource)]
+struct BiomeDefinitionsHandle(Handle<BiomeDefinitionsAsset>);
+
+fn setup_terrain_generation(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.insert_resource(BiomeDefinitionsHandle(asset_server.load(BIOME_DEFINITIONS_PATH)));
+}
+
+// バイオーム定義の読み込みが終わったら WorldGenConfig を作成する
+// それまでの間は地形生成を行わない
+fn init_world_gen_config(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    biome_handle: Res<BiomeDefinitionsHandle>,
+    biome_assets: Res<Assets<BiomeDefinitionsAsset>>,
+    voxel_handle: Option<Res<VoxelDefinitionsHandle>>,
+    voxel_registry: Res<VoxelRegistry>,
+    mut reported: Local<bool>,
+) {
+    // バイオームから独自のボクセルを参照できるように、ボクセル定義の読み込みを待つ
+    if voxel_handle.is_some_and(|handle| asset_server.load_state(&handle.0).is_loading()) {
+        return;
+    }
+    let Some(asset) = biome_assets.get(&biome_handle.0) else {
+        if asset_server.load_state(&biome_handle.0).is_failed() && !*reported {
+            error!("Failed to load {BIOME_DEFINITIONS_PATH}; terrain will not be generated");
+            *reported = true;
+        }
+        return;
+    };
+    match BiomeRegistry::from_asset(WORLD_SEED, asset, &voxel_registry) {
+        Ok(biome_registry) => {
+            info!("Loaded {} biomes from {BIOME_DEFINITIONS_PATH}", biome_registry.biomes.len());
+            commands.insert_resource(WorldGenConfig {
+                seed: WORLD_SEED,
+                biome_registry: Arc::new(biome_registry),
+                caves: Arc::new(CaveGenerator::new(WORLD_SEED)),
+            });
+        },
+        Err(err) if !*reported => {
+            error!("Invalid biome definitions in {BIOME_DEFINITIONS_PATH}: {err}");
+            *reported = true;
+        },
+        Err(_) => {},
+    }
 }
 
 
@@ -75,17 +128,22 @@ struct AltitudeTaskResult {
     chunk_xz: IVec2,
     altitude_map: Arc<[i32]>,
     biome_map: Arc<[u8]>,
+    padded_altitude_map: Arc<[i32]>,
 }
 
 #[derive(Debug)]
 struct BaseTerrainTaskResult {
     chunk_pos: IVec3,
     chunk_data: TerrainChunkData,
+    // リージョンファイルから読み込んだ場合はtrue
+    restored: bool,
 }
 
 #[derive(Debug)]
 struct FeaturesTaskResult {
     changes: Vec<(IVec3, Voxel)>,
+    // このタスクで新しく生成したチャンクごとのフィーチャー
+    generated: Vec<(IVec3, FeatureChanges)>,
 }
 
 const MAX_COMPUTE_TERRAIN_TASKS_PER_FRAME: usize = 10;
@@ -128,11 +186,14 @@ fn queue_altitude_tasks(
             let config = config.clone();
             let task = thread_pool.spawn(async move {
                 let (altitude_map, biome_map) = generation::generate_altitude_map(seed, chunk_xz, &config);
+                // 周囲の列は y 方向のどのチャンクでも同じなので、XZ 列ごとに一度だけ計算する
+                let padded_altitude_map = generation::padded_altitude_map(seed, chunk_xz, &altitude_map, &config, CAVE_ALTITUDE_MARGIN);
 
                 AltitudeTaskResult {
                     chunk_xz,
                     altitude_map: altitude_map.into(),
                     biome_map: biome_map.into(),
+                    padded_altitude_map: padded_altitude_map.into(),
                 }
             });
             commands.queue(move |world: &mut World| {
@@ -154,6 +215,7 @@ fn handle_altitude_tasks(
         if let Some(result) = check_ready(&mut task.0) {
             storage.altitude_maps.insert(result.chunk_xz, result.altitude_map);
             storage.biome_maps.insert(result.chunk_xz, result.biome_map);
+            storage.padded_altitude_maps.insert(result.chunk_xz, result.padded_altitude_map);
             commands.queue(move |world: &mut World| {
                 if let Ok(mut entity_world) = world.get_entity_mut(entity) {
                     entity_world.remove::<ComputingAltitude>();
@@ -170,9 +232,11 @@ fn queue_base_terrain_tasks(
     world_gen_config: Res<WorldGenConfig>,
     storage: Res<TerrainGenerationStorage>,
     render_distance_params: Res<RenderDistanceParams>,
+    region_store: Res<RegionStore>,
 ) {
     let thread_pool = AsyncComputeTaskPool::get();
     let config = world_gen_config.biome_registry.clone();
+    let caves = world_gen_config.caves.clone();
 
     for (entity, terrain_chunk) in target_chunks.iter() {
         let chunk_pos = terrain_chunk.position;
@@ -183,18 +247,30 @@ fn queue_base_terrain_tasks(
 
         let chunk_xz = chunk_pos.xz();
 
-        if let (Some(altitude_map), Some(biome_map)) = (
+        if let (Some(altitude_map), Some(biome_map), Some(padded_altitude_map)) = (
             storage.altitude_maps.get(&chunk_xz),
             storage.biome_maps.get(&chunk_xz),
+            storage.padded_altitude_maps.get(&chunk_xz),
         ) {
             let altitude_map = altitude_map.clone();
             let biome_map = biome_map.clone();
+            let padded_altitude_map = padded_altitude_map.clone();
             let config = config.clone();
+            let caves = caves.clone();
+            let region_store = region_store.clone();
 
             let task = thread_pool.spawn(async move {
-                let chunk_data = generation::generate_base_terrain(chunk_pos, &altitude_map, &biome_map, &config);
+                // 保存済みのチャンクがあればノイズから生成せずにそれを使う
+                match region_store.load_chunk(chunk_pos) {
+                    Ok(Some(chunk_data)) => {
+                        return BaseTerrainTaskResult { chunk_pos, chunk_data, restored: true };
+                    },
+                    Ok(None) => {},
+                    Err(err) => error!("Failed to load chunk {chunk_pos}: {err}"),
+                }
+                let chunk_data = generation::generate_base_terrain(chunk_pos, &altitude_map, &padded_altitude_map, &biome_map, &config, &caves);
 
-                BaseTerrainTaskResult { chunk_pos, chunk_data }
+                BaseTerrainTaskResult { chunk_pos, chunk_data, restored: false }
             });
             commands.queue(move |world: &mut World| {
                 if let Ok(mut entity_world) = world.get_entity_mut(entity) {
@@ -216,6 +292,11 @@ fn handle_base_terrain_tasks(
         if let Some(result) = check_ready(&mut task.0) {
             chunk_map.insert(result.chunk_data);
             storage.base_terrain_generated.insert(result.chunk_pos);
+            if result.restored {
+                storage.restored_from_disk.insert(result.chunk_pos);
+            } else {
+                storage.restored_from_disk.remove(&result.chunk_pos);
+            }
             commands.queue(move |world: &mut World| {
                 if let Ok(mut entity_world) = world.get_entity_mut(entity) {
                     entity_world.remove::<ComputingBaseTerrain>();
@@ -245,45 +326,60 @@ fn queue_feature_tasks(
         }
 
         let chunk_xz = chunk_pos.xz();
-        
-        // Check neighbors (3x3 area in XZ plane)
-        let mut all_neighbors_ready = true;
-        for dx in -1..=1 {
-            for dz in -1..=1 {
-                if dx == 0 && dz == 0 { continue; }
-                let neighbor_pos = chunk_pos + IVec3::new(dx, 0, dz);
-                if !storage.base_terrain_generated.contains(&neighbor_pos) {
-                    all_neighbors_ready = false;
-                    break;
-                }
-            }
-        }
 
-        if all_neighbors_ready {
-            let (Some(altitude_map), Some(biome_map)) = (
-                storage.altitude_maps.get(&chunk_xz),
-                storage.biome_maps.get(&chunk_xz),
-            ) else {
-                continue;
-            };
-
-            let config = config.clone();
-            let altitude_map = altitude_map.clone();
-            let biome_map = biome_map.clone();
+        // 周囲 3x3 の列の高度マップとバイオームマップが揃うまで待つ
+        let Some(columns) = (-1..=1)
+            .cartesian_product(-1..=1)
+            .map(|(dz, dx)| {
+                let xz = chunk_xz + IVec2::new(dx, dz);
+                Some((xz, storage.altitude_maps.get(&xz)?.clone(), storage.biome_maps.get(&xz)?.clone()))
+            })
+            .collect::<Option<Vec<_>>>()
+        else {
+            continue;
+        };
+
+        let config = config.clone();
+        // 保存済みのチャンクには、プレイヤーの編集を上書きしないようにフィーチャーを書き込まない
+        let restored = storage.restored_from_disk.contains(&chunk_pos);
+        // 周囲 3x3x3 のチャンクのうち、フィーチャーを生成済みのものはそれを使う
+        let features = &storage.features;
+        let sources = columns
+            .into_iter()
+            .flat_map(|(xz, altitude_map, biome_map)| (-1..=1).map(move |dy| {
+                let source = IVec3::new(xz.x, chunk_pos.y + dy, xz.y);
+                (source, altitude_map.clone(), biome_map.clone(), features.get(&source).cloned())
+            }))
+            .collect::<Vec<_>>();
+
+        let task = thread_pool.spawn(async move {
+            if restored {
+                return FeaturesTaskResult { changes: Vec::new(), generated: Vec::new() };
+            }
+            // 周囲 3x3x3 のチャンクのフィーチャーを決まった順に集め、このチャンクに入る分だけを使う
+            // 書き込み先は常にこのチャンク自身なので、チャンクの生成順や再読み込みに関係なく同じ地形になる
+            let mut changes = Vec::new();
+            let mut generated = Vec::new();
+            for (source, altitude_map, biome_map, cached) in sources {
+                let source_changes = cached.unwrap_or_else(|| {
+                    let source_changes: FeatureChanges = generation::generate_features(source, seed, &altitude_map, &biome_map, &config).into();
+                    generated.push((source, source_changes.clone()));
+                    source_changes
+                });
+                changes.extend(source_changes.iter().copied().filter(|(world_pos, _)| {
+                    world_pos.div_euclid(IVec3::splat(TERRAIN_CHUNK_SIZE as i32)) == chunk_pos
+                }));
+            }
 
-            let task = thread_pool.spawn(async move {
-                let changes = generation::generate_features(chunk_pos, seed, &altitude_map, &biome_map, &config);
+            FeaturesTaskResult { changes, generated }
+        });
 
-                FeaturesTaskResult { changes }
-            });
-            
-            commands.queue(move |world: &mut World| {
-                if let Ok(mut entity_world) = world.get_entity_mut(entity) {
-                    entity_world.remove::<WaitForNeighbors>();
-                    entity_world.insert(ComputingFeatures(task));
-                }
-            });
-        }
+        commands.queue(move |world: &mut World| {
+            if let Ok(mut entity_world) = world.get_entity_mut(entity) {
+                entity_world.remove::<WaitForNeighbors>();
+                entity_world.insert(ComputingFeatures(task));
+            }
+        });
     }
 }
 
@@ -293,11 +389,12 @@ fn handle_feature_tasks(
     mut event_writer: MessageWriter<ChunkGeneratedEvent>,
     mut storage: ResMut<TerrainGenerationStorage>,
     chunk_entities: Res<ChunkEntities>,
-    mut chunk_map: ResMut<ChunkMap>,
+    mut voxel_world: VoxelWorld,
 ) {
     for (entity, mut task, terrain_chunk) in &mut tasks {
         if let Some(result) = check_ready(&mut task.0) {
-            chunk_map.set_bulk(result.changes);
+            storage.features.extend(result.generated);
+            voxel_world.apply_generated_features(result.changes);
 
             commands.queue(move |world: &mut World| {
                 if let Ok(mut entity_world) = world.get_entity_mut(entity) {
@@ -314,3 +411,11 @@ fn handle_feature_tasks(
         }
     }
 }
+
+// 遠ざかったチャンクのフィーチャーを破棄する
+fn drop_unloaded_features(
+    mut storage: ResMut<TerrainGenerationStorage>,
+    render_distance_params: Res<RenderDistanceParams>,
+) {
+    storage.features.retain(|chunk_pos, _| !should_unload_chunk(*chunk_pos, &render_distance_params));
+}
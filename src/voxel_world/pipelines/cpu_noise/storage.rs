@@ -1,10 +1,21 @@
 use std::sync::Arc;
 use bevy::{platform::collections::{HashMap, HashSet}, prelude::*};
+use crate::voxel_world::core::voxel::Voxel;
+
+// 1つのチャンクから生成したフィーチャーが書き込むボクセル
+pub type FeatureChanges = Arc<[(IVec3, Voxel)]>;
 
 #[derive(Debug, Default, Resource)]
 pub struct TerrainGenerationStorage {
     pub altitude_maps: HashMap<IVec2, Arc<[i32]>>,
     pub biome_maps: HashMap<IVec2, Arc<[u8]>>,
+    // 周囲に CAVE_ALTITUDE_MARGIN 列ずつ広げた高度マップ (洞窟の生成に使う)
+    pub padded_altitude_maps: HashMap<IVec2, Arc<[i32]>>,
     pub base_terrain_generated: HashSet<IVec3>,
     pub fully_generated: HashSet<IVec3>,
-}
\ No newline at end of file
+    // リージョンファイルから復元されたチャンク (フィーチャーを再生成しない)
+    pub restored_from_disk: HashSet<IVec3>,
+    // チャンクごとに生成したフィーチャー (隣のチャンクにはみ出す分も含む)
+    // 周囲 3x3x3 のチャンクから参照されるので、チャンクが遠ざかるまで残しておく
+    pub features: HashMap<IVec3, FeatureChanges>,
+}
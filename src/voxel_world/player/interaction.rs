@@ -0,0 +1,177 @@
+use bevy::{input::mouse::{AccumulatedMouseScroll, MouseScrollUnit}, prelude::*, window::{CursorGrabMode, CursorOptions, PrimaryWindow}};
+
+use crate::voxel_world::{
+    core::{block_state::{VoxelAxis, VoxelFacing}, coordinates::VOXEL_SIZE, registry::VoxelRegistry, voxel::{Voxel, VoxelMaterial}},
+    editing::VoxelWorld,
+    raycast::{raycast_voxels, VoxelRayHit, VoxelRaycastFilter},
+    storage::ChunkMap,
+};
+use super::{physics::Aabb, Player, PlayerSettings};
+
+// プレイヤーが見ているボクセル (届く範囲にない場合はNone)
+#[derive(Resource, Debug, Default)]
+pub struct TargetedVoxel(pub Option<VoxelRayHit>);
+
+// 右クリックで設置するボクセルの候補と選択中のインデックス
+// 候補は VoxelRegistry が更新されるたびに作り直される
+#[derive(Resource, Debug, Default)]
+pub struct VoxelSelection {
+    pub candidates: Vec<Voxel>,
+    pub index: usize,
+}
+
+impl VoxelSelection {
+    pub fn selected(&self) -> Option<Voxel> {
+        self.candidates.get(self.index).copied()
+    }
+}
+
+#[derive(Component)]
+pub struct SelectedVoxelText;
+
+const TARGET_FILTER: VoxelRaycastFilter = VoxelRaycastFilter {
+    hit_water: false,
+    hit_cross: true,
+    stop_at_unloaded: true,
+};
+
+pub fn setup_selection_ui(mut commands: Commands) {
+    commands.spawn((
+        Text::new(""),
+        TextFont {
+            font_size: 24.0,
+            ..default()
+        },
+        Node {
+            position_type: PositionType::Absolute,
+            bottom: Val::Px(12.0),
+            left: Val::Px(12.0),
+            ..default()
+        },
+        SelectedVoxelText,
+    ));
+}
+
+pub fn update_targeted_voxel(
+    player_transform: Single<&Transform, With<Player>>,
+    chunk_map: Res<ChunkMap>,
+    registry: Res<VoxelRegistry>,
+    settings: Res<PlayerSettings>,
+    mut targeted: ResMut<TargetedVoxel>,
+) {
+    let origin = player_transform.translation;
+    let direction = player_transform.forward().as_vec3();
+    targeted.0 = raycast_voxels(&chunk_map, &registry, origin, direction, settings.reach, &TARGET_FILTER);
+}
+
+pub fn draw_target_outline(
+    mut gizmos: Gizmos,
+    targeted: Res<TargetedVoxel>,
+) {
+    let Some(hit) = targeted.0 else {
+        return;
+    };
+    let center = (hit.position.as_vec3() + Vec3::splat(0.5)) * VOXEL_SIZE;
+    // Z-fightingを避けるために少しだけ大きく描く
+    let transform = Transform::from_translation(center).with_scale(Vec3::splat(VOXEL_SIZE * 1.005));
+    gizmos.cuboid(transform, Color::srgb(0.05, 0.05, 0.05));
+}
+
+pub fn update_selection_candidates(
+    registry: Res<VoxelRegistry>,
+    mut selection: ResMut<VoxelSelection>,
+) {
+    let selected = selection.selected();
+    // 描画されないボクセル (EMPTYなど) は設置できない
+    selection.candidates = registry
+        .definitions()
+        .iter()
+        .filter(|def| !matches!(def.material, VoxelMaterial::None))
+        .map(|def| Voxel::new(def.id))
+        .collect();
+    // 選択中のボクセルが残っていればそれを選んだままにする
+    selection.index = selected
+        .and_then(|voxel| selection.candidates.iter().position(|&candidate| candidate == voxel))
+        .unwrap_or(0);
+}
+
+pub fn select_voxel(
+    accumulated_mouse_scroll: Res<AccumulatedMouseScroll>,
+    primary_cursor_options: Single<&CursorOptions, With<PrimaryWindow>>,
+    mut selection: ResMut<VoxelSelection>,
+) {
+    if primary_cursor_options.grab_mode == CursorGrabMode::None || selection.candidates.is_empty() {
+        return;
+    }
+    let delta = match accumulated_mouse_scroll.unit {
+        MouseScrollUnit::Line => accumulated_mouse_scroll.delta.y,
+        MouseScrollUnit::Pixel => accumulated_mouse_scroll.delta.y / 40.0,
+    };
+    let steps = delta.round() as i32;
+    if steps == 0 {
+        return;
+    }
+    // ホイールを下に回すと次の候補へ進む
+    let len = selection.candidates.len() as i32;
+    selection.index = (selection.index as i32 - steps).rem_euclid(len) as usize;
+}
+
+pub fn update_selection_text(
+    selection: Res<VoxelSelection>,
+    registry: Res<VoxelRegistry>,
+    mut text: Single<&mut Text, With<SelectedVoxelText>>,
+) {
+    if !selection.is_changed() && !registry.is_changed() {
+        return;
+    }
+    text.0 = match selection.selected() {
+        Some(voxel) => format!("Block: {}", registry.name(voxel)),
+        None => String::new(),
+    };
+}
+
+pub fn break_and_place_voxel(
+    mouse_buttons: Res<ButtonInput<MouseButton>>,
+    primary_cursor_options: Single<&CursorOptions, With<PrimaryWindow>>,
+    player_transform: Single<&Transform, With<Player>>,
+    targeted: Res<TargetedVoxel>,
+    selection: Res<VoxelSelection>,
+    registry: Res<VoxelRegistry>,
+    mut voxel_world: VoxelWorld,
+) {
+    // カーソルが解放されている場合はUI操作とみなして何もしない
+    if primary_cursor_options.grab_mode == CursorGrabMode::None {
+        return;
+    }
+    let Some(hit) = targeted.0 else {
+        return;
+    };
+
+    if mouse_buttons.just_pressed(MouseButton::Left) {
+        voxel_world.set_voxel(hit.position, Voxel::EMPTY);
+    } else if mouse_buttons.just_pressed(MouseButton::Right) {
+        let Some(voxel) = selection.selected() else {
+            return;
+        };
+        // 始点がボクセル内部にある場合は設置する面がない
+        if hit.normal == IVec3::ZERO {
+            return;
+        }
+        let place_pos = hit.adjacent();
+        // プレイヤーが埋まってしまう位置には通り抜けられないボクセルを置かない
+        if registry.is_solid(voxel) && Aabb::from_eye(player_transform.translation).intersects_voxel(place_pos) {
+            return;
+        }
+        // 空気や水など、通り抜けられるボクセルだけを置き換える
+        let replaceable = voxel_world
+            .get_voxel(place_pos)
+            .is_some_and(|current| current.is(Voxel::EMPTY) || current.is(Voxel::WATER));
+        if replaceable {
+            // 向きを持つボクセルはクリックした面に合わせて置く
+            let voxel = voxel
+                .with_axis(VoxelAxis::from_direction(hit.normal))
+                .with_facing(VoxelFacing::from_direction(hit.normal));
+            voxel_world.set_voxel(place_pos, voxel);
+        }
+    }
+}
@@ -1,8 +1,15 @@
+pub mod interaction;
+pub mod physics;
+pub mod underwater;
+
 use std::f32::consts::FRAC_PI_2;
 
 use bevy::{core_pipeline::prepass::DepthPrepass, input::mouse::AccumulatedMouseMotion, pbr::Atmosphere, prelude::*, window::{CursorGrabMode, CursorOptions, PrimaryWindow}};
 
-use crate::voxel_world::core::{RenderDistanceParams, coordinates::TERRAIN_CHUNK_LENGTH};
+use crate::voxel_world::{core::{RenderDistanceParams, VoxelRegistry, coordinates::TERRAIN_CHUNK_LENGTH}, storage::ChunkMap};
+use interaction::*;
+use physics::{step_walking, Collider, MovementMode, PlayerBody, WalkInput};
+use underwater::*;
 
 pub struct VoxelPlayerPlugin;
 
@@ -10,12 +17,30 @@ impl Plugin for VoxelPlayerPlugin {
     fn build(&self, app: &mut App) {
         app
             .insert_resource(PlayerSettings::default())
-            .add_systems(Startup, setup_player)
+            .insert_resource(TargetedVoxel::default())
+            .insert_resource(VoxelSelection::default())
+            .insert_resource(Submerged::default())
+            .add_systems(Startup, (setup_player, setup_selection_ui))
             .add_systems(PreUpdate, update_player_chunk)
             .add_systems(Update, (
                 player_look,
+                toggle_movement_mode.before(player_move),
                 player_move,
                 toggle_grab_cursor,
+                (
+                    update_submerged,
+                    apply_underwater_fog.run_if(resource_changed::<Submerged>),
+                ).chain().after(player_move),
+                (
+                    update_targeted_voxel,
+                    break_and_place_voxel,
+                    draw_target_outline,
+                ).chain().after(player_move).after(player_look),
+                (
+                    update_selection_candidates.run_if(resource_changed::<VoxelRegistry>),
+                    select_voxel,
+                    update_selection_text,
+                ).chain(),
             ));
     }
 }
@@ -24,18 +49,10 @@ fn setup_player(mut commands: Commands) {
     commands.spawn((
         Camera3d::default(),
         Player,
+        PlayerBody::default(),
         // 水面のレンダリングなどのためにDepthPrepassを有効化
         DepthPrepass,
-        DistanceFog {
-            color: Color::srgba(0.35, 0.48, 0.66, 1.0),
-            directional_light_color: Color::srgba(1.0, 0.95, 0.85, 0.5),
-            directional_light_exponent: 30.0,
-            falloff: FogFalloff::from_visibility_colors(
-                12000.0, // distance in world units up to which objects retain visibility (>= 5% contrast)
-                Color::srgb(0.35, 0.5, 0.66), // atmospheric extinction color (after light is lost due to absorption by atmospheric particles)
-                Color::srgb(0.8, 0.844, 1.0), // atmospheric inscattering color (light gained due to scattering from the sun)
-            ),
-        },
+        default_fog(),
         Atmosphere::default(),
         Transform::from_xyz(0.0, 150.0, 0.0).looking_at(Vec3::new(0.0, 150.0, 10.0), Vec3::Y),
     ));
@@ -47,9 +64,19 @@ pub struct Player;
 
 #[derive(Resource)]
 pub struct PlayerSettings {
+    // 飛行時の速度
     pub speed: f32,
     pub run_speed: f32,
+    // 歩行時の速度
+    pub walk_speed: f32,
+    pub sprint_speed: f32,
+    pub jump_speed: f32,
+    pub gravity: f32,
+    // 水中での速度
+    pub swim_speed: f32,
     pub sensitivity: f32,
+    // ブロックの破壊・設置ができる距離
+    pub reach: f32,
 }
 
 impl Default for PlayerSettings {
@@ -57,12 +84,18 @@ impl Default for PlayerSettings {
         Self {
             speed: 20.0,
             run_speed: 100.0,
+            walk_speed: 4.5,
+            sprint_speed: 7.0,
+            // 1.25ブロックほどの高さまで跳べる
+            jump_speed: 8.4,
+            gravity: 28.0,
+            swim_speed: 3.0,
             sensitivity: 0.002,
+            reach: 8.0,
         }
     }
 }
 
-
 pub fn update_player_chunk(
     player_transform: Single<&Transform, With<Player>>,
     mut render_distance_params: ResMut<RenderDistanceParams>,
@@ -104,17 +137,38 @@ pub fn player_look(
     transform.rotation = Quat::from_euler(EulerRot::YXZ, yaw, pitch, roll);
 }
 
+// Fキーで歩行と飛行を切り替える
+fn toggle_movement_mode(
+    mut body: Single<&mut PlayerBody, With<Player>>,
+    keys: Res<ButtonInput<KeyCode>>,
+) {
+    if !keys.just_pressed(KeyCode::KeyF) {
+        return;
+    }
+    body.mode = match body.mode {
+        MovementMode::Walk => MovementMode::Fly,
+        MovementMode::Fly => MovementMode::Walk,
+    };
+    body.velocity = Vec3::ZERO;
+    body.on_ground = false;
+}
+
 pub fn player_move(
-    mut transform: Single<&mut Transform, With<Player>>,
+    player: Single<(&mut Transform, &mut PlayerBody), With<Player>>,
     keys: Res<ButtonInput<KeyCode>>,
     primary_cursor_options: Single<&CursorOptions, With<PrimaryWindow>>,
     time: Res<Time>,
     settings: Res<PlayerSettings>,
+    chunk_map: Res<ChunkMap>,
+    registry: Res<VoxelRegistry>,
 ) {
-    // カーソルが解放されている場合は移動しない
-    if primary_cursor_options.grab_mode == CursorGrabMode::None {
+    let (mut transform, mut body) = player.into_inner();
+    // カーソルが解放されている場合は操作を受け付けない (歩行中は落下だけ続ける)
+    let grabbed = primary_cursor_options.grab_mode != CursorGrabMode::None;
+    if !grabbed && body.mode == MovementMode::Fly {
         return;
     }
+    let pressed = |key: KeyCode| grabbed && keys.pressed(key);
     let mut velocity = Vec3::ZERO;
     
     // 水平移動のための前方・右方ベクトル（Y成分を無視）
@@ -125,34 +179,55 @@ pub fn player_move(
     let flat_right = Vec3::new(right.x, 0.0, right.z).normalize_or_zero();
     let up = Vec3::Y;
 
-    if keys.pressed(KeyCode::KeyW) {
+    if pressed(KeyCode::KeyW) {
         velocity += flat_forward;
     }
-    if keys.pressed(KeyCode::KeyS) {
+    if pressed(KeyCode::KeyS) {
         velocity -= flat_forward;
     }
-    if keys.pressed(KeyCode::KeyA) {
+    if pressed(KeyCode::KeyA) {
         velocity -= flat_right;
     }
-    if keys.pressed(KeyCode::KeyD) {
+    if pressed(KeyCode::KeyD) {
         velocity += flat_right;
     }
-    if keys.pressed(KeyCode::Space) {
-        velocity += up;
-    }
-    if keys.pressed(KeyCode::ShiftLeft) {
-        velocity -= up;
-    }
 
-    velocity = velocity.normalize_or_zero();
+    match body.mode {
+        MovementMode::Fly => {
+            if pressed(KeyCode::Space) {
+                velocity += up;
+            }
+            if pressed(KeyCode::ShiftLeft) {
+                velocity -= up;
+            }
 
-    let speed = if keys.pressed(KeyCode::ControlLeft) {
-        settings.run_speed
-    } else {
-        settings.speed
-    };
+            velocity = velocity.normalize_or_zero();
 
-    transform.translation = transform.translation + velocity * speed * time.delta_secs();
+            let speed = if pressed(KeyCode::ControlLeft) {
+                settings.run_speed
+            } else {
+                settings.speed
+            };
+
+            transform.translation += velocity * speed * time.delta_secs();
+        },
+        MovementMode::Walk => {
+            let speed = if body.in_water {
+                settings.swim_speed
+            } else if pressed(KeyCode::ControlLeft) {
+                settings.sprint_speed
+            } else {
+                settings.walk_speed
+            };
+            let input = WalkInput {
+                wish_velocity: velocity.normalize_or_zero() * speed,
+                jump: pressed(KeyCode::Space),
+                descend: pressed(KeyCode::ShiftLeft),
+            };
+            let collider = Collider { chunk_map: &chunk_map, registry: &registry };
+            step_walking(&mut body, &mut transform.translation, &collider, input, &settings, time.delta_secs());
+        },
+    }
 }
 
 fn toggle_grab_cursor(
@@ -172,4 +247,4 @@ fn toggle_grab_cursor(
             primary_cursor_options.visible = true;
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,380 @@
+use bevy::prelude::*;
+
+use crate::voxel_world::{
+    core::{coordinates::VOXEL_SIZE, registry::VoxelRegistry, voxel::Voxel},
+    storage::ChunkMap,
+};
+use super::PlayerSettings;
+
+// プレイヤーの当たり判定の大きさ (ボクセル単位)
+const PLAYER_WIDTH: f32 = 0.6;
+const PLAYER_HEIGHT: f32 = 1.8;
+// 足元から視点 (Transformの位置) までの高さ
+const EYE_HEIGHT: f32 = 1.62;
+// 歩いているときに自動で登れる段差の高さ
+const STEP_HEIGHT: f32 = 1.0;
+// 落下速度の上限 (ボクセル/秒)
+const MAX_FALL_SPEED: f32 = 60.0;
+// 水中では浮力によって重力がこの割合まで弱まる
+const WATER_GRAVITY_SCALE: f32 = 0.2;
+// 水の抵抗で縦方向の速度が1秒あたりに失われる割合
+const WATER_DRAG: f32 = 4.0;
+// 水中で沈む速度の上限 (ボクセル/秒)
+const MAX_SINK_SPEED: f32 = 2.5;
+// 足元からこの深さまでに水があれば泳いでいるとみなす (水面に浮いている間も泳げるように)
+const WATER_SURFACE_MARGIN: f32 = 0.3;
+// フレームが重いときに1回で進める時間の上限
+const MAX_STEP_SECS: f32 = 0.05;
+// 面にぴったり接している状態を衝突とみなさないための余裕
+const EPSILON: f32 = 1e-4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MovementMode {
+    // 重力と当たり判定のある移動
+    #[default]
+    Walk,
+    // 当たり判定のない自由移動
+    Fly,
+}
+
+#[derive(Component, Debug, Default)]
+pub struct PlayerBody {
+    pub mode: MovementMode,
+    pub velocity: Vec3,
+    pub on_ground: bool,
+    // 体の一部が水に浸かっている (泳いでいる) か
+    pub in_water: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    // 視点の位置からプレイヤーの当たり判定を作る
+    pub fn from_eye(eye: Vec3) -> Self {
+        let half_width = PLAYER_WIDTH * 0.5 * VOXEL_SIZE;
+        Self {
+            min: eye - Vec3::new(half_width, EYE_HEIGHT * VOXEL_SIZE, half_width),
+            max: eye + Vec3::new(half_width, (PLAYER_HEIGHT - EYE_HEIGHT) * VOXEL_SIZE, half_width),
+        }
+    }
+
+    fn translated(&self, offset: Vec3) -> Self {
+        Self { min: self.min + offset, max: self.max + offset }
+    }
+
+    // 重なっているボクセルの範囲 (両端を含む)
+    fn voxel_range(&self) -> (IVec3, IVec3) {
+        (
+            ((self.min + EPSILON) / VOXEL_SIZE).floor().as_ivec3(),
+            ((self.max - EPSILON) / VOXEL_SIZE).floor().as_ivec3(),
+        )
+    }
+
+    pub fn intersects_voxel(&self, voxel_pos: IVec3) -> bool {
+        let (min, max) = self.voxel_range();
+        voxel_pos.cmpge(min).all() && voxel_pos.cmple(max).all()
+    }
+}
+
+// 当たり判定の対象になるボクセルを調べるためのもの
+pub struct Collider<'a> {
+    pub chunk_map: &'a ChunkMap,
+    pub registry: &'a VoxelRegistry,
+}
+
+impl Collider<'_> {
+    // 生成されていないチャンクは壁として扱い、プレイヤーが落ちないようにする
+    pub fn is_solid(&self, voxel_pos: IVec3) -> bool {
+        self.chunk_map
+            .get_at(voxel_pos)
+            .is_none_or(|voxel| self.registry.is_solid(voxel))
+    }
+
+    pub fn is_loaded(&self, voxel_pos: IVec3) -> bool {
+        self.chunk_map.get_at(voxel_pos).is_some()
+    }
+
+    fn overlaps_solid(&self, aabb: &Aabb) -> bool {
+        let (min, max) = aabb.voxel_range();
+        self.any_solid(min, max)
+    }
+
+    fn overlaps_water(&self, aabb: &Aabb) -> bool {
+        let (min, max) = aabb.voxel_range();
+        for z in min.z..=max.z {
+            for y in min.y..=max.y {
+                for x in min.x..=max.x {
+                    if self.chunk_map.get_at(IVec3::new(x, y, z)).is_some_and(|voxel| voxel.is(Voxel::WATER)) {
+                        return true;
+                    }
+                }
+            }
+        }
+        false
+    }
+
+    // axis方向にdeltaだけ動かしたときに、ぶつからずに動ける距離を返す
+    fn sweep_axis(&self, aabb: &Aabb, axis: usize, delta: f32) -> f32 {
+        if delta == 0.0 {
+            return 0.0;
+        }
+        let (mut min, mut max) = aabb.voxel_range();
+        let layers: Box<dyn Iterator<Item = i32>> = if delta > 0.0 {
+            let first = ((aabb.max[axis] - EPSILON) / VOXEL_SIZE).ceil() as i32;
+            let last = ((aabb.max[axis] + delta) / VOXEL_SIZE).ceil() as i32 - 1;
+            Box::new(first..=last)
+        } else {
+            let first = ((aabb.min[axis] + EPSILON) / VOXEL_SIZE).floor() as i32 - 1;
+            let last = ((aabb.min[axis] + delta) / VOXEL_SIZE).floor() as i32;
+            Box::new((last..=first).rev())
+        };
+        // 近い層から順に調べ、最初にぶつかった層の面で止める
+        for layer in layers {
+            min[axis] = layer;
+            max[axis] = layer;
+            if self.any_solid(min, max) {
+                return if delta > 0.0 {
+                    (layer as f32 * VOXEL_SIZE - aabb.max[axis]).clamp(0.0, delta)
+                } else {
+                    ((layer + 1) as f32 * VOXEL_SIZE - aabb.min[axis]).clamp(delta, 0.0)
+                };
+            }
+        }
+        delta
+    }
+
+    fn any_solid(&self, min: IVec3, max: IVec3) -> bool {
+        for z in min.z..=max.z {
+            for y in min.y..=max.y {
+                for x in min.x..=max.x {
+                    if self.is_solid(IVec3::new(x, y, z)) {
+                        return true;
+                    }
+                }
+            }
+        }
+        false
+    }
+
+    // 軸ごとに順番に動かし、実際に動いた量を返す
+    fn move_aabb(&self, aabb: &Aabb, delta: Vec3) -> Vec3 {
+        let mut moved = Vec3::ZERO;
+        let mut current = *aabb;
+        // 先に縦方向を解決して、接地しているときに床の角に引っかからないようにする
+        for axis in [1, 0, 2] {
+            let allowed = self.sweep_axis(&current, axis, delta[axis]);
+            let mut offset = Vec3::ZERO;
+            offset[axis] = allowed;
+            current = current.translated(offset);
+            moved[axis] = allowed;
+        }
+        moved
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WalkInput {
+    // 水平方向の目標速度
+    pub wish_velocity: Vec3,
+    // 地上ではジャンプ、水中では浮上
+    pub jump: bool,
+    // 水中で潜る
+    pub descend: bool,
+}
+
+// 歩行時の1フレーム分の移動
+pub fn step_walking(
+    body: &mut PlayerBody,
+    eye: &mut Vec3,
+    collider: &Collider,
+    input: WalkInput,
+    settings: &PlayerSettings,
+    delta_secs: f32,
+) {
+    let aabb = Aabb::from_eye(*eye);
+    // 足元のチャンクがまだ生成されていない間は空中で待つ
+    let feet = Vec3::new(eye.x, aabb.min.y - EPSILON, eye.z);
+    if !collider.is_loaded((feet / VOXEL_SIZE).floor().as_ivec3()) {
+        body.velocity = Vec3::ZERO;
+        return;
+    }
+    // 地形に埋まっている場合 (生成直後など) は上に押し出す
+    if collider.overlaps_solid(&aabb) {
+        eye.y += VOXEL_SIZE;
+        body.velocity = Vec3::ZERO;
+        return;
+    }
+
+    let dt = delta_secs.min(MAX_STEP_SECS);
+    let wading = Aabb { min: aabb.min - Vec3::Y * WATER_SURFACE_MARGIN * VOXEL_SIZE, max: aabb.max };
+    body.in_water = collider.overlaps_water(&wading);
+    body.velocity.x = input.wish_velocity.x;
+    body.velocity.z = input.wish_velocity.z;
+    if body.in_water {
+        // 浮力で重力が弱まり、水の抵抗で縦方向の速度が落ちる
+        let drag = (1.0 - WATER_DRAG * dt).max(0.0);
+        body.velocity.y = body.velocity.y * drag - settings.gravity * WATER_GRAVITY_SCALE * dt;
+        if input.jump {
+            body.velocity.y = body.velocity.y.max(settings.swim_speed);
+        } else if input.descend {
+            body.velocity.y = body.velocity.y.min(-settings.swim_speed);
+        }
+        body.velocity.y = body.velocity.y.max(-MAX_SINK_SPEED.max(settings.swim_speed));
+    } else {
+        if body.on_ground && input.jump {
+            body.velocity.y = settings.jump_speed;
+        }
+        body.velocity.y = (body.velocity.y - settings.gravity * dt).max(-MAX_FALL_SPEED);
+    }
+
+    let delta = body.velocity * dt;
+    let mut moved = collider.move_aabb(&aabb, delta);
+
+    // 水平方向にぶつかった場合は、段差を登れるか試す (泳いでいるときは岸に上がれる)
+    let blocked = (moved.x - delta.x).abs() > EPSILON || (moved.z - delta.z).abs() > EPSILON;
+    if (body.on_ground || body.in_water) && blocked {
+        let rise = collider.sweep_axis(&aabb, 1, STEP_HEIGHT * VOXEL_SIZE);
+        let raised = aabb.translated(Vec3::new(0.0, rise, 0.0));
+        let horizontal = collider.move_aabb(&raised, Vec3::new(delta.x, 0.0, delta.z));
+        let stepped = raised.translated(horizontal);
+        let drop = collider.sweep_axis(&stepped, 1, -rise);
+        let step_moved = Vec3::new(horizontal.x, rise + drop, horizontal.z);
+        if step_moved.xz().length_squared() > moved.xz().length_squared() + EPSILON {
+            moved = step_moved;
+        }
+    }
+
+    let landed = delta.y < 0.0 && moved.y > delta.y + EPSILON;
+    let hit_ceiling = delta.y > 0.0 && moved.y < delta.y - EPSILON;
+    if landed || hit_ceiling {
+        body.velocity.y = 0.0;
+    }
+    // 接地判定は真下に少しだけ動けるかで調べる
+    let settled = aabb.translated(moved);
+    body.on_ground = collider.sweep_axis(&settled, 1, -0.01 * VOXEL_SIZE) > -0.01 * VOXEL_SIZE + EPSILON;
+
+    *eye += moved;
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::voxel_world::core::{coordinates::TERRAIN_CHUNK_SIZE, terrain_chunk::TerrainChunkData};
+
+    const SIZE: f32 = TERRAIN_CHUNK_SIZE as f32;
+
+    // y = 0 に石の床を敷いたチャンクに、追加の石を置く
+    fn floor_with(stones: &[IVec3]) -> ChunkMap {
+        let mut chunk_map = ChunkMap::default();
+        chunk_map.insert(TerrainChunkData::new_from_fn_local(IVec3::ZERO, |local| {
+            if local.y == 0 { Voxel::STONE } else { Voxel::EMPTY }
+        }));
+        for &stone in stones {
+            chunk_map.set_at(stone, Voxel::STONE).unwrap();
+        }
+        chunk_map
+    }
+
+    // 足元の中心から視点の位置を求める
+    fn eye_at(feet: Vec3) -> Vec3 {
+        (feet + Vec3::Y * EYE_HEIGHT) * VOXEL_SIZE
+    }
+
+    fn feet(eye: Vec3) -> Vec3 {
+        eye / VOXEL_SIZE - Vec3::Y * EYE_HEIGHT
+    }
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!((actual - expected).abs() < 1e-3, "expected {expected}, got {actual}");
+    }
+
+    #[test]
+    fn sweep_stops_at_the_first_solid_layer() {
+        let chunk_map = floor_with(&[IVec3::new(10, 4, 10), IVec3::new(12, 1, 10)]);
+        let registry = VoxelRegistry::default();
+        let collider = Collider { chunk_map: &chunk_map, registry: &registry };
+
+        let standing = Aabb::from_eye(eye_at(Vec3::new(10.5, 1.0, 10.5)));
+        assert_close(collider.sweep_axis(&standing, 1, -0.5 * VOXEL_SIZE), 0.0);
+        let hovering = Aabb::from_eye(eye_at(Vec3::new(10.5, 1.3, 10.5)));
+        assert_close(collider.sweep_axis(&hovering, 1, -VOXEL_SIZE), -0.3 * VOXEL_SIZE);
+        // 頭 (1.0 + PLAYER_HEIGHT) から天井 (y = 4) まで
+        assert_close(collider.sweep_axis(&standing, 1, 2.0 * VOXEL_SIZE), (3.0 - PLAYER_HEIGHT) * VOXEL_SIZE);
+        // 体の端 (10.5 + PLAYER_WIDTH / 2) から壁 (x = 12) まで
+        assert_close(collider.sweep_axis(&standing, 0, 3.0 * VOXEL_SIZE), (1.5 - PLAYER_WIDTH * 0.5) * VOXEL_SIZE);
+        // 遮るものがなければそのまま動ける
+        assert_close(collider.sweep_axis(&standing, 2, -3.0 * VOXEL_SIZE), -3.0 * VOXEL_SIZE);
+    }
+
+    #[test]
+    fn unloaded_chunks_count_as_solid() {
+        let chunk_map = floor_with(&[]);
+        let registry = VoxelRegistry::default();
+        let collider = Collider { chunk_map: &chunk_map, registry: &registry };
+
+        assert!(collider.is_solid(IVec3::new(-1, 5, 5)));
+        assert!(!collider.is_loaded(IVec3::new(-1, 5, 5)));
+        let near_edge = Aabb::from_eye(eye_at(Vec3::new(SIZE - 0.5, 1.0, 10.5)));
+        assert_close(collider.sweep_axis(&near_edge, 0, 2.0 * VOXEL_SIZE), (0.5 - PLAYER_WIDTH * 0.5) * VOXEL_SIZE);
+    }
+
+    // 一定の入力で frames フレーム歩く
+    fn walk(chunk_map: &ChunkMap, eye: &mut Vec3, body: &mut PlayerBody, wish_velocity: Vec3, frames: usize) {
+        let registry = VoxelRegistry::default();
+        let collider = Collider { chunk_map, registry: &registry };
+        let settings = PlayerSettings::default();
+        let input = WalkInput { wish_velocity, ..default() };
+        for _ in 0..frames {
+            step_walking(body, eye, &collider, input, &settings, 1.0 / 60.0);
+        }
+    }
+
+    #[test]
+    fn falling_player_lands_on_the_floor() {
+        let chunk_map = floor_with(&[]);
+        let mut eye = eye_at(Vec3::new(10.5, 5.0, 10.5));
+        let mut body = PlayerBody::default();
+        walk(&chunk_map, &mut eye, &mut body, Vec3::ZERO, 120);
+
+        assert_close(feet(eye).y, 1.0);
+        assert!(body.on_ground);
+        assert_eq!(body.velocity.y, 0.0);
+    }
+
+    #[test]
+    fn walking_climbs_one_block_steps_but_not_walls() {
+        // x = 12 から先は1段高い台
+        let step: Vec<IVec3> = (12..32).flat_map(|x| (0..32).map(move |z| IVec3::new(x, 1, z))).collect();
+        let chunk_map = floor_with(&step);
+        let mut eye = eye_at(Vec3::new(10.5, 1.0, 10.5));
+        let mut body = PlayerBody { on_ground: true, ..default() };
+        walk(&chunk_map, &mut eye, &mut body, Vec3::X * 4.0 * VOXEL_SIZE, 60);
+        assert_close(feet(eye).y, 2.0);
+        assert!(feet(eye).x > 12.0);
+
+        let wall: Vec<IVec3> = (0..32).flat_map(|z| [IVec3::new(12, 1, z), IVec3::new(12, 2, z)]).collect();
+        let chunk_map = floor_with(&wall);
+        let mut eye = eye_at(Vec3::new(10.5, 1.0, 10.5));
+        let mut body = PlayerBody { on_ground: true, ..default() };
+        walk(&chunk_map, &mut eye, &mut body, Vec3::X * 4.0 * VOXEL_SIZE, 60);
+        assert_close(feet(eye).y, 1.0);
+        assert_close(feet(eye).x, 12.0 - PLAYER_WIDTH * 0.5);
+    }
+
+    #[test]
+    fn player_waits_while_the_ground_is_unloaded() {
+        let chunk_map = floor_with(&[]);
+        let start = eye_at(Vec3::new(-10.5, 5.0, 10.5));
+        let mut eye = start;
+        let mut body = PlayerBody { velocity: Vec3::new(1.0, -5.0, 0.0), ..default() };
+        walk(&chunk_map, &mut eye, &mut body, Vec3::X, 10);
+
+        assert_eq!(eye, start);
+        assert_eq!(body.velocity, Vec3::ZERO);
+    }
+}
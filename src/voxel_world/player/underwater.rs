@@ -0,0 +1,63 @@
+use bevy::prelude::*;
+
+use crate::voxel_world::{
+    core::{coordinates::VOXEL_SIZE, voxel::Voxel},
+    fluid::water_surface_height,
+    storage::ChunkMap,
+};
+use super::Player;
+
+// カメラが水中にあるか
+#[derive(Resource, Debug, Default, PartialEq, Eq)]
+pub struct Submerged(pub bool);
+
+// 地上のフォグ
+pub fn default_fog() -> DistanceFog {
+    DistanceFog {
+        color: Color::srgba(0.35, 0.48, 0.66, 1.0),
+        directional_light_color: Color::srgba(1.0, 0.95, 0.85, 0.5),
+        directional_light_exponent: 30.0,
+        falloff: FogFalloff::from_visibility_colors(
+            12000.0, // distance in world units up to which objects retain visibility (>= 5% contrast)
+            Color::srgb(0.35, 0.5, 0.66), // atmospheric extinction color (after light is lost due to absorption by atmospheric particles)
+            Color::srgb(0.8, 0.844, 1.0), // atmospheric inscattering color (light gained due to scattering from the sun)
+        ),
+    }
+}
+
+// 水中のフォグ。数ブロック先までしか見えないようにする
+fn underwater_fog() -> DistanceFog {
+    DistanceFog {
+        color: Color::srgb(0.03, 0.18, 0.32),
+        directional_light_color: Color::NONE,
+        directional_light_exponent: 30.0,
+        falloff: FogFalloff::from_visibility_colors(
+            24.0 * VOXEL_SIZE,
+            Color::srgb(0.25, 0.08, 0.04), // 赤い光ほど早く吸収される
+            Color::srgb(0.02, 0.15, 0.3),
+        ),
+    }
+}
+
+pub fn update_submerged(
+    player_transform: Single<&Transform, With<Player>>,
+    chunk_map: Res<ChunkMap>,
+    mut submerged: ResMut<Submerged>,
+) {
+    let camera_pos = player_transform.translation / VOXEL_SIZE;
+    let camera_voxel = camera_pos.floor().as_ivec3();
+    // 水面はボクセルの上端より低いので、ボクセル内の高さも比べる
+    let in_water = chunk_map.get_at(camera_voxel).is_some_and(|voxel| {
+        let water_above = chunk_map.get_at(camera_voxel + IVec3::Y).is_some_and(|above| above.is(Voxel::WATER));
+        voxel.is(Voxel::WATER) && camera_pos.y - camera_pos.y.floor() < water_surface_height(voxel, water_above)
+    });
+    // resource_changed で切り替わったときだけ検知できるように、値が変わったときだけ書き込む
+    submerged.set_if_neq(Submerged(in_water));
+}
+
+pub fn apply_underwater_fog(
+    submerged: Res<Submerged>,
+    mut fog: Single<&mut DistanceFog, With<Player>>,
+) {
+    **fog = if submerged.0 { underwater_fog() } else { default_fog() };
+}
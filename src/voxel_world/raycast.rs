@@ -0,0 +1,241 @@
+use bevy::prelude::*;
+use block_mesh::VoxelVisibility;
+
+use crate::voxel_world::{
+    core::{coordinates::VOXEL_SIZE, registry::VoxelRegistry, voxel::Voxel},
+    storage::ChunkMap,
+};
+
+// レイキャストでどのボクセルに当たったとみなすかの設定
+// VoxelVisibility::Empty のボクセル (空気など) は常に通過する
+#[derive(Debug, Clone, Copy)]
+pub struct VoxelRaycastFilter {
+    // WATER に当たったとみなすか
+    pub hit_water: bool,
+    // 草花などのCrossボクセルに当たったとみなすか
+    pub hit_cross: bool,
+    // 読み込まれていないチャンクに入ったら探索を打ち切るか
+    // false の場合は空のチャンクとして通過する
+    pub stop_at_unloaded: bool,
+}
+
+impl Default for VoxelRaycastFilter {
+    fn default() -> Self {
+        Self {
+            hit_water: false,
+            hit_cross: true,
+            stop_at_unloaded: true,
+        }
+    }
+}
+
+impl VoxelRaycastFilter {
+    // アセットで追加・上書きされたボクセルも扱えるように、見た目はレジストリで判定する
+    pub fn is_hit(&self, voxel: Voxel, registry: &VoxelRegistry) -> bool {
+        if voxel.is(Voxel::WATER) {
+            return self.hit_water;
+        }
+        if registry.is_cross(voxel) {
+            return self.hit_cross;
+        }
+        registry.visibility(voxel) != VoxelVisibility::Empty
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+pub struct VoxelRayHit {
+    // 当たったボクセルの座標
+    pub position: IVec3,
+    // レイがボクセルの表面に当たったワールド座標
+    pub point: Vec3,
+    pub voxel: Voxel,
+    // レイが入ってきた面の法線。始点がボクセル内部の場合はゼロ
+    pub normal: IVec3,
+    pub distance: f32,
+}
+
+#[allow(dead_code)]
+impl VoxelRayHit {
+    // 当たった面に隣接するボクセルの座標 (ブロックを置く位置)
+    pub fn adjacent(&self) -> IVec3 {
+        self.position + self.normal
+    }
+}
+
+// ボクセルグリッド上のDDA (Amanatides & Woo) によるレイキャスト
+// origin, max_distance はワールド座標系。どちらかが有限でない場合は None を返す
+pub fn raycast_voxels(
+    chunk_map: &ChunkMap,
+    registry: &VoxelRegistry,
+    origin: Vec3,
+    direction: Vec3,
+    max_distance: f32,
+    filter: &VoxelRaycastFilter,
+) -> Option<VoxelRayHit> {
+    let direction = direction.normalize_or_zero();
+    if direction == Vec3::ZERO {
+        return None;
+    }
+    // 無限大や NaN では t > max_t が成り立たず、読み込まれていないチャンクを通過し続けて終わらない
+    if !max_distance.is_finite() || !origin.is_finite() {
+        return None;
+    }
+
+    // ボクセル単位の座標系で計算する
+    let start = origin / VOXEL_SIZE;
+    let max_t = max_distance / VOXEL_SIZE;
+    let mut voxel_pos = start.floor().as_ivec3();
+    let step = direction.signum().as_ivec3();
+
+    let t_delta = Vec3::new(
+        if direction.x != 0.0 { (1.0 / direction.x).abs() } else { f32::INFINITY },
+        if direction.y != 0.0 { (1.0 / direction.y).abs() } else { f32::INFINITY },
+        if direction.z != 0.0 { (1.0 / direction.z).abs() } else { f32::INFINITY },
+    );
+    // 各軸で次のボクセル境界に到達するまでの距離
+    let mut t_max = Vec3::ZERO;
+    for axis in 0..3 {
+        t_max[axis] = if direction[axis] > 0.0 {
+            (voxel_pos[axis] as f32 + 1.0 - start[axis]) * t_delta[axis]
+        } else if direction[axis] < 0.0 {
+            (start[axis] - voxel_pos[axis] as f32) * t_delta[axis]
+        } else {
+            f32::INFINITY
+        };
+    }
+
+    let mut normal = IVec3::ZERO;
+    let mut t = 0.0;
+    loop {
+        match chunk_map.get_at(voxel_pos) {
+            Some(voxel) if filter.is_hit(voxel, registry) => {
+                return Some(VoxelRayHit {
+                    position: voxel_pos,
+                    point: (start + direction * t) * VOXEL_SIZE,
+                    voxel,
+                    normal,
+                    distance: t * VOXEL_SIZE,
+                });
+            },
+            Some(_) => {},
+            None if filter.stop_at_unloaded => return None,
+            None => {},
+        }
+
+        // 最も近い境界を持つ軸に沿って隣のボクセルへ進む
+        let axis = if t_max.x < t_max.y {
+            if t_max.x < t_max.z { 0 } else { 2 }
+        } else if t_max.y < t_max.z {
+            1
+        } else {
+            2
+        };
+        t = t_max[axis];
+        if t > max_t {
+            return None;
+        }
+        voxel_pos[axis] += step[axis];
+        t_max[axis] += t_delta[axis];
+        normal = IVec3::ZERO;
+        normal[axis] = -step[axis];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::voxel_world::core::{coordinates::TERRAIN_CHUNK_SIZE, terrain_chunk::TerrainChunkData};
+
+    const SIZE: i32 = TERRAIN_CHUNK_SIZE as i32;
+
+    fn chunk_map(chunks: &[IVec3], voxels: &[(IVec3, Voxel)]) -> ChunkMap {
+        let mut chunk_map = ChunkMap::default();
+        for &position in chunks {
+            chunk_map.insert(TerrainChunkData::new_empty(position));
+        }
+        for &(world_pos, voxel) in voxels {
+            chunk_map.set_at(world_pos, voxel).unwrap();
+        }
+        chunk_map
+    }
+
+    // ボクセルの中心のワールド座標
+    fn center(voxel_pos: IVec3) -> Vec3 {
+        (voxel_pos.as_vec3() + Vec3::splat(0.5)) * VOXEL_SIZE
+    }
+
+    fn cast(chunk_map: &ChunkMap, origin: Vec3, direction: Vec3, max_distance: f32, filter: VoxelRaycastFilter) -> Option<VoxelRayHit> {
+        raycast_voxels(chunk_map, &VoxelRegistry::default(), origin, direction, max_distance, &filter)
+    }
+
+    #[test]
+    fn axis_aligned_ray_reports_face_and_distance() {
+        let chunk_map = chunk_map(&[IVec3::ZERO], &[(IVec3::new(5, 1, 1), Voxel::STONE), (IVec3::new(1, 0, 1), Voxel::STONE)]);
+        let filter = VoxelRaycastFilter::default();
+
+        let hit = cast(&chunk_map, center(IVec3::ONE), Vec3::X, 10.0 * VOXEL_SIZE, filter).unwrap();
+        assert_eq!(hit.position, IVec3::new(5, 1, 1));
+        assert_eq!(hit.normal, IVec3::NEG_X);
+        assert_eq!(hit.adjacent(), IVec3::new(4, 1, 1));
+        assert!((hit.distance - 3.5 * VOXEL_SIZE).abs() < 1e-4);
+        assert!((hit.point.x - 5.0 * VOXEL_SIZE).abs() < 1e-4);
+
+        let hit = cast(&chunk_map, center(IVec3::ONE), Vec3::NEG_Y, 10.0 * VOXEL_SIZE, filter).unwrap();
+        assert_eq!(hit.position, IVec3::new(1, 0, 1));
+        assert_eq!(hit.normal, IVec3::Y);
+        assert!((hit.distance - 0.5 * VOXEL_SIZE).abs() < 1e-4);
+
+        // 最大距離より遠いボクセルには当たらない
+        assert!(cast(&chunk_map, center(IVec3::ONE), Vec3::X, 3.0 * VOXEL_SIZE, filter).is_none());
+    }
+
+    #[test]
+    fn ray_crosses_chunk_border() {
+        let target = IVec3::new(SIZE + 2, 1, -3);
+        let chunk_map = chunk_map(&[IVec3::ZERO, IVec3::X, IVec3::new(0, 0, -1), IVec3::new(1, 0, -1)], &[(target, Voxel::STONE)]);
+        let origin = center(IVec3::new(SIZE - 3, 1, 2));
+        let direction = center(target) - origin;
+
+        let hit = cast(&chunk_map, origin, direction, direction.length() * 2.0, VoxelRaycastFilter::default()).unwrap();
+        assert_eq!(hit.position, target);
+    }
+
+    #[test]
+    fn unloaded_chunks_stop_or_pass_the_ray() {
+        // 間の IVec3::X のチャンクは読み込まれていない
+        let target = IVec3::new(2 * SIZE + 1, 1, 1);
+        let chunk_map = chunk_map(&[IVec3::ZERO, IVec3::new(2, 0, 0)], &[(target, Voxel::STONE)]);
+        let max_distance = 3.0 * SIZE as f32 * VOXEL_SIZE;
+
+        let stop = VoxelRaycastFilter { stop_at_unloaded: true, ..default() };
+        assert!(cast(&chunk_map, center(IVec3::ONE), Vec3::X, max_distance, stop).is_none());
+
+        let pass = VoxelRaycastFilter { stop_at_unloaded: false, ..default() };
+        assert_eq!(cast(&chunk_map, center(IVec3::ONE), Vec3::X, max_distance, pass).unwrap().position, target);
+    }
+
+    #[test]
+    fn filter_skips_water_and_cross_voxels() {
+        let chunk_map = chunk_map(
+            &[IVec3::ZERO],
+            &[(IVec3::new(3, 1, 1), Voxel::FLOWER_RED), (IVec3::new(5, 1, 1), Voxel::WATER), (IVec3::new(7, 1, 1), Voxel::STONE)],
+        );
+        let hit_x = |filter| cast(&chunk_map, center(IVec3::ONE), Vec3::X, 20.0 * VOXEL_SIZE, filter).map(|hit| hit.position.x);
+
+        assert_eq!(hit_x(VoxelRaycastFilter::default()), Some(3));
+        assert_eq!(hit_x(VoxelRaycastFilter { hit_cross: false, ..default() }), Some(7));
+        assert_eq!(hit_x(VoxelRaycastFilter { hit_cross: false, hit_water: true, ..default() }), Some(5));
+    }
+
+    #[test]
+    fn non_finite_input_returns_none() {
+        let chunk_map = chunk_map(&[IVec3::ZERO], &[]);
+        let pass = VoxelRaycastFilter { stop_at_unloaded: false, ..default() };
+
+        assert!(cast(&chunk_map, center(IVec3::ONE), Vec3::X, f32::INFINITY, pass).is_none());
+        assert!(cast(&chunk_map, center(IVec3::ONE), Vec3::X, f32::NAN, pass).is_none());
+        assert!(cast(&chunk_map, Vec3::NAN, Vec3::X, 10.0, pass).is_none());
+        assert!(cast(&chunk_map, center(IVec3::ONE), Vec3::new(f32::NAN, 0.0, 0.0), 10.0, pass).is_none());
+    }
+}
@@ -5,103 +5,76 @@ use bevy::{ecs::resource::Resource, math::{IVec3, UVec3}, platform::collections:
 use block_mesh::ndshape::ConstShape;
 use itertools::iproduct;
 
-use crate::voxel_world::core::{chunk::Chunk, terrain_chunk::{PaddedTerrainChunkShape, TerrainChunkData}, coordinates::TERRAIN_CHUNK_SIZE, voxel::Voxel};
+use crate::voxel_world::core::{terrain_chunk::{padding_span, PaddedTerrainChunkShape, TerrainChunkData}, coordinates::TERRAIN_CHUNK_SIZE, voxel::Voxel};
+use crate::voxel_world::editing::chunks_affected_by;
+use super::region::RegionStore;
 
 #[derive(Debug, Resource, Default)]
 pub struct ChunkMap {
     pub chunks: HashMap<IVec3, TerrainChunkData>,
+    // 生成後に編集され、アンロード時にディスクへ保存する必要があるチャンク
+    pub dirty: HashSet<IVec3>,
 }
 
-#[allow(dead_code)]
 impl ChunkMap {
-    pub fn new() -> Self {
-        Self {
-            chunks: HashMap::new(),
+    pub fn insert(&mut self, chunk: TerrainChunkData) {
+        self.dirty.remove(&chunk.position);
+        self.chunks.insert(chunk.position, chunk);
+    }
+
+    // チャンクをメモリから取り除く。編集済みのチャンクはバックグラウンドでリージョンファイルに書き出す
+    pub fn unload(&mut self, position: &IVec3, store: &RegionStore) {
+        let Some(mut chunk) = self.chunks.remove(position) else {
+            return;
+        };
+        if self.dirty.remove(position) {
+            // 編集で使われなくなったパレットエントリを取り除いてから保存する
+            chunk.chunk.compact();
+            store.queue_save(chunk);
         }
     }
 
-    pub fn insert(&mut self, chunk: TerrainChunkData) {
-        self.chunks.insert(chunk.position, chunk);
+    // 編集済みのチャンクをすべて保存待ちにする (終了時などに使用)
+    pub fn save_dirty(&mut self, store: &RegionStore) {
+        for position in self.dirty.drain() {
+            if let Some(chunk) = self.chunks.get_mut(&position) {
+                chunk.chunk.compact();
+                store.queue_save(chunk.clone());
+            }
+        }
     }
 
     pub fn get(&self, position: &IVec3) -> Option<&TerrainChunkData> {
         self.chunks.get(position)
     }
-    pub fn get_slice(&self, position: &IVec3) -> Option<&[Voxel]> {
-        let chunk = self.chunks.get(position)?;
-        Some(chunk.chunk.as_slice())
-    }
-    // meshingする際に使用。隣接する6チャンクの1層分を取り込んで取得する
+    // meshingする際に使用。周囲26チャンクの1層分 (面・辺・角) を取り込んで取得する
     // positionのチャンクが存在しないときはNoneを返す
     // 隣接するチャンクが存在しないときはEMPTY_VOXELで埋める
-    pub fn get_padded_chunk_vec(&self, position: &IVec3) -> Option<Chunk<PaddedTerrainChunkShape>> {
-        let center_chunk = self.chunks.get(position)?;
+    // PaddedTerrainChunkShape の並びのまま返し、メッシュ生成にそのまま渡す
+    pub fn get_padded_chunk_vec(&self, position: &IVec3) -> Option<Vec<Voxel>> {
+        self.chunks.get(position)?;
         let mut padded_voxels = vec![Voxel::EMPTY; PaddedTerrainChunkShape::USIZE];
-        // 中心チャンクをコピー
-        for (x, y, z) in iproduct!(0..TERRAIN_CHUNK_SIZE, 0..TERRAIN_CHUNK_SIZE, 0..TERRAIN_CHUNK_SIZE) {
-            let voxel = center_chunk.get_local_at(UVec3::new(x, y, z));
-            let index = PaddedTerrainChunkShape::linearize([x + 1, y + 1, z + 1]) as usize;
-            padded_voxels[index] = voxel;
-        }
-        // 6方向の隣接チャンクをコピー
-        // -X (West) 方向
-        if let Some(west_chunk) = self.chunks.get(&( *position + IVec3::new(-1, 0, 0))) {
-            for (y, z) in iproduct!(0..TERRAIN_CHUNK_SIZE, 0..TERRAIN_CHUNK_SIZE) {
-                let voxel = west_chunk.get_local_at(UVec3::new(TERRAIN_CHUNK_SIZE - 1, y, z));
-                let index = PaddedTerrainChunkShape::linearize([0, y + 1, z + 1]) as usize;
-                padded_voxels[index] = voxel;
-            }
-        }
-        // +X (East) 方向
-        if let Some(east_chunk) = self.chunks.get(&( *position + IVec3::new(1, 0, 0))) {
-            for (y, z) in iproduct!(0..TERRAIN_CHUNK_SIZE, 0..TERRAIN_CHUNK_SIZE) {
-                let voxel = east_chunk.get_local_at(UVec3::new(0, y, z));
-                let index = PaddedTerrainChunkShape::linearize([TERRAIN_CHUNK_SIZE + 1, y + 1, z + 1]) as usize;
-                padded_voxels[index] = voxel;
-            }
-        }
-        // -Y (Down) 方向
-        if let Some(down_chunk) = self.chunks.get(&( *position + IVec3::new(0, -1, 0))) {
-            for (x, z) in iproduct!(0..TERRAIN_CHUNK_SIZE, 0..TERRAIN_CHUNK_SIZE) {
-                let voxel = down_chunk.get_local_at(UVec3::new(x, TERRAIN_CHUNK_SIZE - 1, z));
-                let index = PaddedTerrainChunkShape::linearize([x + 1, 0, z + 1]) as usize;
-                padded_voxels[index] = voxel;
-            }
-        }
-        // +Y (Up) 方向
-        if let Some(up_chunk) = self.chunks.get(&( *position + IVec3::new(0, 1, 0))) {
-            for (x, z) in iproduct!(0..TERRAIN_CHUNK_SIZE, 0..TERRAIN_CHUNK_SIZE) {
-                let voxel = up_chunk.get_local_at(UVec3::new(x, 0, z));
-                let index = PaddedTerrainChunkShape::linearize([x + 1, TERRAIN_CHUNK_SIZE + 1, z + 1]) as usize;
-                padded_voxels[index] = voxel;
+        // 中心チャンク (オフセット0) と周囲26チャンクをコピー
+        for (dx, dy, dz) in iproduct!(-1..=1, -1..=1, -1..=1) {
+            let Some(chunk) = self.chunks.get(&(*position + IVec3::new(dx, dy, dz))) else {
+                continue;
+            };
+            let single = chunk.chunk.as_single();
+            if single == Some(Voxel::EMPTY) {
+                continue;
             }
-        }
-        // -Z (North) 方向
-        if let Some(north_chunk) = self.chunks.get(&( *position + IVec3::new(0, 0, -1))) {
-            for (x, y) in iproduct!(0..TERRAIN_CHUNK_SIZE, 0..TERRAIN_CHUNK_SIZE) {
-                let voxel = north_chunk.get_local_at(UVec3::new(x, y, TERRAIN_CHUNK_SIZE - 1));
-                let index = PaddedTerrainChunkShape::linearize([x + 1, y + 1, 0]) as usize;
+            let [(px, lx, nx), (py, ly, ny), (pz, lz, nz)] = [padding_span(dx), padding_span(dy), padding_span(dz)];
+            for (x, y, z) in iproduct!(0..nx, 0..ny, 0..nz) {
+                let voxel = single.unwrap_or_else(|| chunk.get_local_at(UVec3::new(lx + x, ly + y, lz + z)));
+                let index = PaddedTerrainChunkShape::linearize([px + x, py + y, pz + z]) as usize;
                 padded_voxels[index] = voxel;
             }
         }
-        // +Z (South) 方向
-        if let Some(south_chunk) = self.chunks.get(&( *position + IVec3::new(0, 0, 1))) {
-            for (x, y) in iproduct!(0..TERRAIN_CHUNK_SIZE, 0..TERRAIN_CHUNK_SIZE) {
-                let voxel = south_chunk.get_local_at(UVec3::new(x, y, 0));
-                let index = PaddedTerrainChunkShape::linearize([x + 1, y + 1, TERRAIN_CHUNK_SIZE + 1]) as usize;
-                padded_voxels[index] = voxel;
-            }
-        }
-        Some(Chunk {
-            voxels: padded_voxels.into_boxed_slice(),
-            shape: PaddedTerrainChunkShape {},
-        })
-    }
-
-    pub fn get_mut(&mut self, position: &IVec3) -> Option<&mut TerrainChunkData> {
-        self.chunks.get_mut(position)
+        Some(padded_voxels)
     }
 
+    // フィーチャーの書き込み用。空気・水・雪だけを上書きし、読み込まれていないチャンクへの変更は捨てる
+    // メッシュを作り直す必要があるチャンクを返す
     pub fn set_bulk(&mut self, changes: Vec<(IVec3, Voxel)>) -> HashSet<IVec3> {
         let mut chunks_to_update = HashSet::new();
         let mut changes_by_chunk: HashMap<IVec3, Vec<(IVec3, Voxel)>> = HashMap::new();
@@ -113,20 +86,19 @@ impl ChunkMap {
 
         for (chunk_pos, chunk_changes) in changes_by_chunk {
             if let Some(chunk) = self.chunks.get_mut(&chunk_pos) {
-                let mut modified = false;
                 for (world_pos, voxel) in chunk_changes {
                     let local_pos = (world_pos.rem_euclid(IVec3::splat(TERRAIN_CHUNK_SIZE as i32))).as_uvec3();
-                    let target_voxel = chunk.get_local_at_mut(local_pos);
+                    let target_voxel = chunk.get_local_at(local_pos);
                     
                     // Only overwrite if the target is empty, water, or snow (soft blocks)
-                    if target_voxel.id == Voxel::EMPTY.id || target_voxel.id == Voxel::WATER.id || target_voxel.id == Voxel::SNOW.id {
-                        *target_voxel = voxel;
-                        modified = true;
+                    if (target_voxel.id == Voxel::EMPTY.id || target_voxel.id == Voxel::WATER.id || target_voxel.id == Voxel::SNOW.id)
+                        && target_voxel != voxel
+                    {
+                        chunk.set_local_at(local_pos, voxel);
+                        // 境界のボクセルは隣接チャンクのメッシュのパディングにも含まれる
+                        chunks_to_update.extend(chunks_affected_by(world_pos));
                     }
                 }
-                if modified {
-                    chunks_to_update.insert(chunk_pos);
-                }
             }
         }
         chunks_to_update
@@ -137,9 +109,45 @@ impl ChunkMap {
         let chunk = self.chunks.get(&chunk_pos)?;
         Some(chunk.get_at(world_pos))
     }
-    pub fn get_at_mut(&mut self, world_pos: IVec3) -> Option<&mut Voxel> {
+    // ボクセルを書き換え、変更前のボクセルを返す
+    // チャンクが読み込まれていない場合はNoneを返す
+    // メッシュの更新や VoxelChanged の送信はしないので、ゲーム中の編集は VoxelWorld を通す
+    pub fn set_at(&mut self, world_pos: IVec3, voxel: Voxel) -> Option<Voxel> {
         let chunk_pos = world_pos.div_euclid(IVec3::splat(TERRAIN_CHUNK_SIZE as i32));
         let chunk = self.chunks.get_mut(&chunk_pos)?;
-        Some(chunk.get_at_mut(world_pos))
+        let local_pos = world_pos.rem_euclid(IVec3::splat(TERRAIN_CHUNK_SIZE as i32)).as_uvec3();
+        let old = chunk.get_local_at(local_pos);
+        if old != voxel {
+            chunk.set_local_at(local_pos, voxel);
+            self.dirty.insert(chunk_pos);
+        }
+        Some(old)
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use bevy::tasks::{IoTaskPool, TaskPool};
+
+    use super::*;
+
+    #[test]
+    fn saving_compacts_dirty_chunks() {
+        IoTaskPool::get_or_init(TaskPool::new);
+        let dir = std::env::temp_dir().join(format!("furaxel-chunk-map-{}", std::process::id()));
+        let store = RegionStore::new(&dir);
+        let mut chunk_map = ChunkMap::default();
+        chunk_map.insert(TerrainChunkData::new_empty(IVec3::ZERO));
+
+        // 元に戻してもパレットには STONE が残る
+        chunk_map.set_at(IVec3::ONE, Voxel::STONE).unwrap();
+        chunk_map.set_at(IVec3::ONE, Voxel::EMPTY).unwrap();
+        assert_eq!(chunk_map.get(&IVec3::ZERO).unwrap().chunk.as_single(), None);
+
+        chunk_map.save_dirty(&store);
+        assert_eq!(chunk_map.get(&IVec3::ZERO).unwrap().chunk.as_single(), Some(Voxel::EMPTY));
+        assert!(chunk_map.dirty.is_empty());
+
+        store.flush_pending().unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -1,3 +1,5 @@
 pub mod cpu_chunk_map;
+pub mod region;
 
-pub use cpu_chunk_map::*;
\ No newline at end of file
+pub use cpu_chunk_map::*;
+pub use region::*;
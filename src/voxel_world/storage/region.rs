@@ -0,0 +1,518 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use bevy::{ecs::resource::Resource, log::{error, info}, math::IVec3, platform::collections::HashMap, tasks::IoTaskPool};
+use flate2::{Compression, read::ZlibDecoder, write::ZlibEncoder};
+
+use crate::voxel_world::core::{coordinates::TERRAIN_CHUNK_SIZE, terrain_chunk::TerrainChunkData, voxel::Voxel};
+
+// 1つのリージョンファイルに格納するチャンク数 (各軸)
+pub const REGION_SIZE: i32 = 32;
+const REGION_CHUNK_COUNT: usize = (REGION_SIZE * REGION_SIZE * REGION_SIZE) as usize;
+
+const REGION_MAGIC: &[u8; 4] = b"FXRG";
+const REGION_VERSION: u32 = 3;
+// 以前のバージョンは開いたときに REGION_VERSION に変換する
+// バージョン1: ステートを持たない (連続数 u16, ボクセルID u16) の列を圧縮せずに格納
+const REGION_VERSION_V1: u32 = 1;
+// バージョン2: (連続数 u16, ボクセルID u16, ステート u16) の列を圧縮せずに格納
+const REGION_VERSION_V2: u32 = 2;
+// 上書きで使われなくなった領域がこのサイズを超え、かつ使われている領域より大きければ開いたときに詰め直す
+const COMPACT_MIN_WASTE: u64 = 1 << 20;
+// magic + version + (offset, length) * チャンク数
+const HEADER_SIZE: u64 = 8 + REGION_CHUNK_COUNT as u64 * 8;
+
+// リージョンファイル内の1チャンク分の位置情報
+#[derive(Debug, Clone, Copy, Default)]
+struct ChunkSlot {
+    offset: u32,
+    length: u32,
+}
+
+impl ChunkSlot {
+    fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+}
+
+// 開いているリージョンファイルとそのオフセットテーブル
+#[derive(Debug)]
+struct RegionFile {
+    file: File,
+    slots: Box<[ChunkSlot]>,
+    end: u64,
+}
+
+impl RegionFile {
+    fn open(path: &Path) -> io::Result<Self> {
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        let len = file.metadata()?.len();
+        let mut slots = vec![ChunkSlot::default(); REGION_CHUNK_COUNT].into_boxed_slice();
+
+        if len == 0 {
+            // 新規ファイル: 空のヘッダを書き込む
+            let mut header = Vec::with_capacity(HEADER_SIZE as usize);
+            header.extend_from_slice(REGION_MAGIC);
+            header.extend_from_slice(&REGION_VERSION.to_le_bytes());
+            header.resize(HEADER_SIZE as usize, 0);
+            file.write_all(&header)?;
+            return Ok(Self { file, slots, end: HEADER_SIZE });
+        }
+
+        let mut header = vec![0u8; HEADER_SIZE as usize];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut header)?;
+        if &header[0..4] != REGION_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid region file magic"));
+        }
+        let version = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        if !matches!(version, REGION_VERSION | REGION_VERSION_V1 | REGION_VERSION_V2) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unsupported region file version {version}")));
+        }
+        for (i, slot) in slots.iter_mut().enumerate() {
+            let base = 8 + i * 8;
+            slot.offset = u32::from_le_bytes(header[base..base + 4].try_into().unwrap());
+            slot.length = u32::from_le_bytes(header[base + 4..base + 8].try_into().unwrap());
+        }
+        let region = Self { file, slots, end: len.max(HEADER_SIZE) };
+        let used: u64 = region.slots.iter().map(|slot| slot.length as u64).sum();
+        let waste = (region.end - HEADER_SIZE).saturating_sub(used);
+        if version != REGION_VERSION || (waste > COMPACT_MIN_WASTE && waste > used) {
+            region.rewrite(path, version)?;
+            return Self::open(path);
+        }
+        Ok(region)
+    }
+
+    // 使われている領域だけを別名のファイルに書き直してから置き換える
+    // 古いバージョンのファイルはペイロードを変換し、元のファイルを .v{バージョン} を付けた名前で残す
+    fn rewrite(mut self, path: &Path, version: u32) -> io::Result<()> {
+        let rewritten_path = path.with_extension("bin.tmp");
+        // 途中で終了したときの書きかけのファイルは作り直す
+        match fs::remove_file(&rewritten_path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {},
+        }
+
+        let mut rewritten = Self::open(&rewritten_path)?;
+        for index in 0..REGION_CHUNK_COUNT {
+            if let Some(payload) = self.read(index)? {
+                let slot = rewritten.append(&upgrade_payload(version, payload)?)?;
+                rewritten.set_slot(index, slot)?;
+            }
+        }
+        rewritten.file.sync_all()?;
+        drop(rewritten);
+        drop(self);
+
+        if version == REGION_VERSION {
+            fs::rename(&rewritten_path, path)?;
+            info!("Compacted region file {}", path.display());
+        } else {
+            let backup_path = path.with_extension(format!("bin.v{version}"));
+            fs::rename(path, &backup_path)?;
+            fs::rename(&rewritten_path, path)?;
+            info!("Upgraded region file {} to version {REGION_VERSION}; the original is kept as {}", path.display(), backup_path.display());
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, index: usize) -> io::Result<Option<Vec<u8>>> {
+        let slot = self.slots[index];
+        if slot.is_empty() {
+            return Ok(None);
+        }
+        let mut payload = vec![0u8; slot.length as usize];
+        self.file.seek(SeekFrom::Start(slot.offset as u64))?;
+        self.file.read_exact(&mut payload)?;
+        Ok(Some(payload))
+    }
+
+    // 以前の領域は上書きせず、常に末尾に追記してからヘッダを書き換える
+    // 途中で終了しても、ヘッダは古いペイロードか新しいペイロードのどちらかを指す
+    fn write(&mut self, index: usize, payload: &[u8]) -> io::Result<()> {
+        let slot = self.append(payload)?;
+        self.file.sync_data()?;
+        self.set_slot(index, slot)
+    }
+
+    fn append(&mut self, payload: &[u8]) -> io::Result<ChunkSlot> {
+        let offset = self.end;
+        let new_end = offset + payload.len() as u64;
+        if new_end > u32::MAX as u64 {
+            return Err(io::Error::other("region file exceeds 4 GiB"));
+        }
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(payload)?;
+        self.end = new_end;
+        Ok(ChunkSlot { offset: offset as u32, length: payload.len() as u32 })
+    }
+
+    fn set_slot(&mut self, index: usize, slot: ChunkSlot) -> io::Result<()> {
+        let mut entry = [0u8; 8];
+        entry[0..4].copy_from_slice(&slot.offset.to_le_bytes());
+        entry[4..8].copy_from_slice(&slot.length.to_le_bytes());
+        self.file.seek(SeekFrom::Start(8 + index as u64 * 8))?;
+        self.file.write_all(&entry)?;
+        self.slots[index] = slot;
+        Ok(())
+    }
+}
+
+// チャンクをリージョンファイル単位でディスクに保存・読み込みするストア
+// 非同期タスクからも読み込めるように、内部状態はArc<Mutex<_>>で共有する
+#[derive(Debug, Resource, Clone)]
+pub struct RegionStore {
+    root: PathBuf,
+    regions: Arc<Mutex<HashMap<IVec3, RegionFile>>>,
+    // 保存待ちのチャンク (書き出しが終わるまでは読み込みもここから返す)
+    pending: Arc<Mutex<HashMap<IVec3, Arc<TerrainChunkData>>>>,
+}
+
+impl RegionStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            regions: Arc::new(Mutex::new(HashMap::new())),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn region_pos(chunk_pos: IVec3) -> IVec3 {
+        chunk_pos.div_euclid(IVec3::splat(REGION_SIZE))
+    }
+
+    fn slot_index(chunk_pos: IVec3) -> usize {
+        let local = chunk_pos.rem_euclid(IVec3::splat(REGION_SIZE));
+        (local.x + local.z * REGION_SIZE + local.y * REGION_SIZE * REGION_SIZE) as usize
+    }
+
+    fn region_path(&self, region_pos: IVec3) -> PathBuf {
+        self.root.join(format!("r.{}.{}.{}.bin", region_pos.x, region_pos.y, region_pos.z))
+    }
+
+    // ロック済みのリージョンファイルの一覧から、ファイルを開いて返す
+    // create が false の場合、ファイルが存在しなければ None を返す
+    fn open_region<'a>(
+        &self,
+        regions: &'a mut HashMap<IVec3, RegionFile>,
+        region_pos: IVec3,
+        create: bool,
+    ) -> io::Result<Option<&'a mut RegionFile>> {
+        if !regions.contains_key(&region_pos) {
+            let path = self.region_path(region_pos);
+            if !create && !path.exists() {
+                return Ok(None);
+            }
+            fs::create_dir_all(&self.root)?;
+            regions.insert(region_pos, RegionFile::open(&path)?);
+        }
+        Ok(regions.get_mut(&region_pos))
+    }
+
+    // リージョンファイルを開いてクロージャに渡す
+    // create が false の場合、ファイルが存在しなければ None を返す
+    fn with_region<T>(
+        &self,
+        region_pos: IVec3,
+        create: bool,
+        f: impl FnOnce(&mut RegionFile) -> io::Result<T>,
+    ) -> io::Result<Option<T>> {
+        let mut regions = self.regions.lock().unwrap();
+        self.open_region(&mut regions, region_pos, create)?.map(f).transpose()
+    }
+
+    // チャンクを保存待ちにして、バックグラウンドで書き出す
+    // メインスレッドではリージョンファイルのロックを取らない
+    pub fn queue_save(&self, chunk: TerrainChunkData) {
+        let chunk_pos = chunk.position;
+        self.pending.lock().unwrap().insert(chunk_pos, Arc::new(chunk));
+        let store = self.clone();
+        IoTaskPool::get()
+            .spawn(async move {
+                if let Err(err) = store.write_pending(chunk_pos) {
+                    error!("Failed to save chunk {chunk_pos}: {err}");
+                }
+            })
+            .detach();
+    }
+
+    // 保存待ちのチャンクを書き出す
+    // リージョンファイルのロックを取ってから最新の保存待ちのデータを読むので、
+    // 同じチャンクが続けて保存待ちになっても、古いデータが後から書き込まれることはない
+    fn write_pending(&self, chunk_pos: IVec3) -> io::Result<()> {
+        let mut regions = self.regions.lock().unwrap();
+        let Some(chunk) = self.pending.lock().unwrap().get(&chunk_pos).cloned() else {
+            return Ok(());
+        };
+        let payload = encode_chunk(&chunk)?;
+        if let Some(region) = self.open_region(&mut regions, Self::region_pos(chunk_pos), true)? {
+            region.write(Self::slot_index(chunk_pos), &payload)?;
+        }
+        let mut pending = self.pending.lock().unwrap();
+        if pending.get(&chunk_pos).is_some_and(|current| Arc::ptr_eq(current, &chunk)) {
+            pending.remove(&chunk_pos);
+        }
+        Ok(())
+    }
+
+    // 保存待ちのチャンクをすべてその場で書き出し、書き出した数を返す (終了時に使用)
+    pub fn flush_pending(&self) -> io::Result<usize> {
+        let positions: Vec<IVec3> = self.pending.lock().unwrap().keys().copied().collect();
+        for &chunk_pos in &positions {
+            self.write_pending(chunk_pos)?;
+        }
+        Ok(positions.len())
+    }
+
+    pub fn load_chunk(&self, chunk_pos: IVec3) -> io::Result<Option<TerrainChunkData>> {
+        if let Some(chunk) = self.pending.lock().unwrap().get(&chunk_pos) {
+            return Ok(Some(TerrainChunkData::clone(chunk)));
+        }
+        let index = Self::slot_index(chunk_pos);
+        let payload = self.with_region(Self::region_pos(chunk_pos), false, |region| region.read(index))?;
+        match payload.flatten() {
+            Some(payload) => decode_chunk(chunk_pos, &payload).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+// ペイロード形式: ボクセル数(u32) に続いて (連続数 u16, ボクセルID u16, ステート u16) の列を zlib で圧縮したもの
+// 地形は同じボクセルが連続しやすいので、ランレングス符号化で大きく縮み、残った連続数や ID の繰り返しを zlib がさらに詰める
+// zlib のチェックサムで壊れたペイロードも検出できる
+fn encode_chunk(chunk: &TerrainChunkData) -> io::Result<Vec<u8>> {
+    let mut runs = Vec::new();
+    runs.extend_from_slice(&(chunk.chunk.voxels.len() as u32).to_le_bytes());
+
+    let mut iter = chunk.chunk.iter();
+    if let Some(mut current) = iter.next() {
+        let mut run: u16 = 1;
+        for voxel in iter {
+            if voxel == current && run < u16::MAX {
+                run += 1;
+            } else {
+                push_run(&mut runs, run, current);
+                current = voxel;
+                run = 1;
+            }
+        }
+        push_run(&mut runs, run, current);
+    }
+    compress(&runs)
+}
+
+fn push_run(runs: &mut Vec<u8>, run: u16, voxel: Voxel) {
+    runs.extend_from_slice(&run.to_le_bytes());
+    runs.extend_from_slice(&voxel.id.to_le_bytes());
+    runs.extend_from_slice(&voxel.state.to_le_bytes());
+}
+
+fn compress(runs: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(runs)?;
+    encoder.finish()
+}
+
+// 古いバージョンのペイロードを REGION_VERSION の形式に変換する
+fn upgrade_payload(version: u32, payload: Vec<u8>) -> io::Result<Vec<u8>> {
+    match version {
+        REGION_VERSION_V1 => compress(&upgrade_payload_v1(&payload)?),
+        REGION_VERSION_V2 => compress(&payload),
+        _ => Ok(payload),
+    }
+}
+
+// バージョン1のペイロード (連続数 u16, ボクセルID u16) にステート 0 を追加する
+fn upgrade_payload_v1(payload: &[u8]) -> io::Result<Vec<u8>> {
+    if payload.len() < 4 || !(payload.len() - 4).is_multiple_of(4) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated version 1 payload"));
+    }
+    let mut upgraded = Vec::with_capacity(4 + (payload.len() - 4) / 4 * 6);
+    upgraded.extend_from_slice(&payload[0..4]);
+    for entry in payload[4..].chunks_exact(4) {
+        upgraded.extend_from_slice(entry);
+        upgraded.extend_from_slice(&0u16.to_le_bytes());
+    }
+    Ok(upgraded)
+}
+
+fn decode_chunk(chunk_pos: IVec3, payload: &[u8]) -> io::Result<TerrainChunkData> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, format!("chunk {chunk_pos}: {msg}"));
+    let count = TERRAIN_CHUNK_SIZE.pow(3) as usize;
+
+    // 壊れたファイルで大きなメモリを確保しないように、1ボクセル1連続でも収まる大きさまでしか展開しない
+    let max_len = 4 + count * 6;
+    let mut runs = Vec::new();
+    ZlibDecoder::new(payload)
+        .take(max_len as u64 + 1)
+        .read_to_end(&mut runs)
+        .map_err(|err| invalid(&format!("corrupted payload ({err})")))?;
+    if runs.len() > max_len {
+        return Err(invalid("payload too large"));
+    }
+    if runs.len() < 4 || !(runs.len() - 4).is_multiple_of(6) {
+        return Err(invalid("truncated payload"));
+    }
+    if u32::from_le_bytes(runs[0..4].try_into().unwrap()) as usize != count {
+        return Err(invalid("voxel count mismatch"));
+    }
+
+    let mut voxels = Vec::with_capacity(count);
+    for entry in runs[4..].chunks_exact(6) {
+        let run = u16::from_le_bytes([entry[0], entry[1]]) as usize;
+        if run > count - voxels.len() {
+            return Err(invalid("voxel count mismatch"));
+        }
+        let id = u16::from_le_bytes([entry[2], entry[3]]);
+        let state = u16::from_le_bytes([entry[4], entry[5]]);
+        voxels.extend(std::iter::repeat_n(Voxel::with_state(id, state), run));
+    }
+
+    if voxels.len() != count {
+        return Err(invalid("voxel count mismatch"));
+    }
+    Ok(TerrainChunkData::from_vec(chunk_pos, voxels))
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::tasks::{IoTaskPool, TaskPool};
+
+    use super::*;
+
+    // テストごとに別のディレクトリを使い、終了時に削除する
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("furaxel-region-{name}-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn sample_voxels() -> Vec<Voxel> {
+        (0..TERRAIN_CHUNK_SIZE.pow(3))
+            .map(|i| Voxel::with_state((i / 1000 % 5) as u16, (i / 3000 % 2) as u16))
+            .collect()
+    }
+
+    #[test]
+    fn saved_chunk_loads_back_from_disk() {
+        IoTaskPool::get_or_init(TaskPool::new);
+        let dir = TempDir::new("round-trip");
+        // 負の座標はリージョンの境界をまたぐ
+        let positions = [IVec3::new(0, 0, 0), IVec3::new(-1, 2, -33), IVec3::new(31, -1, 5)];
+
+        let store = RegionStore::new(&dir.0);
+        for &position in &positions {
+            store.queue_save(TerrainChunkData::from_vec(position, sample_voxels()));
+        }
+        store.flush_pending().unwrap();
+
+        // 開き直したストアではファイルからしか読めない
+        let reopened = RegionStore::new(&dir.0);
+        for &position in &positions {
+            let chunk = reopened.load_chunk(position).unwrap().expect("chunk should be saved");
+            assert_eq!(chunk.position, position);
+            assert_eq!(chunk.chunk.to_vec(), sample_voxels());
+        }
+        assert!(reopened.load_chunk(IVec3::new(1, 0, 0)).unwrap().is_none());
+        assert!(reopened.load_chunk(IVec3::new(100, 0, 0)).unwrap().is_none());
+    }
+
+    #[test]
+    fn version_1_region_is_upgraded_on_open() {
+        let dir = TempDir::new("upgrade-v1");
+        let store = RegionStore::new(&dir.0);
+        let position = IVec3::new(2, 0, 1);
+        let path = store.region_path(RegionStore::region_pos(position));
+
+        // 前半が空気、後半が ID 1 のチャンクをバージョン1の形式で書く
+        let count = TERRAIN_CHUNK_SIZE.pow(3);
+        let mut payload = count.to_le_bytes().to_vec();
+        for (run, id) in [(count / 2, 0u16), (count / 2, 1u16)] {
+            for start in (0..run).step_by(u16::MAX as usize) {
+                payload.extend_from_slice(&((run - start).min(u16::MAX as u32) as u16).to_le_bytes());
+                payload.extend_from_slice(&id.to_le_bytes());
+            }
+        }
+        let mut header = REGION_MAGIC.to_vec();
+        header.extend_from_slice(&REGION_VERSION_V1.to_le_bytes());
+        header.resize(HEADER_SIZE as usize, 0);
+        let base = 8 + RegionStore::slot_index(position) * 8;
+        header[base..base + 4].copy_from_slice(&(HEADER_SIZE as u32).to_le_bytes());
+        header[base + 4..base + 8].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+        header.extend_from_slice(&payload);
+        fs::write(&path, &header).unwrap();
+
+        let chunk = store.load_chunk(position).unwrap().expect("chunk should be upgraded");
+        let expected: Vec<Voxel> = (0..count).map(|i| Voxel::with_state((i >= count / 2) as u16, 0)).collect();
+        assert_eq!(chunk.chunk.to_vec(), expected);
+
+        assert_eq!(fs::read(path.with_extension("bin.v1")).unwrap(), header);
+        let upgraded = fs::read(&path).unwrap();
+        assert_eq!(u32::from_le_bytes(upgraded[4..8].try_into().unwrap()), REGION_VERSION);
+    }
+
+    #[test]
+    fn corrupted_payloads_are_rejected() {
+        let count = TERRAIN_CHUNK_SIZE.pow(3);
+        let runs = |stored_count: u32, entries: &[(u16, u16)]| {
+            let mut runs = stored_count.to_le_bytes().to_vec();
+            for &(run, id) in entries {
+                push_run(&mut runs, run, Voxel::with_state(id, 0));
+            }
+            compress(&runs).unwrap()
+        };
+
+        // 確保する前にボクセル数を確かめる
+        assert!(decode_chunk(IVec3::ZERO, &runs(u32::MAX, &[(u16::MAX, 1)])).is_err());
+        // 連続数の合計がボクセル数を超えたら読むのをやめる
+        let too_many = vec![(u16::MAX, 1); count as usize / u16::MAX as usize + 2];
+        assert!(decode_chunk(IVec3::ZERO, &runs(count, &too_many)).is_err());
+        assert!(decode_chunk(IVec3::ZERO, &runs(count, &[(1, 1)])).is_err());
+        assert!(decode_chunk(IVec3::ZERO, &[0x78, 0x9c, 1, 2, 3]).is_err());
+
+        let chunk = TerrainChunkData::from_vec(IVec3::ONE, sample_voxels());
+        let mut payload = encode_chunk(&chunk).unwrap();
+        assert_eq!(decode_chunk(IVec3::ONE, &payload).unwrap().chunk.to_vec(), sample_voxels());
+        // チェックサムで書き換わったペイロードを検出する
+        let middle = payload.len() / 2;
+        payload[middle] ^= 0xff;
+        assert!(decode_chunk(IVec3::ONE, &payload).is_err());
+    }
+
+    #[test]
+    fn writes_append_and_reopen_compacts() {
+        let dir = TempDir::new("compact");
+        let path = dir.0.join("r.0.0.0.bin");
+
+        let mut region = RegionFile::open(&path).unwrap();
+        let large = vec![7u8; 2 * COMPACT_MIN_WASTE as usize];
+        region.write(3, &large).unwrap();
+        let first = region.slots[3];
+        region.write(3, b"new").unwrap();
+        // 収まる場合でも古いペイロードを上書きしない
+        assert_ne!(region.slots[3].offset, first.offset);
+        assert_eq!(region.read(3).unwrap().unwrap(), b"new");
+        drop(region);
+
+        let mut reopened = RegionFile::open(&path).unwrap();
+        assert_eq!(reopened.read(3).unwrap().unwrap(), b"new");
+        assert_eq!(fs::metadata(&path).unwrap().len(), HEADER_SIZE + 3);
+        assert!(reopened.read(4).unwrap().is_none());
+    }
+}